@@ -0,0 +1,94 @@
+//! Compares the thread pool's schedulers at different numbers of threads and sizes of jobs.
+//!
+//! Run with `cargo bench --bench scheduler`. Each line is the best of a few runs, in jobs per
+//! second, so bigger is better.
+
+use std::{
+    hint::black_box,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use web_server::{Scheduler, ThreadPool};
+
+const RUNS: usize = 3;
+
+/// How many iterations of busy work each job does, and how many jobs to run.
+const JOB_SIZES: [(&str, u64, usize); 3] = [
+    ("empty", 0, 200_000),
+    ("small", 1_000, 100_000),
+    ("large", 100_000, 2_000),
+];
+
+const WORKERS: [usize; 4] = [1, 2, 4, 8];
+
+fn main() {
+    let mut rows = Vec::new();
+    for (name, work, jobs) in JOB_SIZES {
+        for workers in WORKERS {
+            let shared = best_of(Scheduler::SharedQueue, workers, work, jobs);
+            let stealing = best_of(Scheduler::WorkStealing, workers, work, jobs);
+            rows.push(format!(
+                "{name:<10} {workers:>7} {:>14.0} {:>14.0} {:>7.2}x",
+                jobs_per_second(jobs, shared),
+                jobs_per_second(jobs, stealing),
+                shared.as_secs_f64() / stealing.as_secs_f64(),
+            ));
+        }
+    }
+
+    println!(
+        "\n{:<10} {:>7} {:>14} {:>14} {:>8}",
+        "job", "threads", "shared queue", "work stealing", "ratio"
+    );
+    for row in rows {
+        println!("{row}");
+    }
+}
+
+fn best_of(scheduler: Scheduler, workers: usize, work: u64, jobs: usize) -> Duration {
+    (0..RUNS)
+        .map(|_| run(scheduler, workers, work, jobs))
+        .min()
+        .unwrap()
+}
+
+/// Queues `jobs` jobs from this thread, and times how long it takes until the last one finishes.
+fn run(scheduler: Scheduler, workers: usize, work: u64, jobs: usize) -> Duration {
+    let pool = ThreadPool::builder(workers)
+        .scheduler(scheduler)
+        .build()
+        .unwrap();
+    let remaining = Arc::new(AtomicUsize::new(jobs));
+    let (done, finished) = mpsc::channel();
+
+    let start = Instant::now();
+    for _ in 0..jobs {
+        let remaining = Arc::clone(&remaining);
+        let done = done.clone();
+        pool.execute(move || {
+            spin(work);
+            if remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+                done.send(()).unwrap();
+            }
+        })
+        .unwrap();
+    }
+    finished.recv().unwrap();
+    start.elapsed()
+}
+
+fn spin(iterations: u64) {
+    let mut x = 0u64;
+    for i in 0..iterations {
+        x = black_box(x.wrapping_mul(31).wrapping_add(i));
+    }
+    black_box(x);
+}
+
+fn jobs_per_second(jobs: usize, elapsed: Duration) -> f64 {
+    jobs as f64 / elapsed.as_secs_f64()
+}
@@ -0,0 +1,447 @@
+//! A small DEFLATE compressor (RFC 1951), the gzip (RFC 1952) and zlib (RFC 1950) formats around
+//! it, and the negotiation of which one to send.
+//!
+//! Repeated strings are found with hash chains, as in zlib, and coded with the fixed Huffman codes,
+//! which saves building a code for each block at the cost of a somewhat worse ratio.
+
+/// The furthest back a match can be.
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+/// How many earlier positions to try before settling for the best match so far.
+const MAX_CHAIN: usize = 64;
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// A content coding that responses can be compressed with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Coding {
+    Gzip,
+    /// Despite the name, the zlib format rather than raw DEFLATE (RFC 9110, 8.4.1.2).
+    Deflate,
+}
+
+impl Coding {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Coding::Gzip => "gzip",
+            Coding::Deflate => "deflate",
+        }
+    }
+
+    pub(crate) fn encode(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Coding::Gzip => gzip(data),
+            Coding::Deflate => zlib(data),
+        }
+    }
+
+    /// Picks the coding with the highest weight in the `Accept-Encoding` field values, preferring
+    /// gzip on a tie, or `None` to send the body as it is (RFC 9110, 12.5.3).
+    ///
+    /// A coding that isn't listed takes the weight of `*`, if there is one. No field at all, or
+    /// one that weighs `identity` above both codings, means no compression.
+    pub(crate) fn negotiate<'a>(
+        accept_encoding: impl IntoIterator<Item = &'a str>,
+    ) -> Option<Coding> {
+        let mut weights: Vec<(&str, f32)> = Vec::new();
+        for coding in accept_encoding
+            .into_iter()
+            .flat_map(|value| value.split(','))
+        {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            if name.is_empty() {
+                continue;
+            }
+            let weight = parts
+                .find_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q=")))
+                .map_or(Some(1.0), |q| {
+                    q.parse::<f32>().ok().filter(|q| (0.0..=1.0).contains(q))
+                });
+            // A malformed weight makes the whole entry unusable.
+            if let Some(weight) = weight {
+                weights.push((name, weight));
+            }
+        }
+        let weight_of = |names: &[&str]| {
+            let explicit = weights
+                .iter()
+                .find(|(name, _)| names.iter().any(|n| name.eq_ignore_ascii_case(n)));
+            explicit
+                .or_else(|| weights.iter().find(|(name, _)| *name == "*"))
+                .map(|&(_, weight)| weight)
+        };
+
+        // Sending the body as it is stays acceptable either way, but only an explicit weight for
+        // it is compared with the codings'.
+        let identity = weight_of(&["identity"]).unwrap_or(0.0);
+        [
+            (Coding::Gzip, weight_of(&["gzip", "x-gzip"])),
+            (Coding::Deflate, weight_of(&["deflate"])),
+        ]
+        .into_iter()
+        .filter_map(|(coding, weight)| Some((coding, weight?)))
+        .filter(|&(_, weight)| weight > 0.0 && weight >= identity)
+        // `max_by` keeps the last of equal elements, so go from least to most preferred.
+        .rev()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(coding, _)| coding)
+    }
+}
+
+/// Compresses `data` into a gzip member, as sent with `Content-Encoding: gzip`.
+pub(crate) fn gzip(data: &[u8]) -> Vec<u8> {
+    // No file name or modification time, and an unknown operating system.
+    let mut gzip = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+    gzip.extend(deflate(data));
+    gzip.extend(crc32(data).to_le_bytes());
+    // The length is only kept modulo 2^32.
+    gzip.extend((data.len() as u32).to_le_bytes());
+    gzip
+}
+
+/// Compresses `data` into a zlib stream, as sent with `Content-Encoding: deflate`.
+pub(crate) fn zlib(data: &[u8]) -> Vec<u8> {
+    // A 32K window and the fastest level, which makes the header a multiple of 31.
+    let mut zlib = vec![0x78, 0x01];
+    zlib.extend(deflate(data));
+    zlib.extend(adler32(data).to_be_bytes());
+    zlib
+}
+
+/// Compresses `data` into raw DEFLATE, as a single block.
+pub(crate) fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut out = BitWriter::default();
+    // The final block, with fixed Huffman codes.
+    out.write_bits(1, 1);
+    out.write_bits(1, 2);
+
+    let mut chains = Chains::new();
+    let mut i = 0;
+    while i < data.len() {
+        match chains.longest_match(data, i) {
+            Some((length, distance)) => {
+                write_length(&mut out, length);
+                write_distance(&mut out, distance);
+                for j in i..i + length {
+                    chains.insert(data, j);
+                }
+                i += length;
+            }
+            None => {
+                write_literal(&mut out, u16::from(data[i]));
+                chains.insert(data, i);
+                i += 1;
+            }
+        }
+    }
+    // End of block.
+    write_literal(&mut out, 256);
+    out.finish()
+}
+
+/// For each hash of three bytes, the positions they were last seen at, most recent first.
+struct Chains {
+    /// The most recent position for each hash.
+    head: Vec<usize>,
+    /// The position before each one with the same hash, indexed modulo the window size.
+    previous: Vec<usize>,
+}
+
+impl Chains {
+    fn new() -> Chains {
+        Chains {
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            previous: vec![usize::MAX; WINDOW_SIZE],
+        }
+    }
+
+    fn hash(data: &[u8], i: usize) -> usize {
+        let bytes = u32::from(data[i]) << 16 | u32::from(data[i + 1]) << 8 | u32::from(data[i + 2]);
+        (bytes.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, data: &[u8], i: usize) {
+        if i + MIN_MATCH > data.len() {
+            return;
+        }
+        let hash = Chains::hash(data, i);
+        self.previous[i % WINDOW_SIZE] = self.head[hash];
+        self.head[hash] = i;
+    }
+
+    /// The longest earlier occurrence of the bytes at `i`, as a length and distance.
+    fn longest_match(&self, data: &[u8], i: usize) -> Option<(usize, usize)> {
+        if i + MIN_MATCH > data.len() {
+            return None;
+        }
+        let max_length = MAX_MATCH.min(data.len() - i);
+        let mut best: Option<(usize, usize)> = None;
+        let mut candidate = self.head[Chains::hash(data, i)];
+        for _ in 0..MAX_CHAIN {
+            // Positions beyond the window may have had their entries reused, so stop there.
+            if candidate == usize::MAX || i - candidate > WINDOW_SIZE {
+                break;
+            }
+            let length = data[candidate..]
+                .iter()
+                .zip(&data[i..i + max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length >= MIN_MATCH && best.is_none_or(|(best, _)| length > best) {
+                best = Some((length, i - candidate));
+                if length == max_length {
+                    break;
+                }
+            }
+            let previous = self.previous[candidate % WINDOW_SIZE];
+            if previous >= candidate {
+                break;
+            }
+            candidate = previous;
+        }
+        best
+    }
+}
+
+fn write_literal(out: &mut BitWriter, literal: u16) {
+    let (code, length) = match literal {
+        0..=143 => (0x30 + literal, 8),
+        144..=255 => (0x190 + literal - 144, 9),
+        256..=279 => (literal - 256, 7),
+        _ => (0xc0 + literal - 280, 8),
+    };
+    out.write_code(code, length);
+}
+
+fn write_length(out: &mut BitWriter, length: usize) {
+    let index = LENGTH_BASES
+        .iter()
+        .rposition(|&base| usize::from(base) <= length)
+        .expect("matches are at least 3 long");
+    write_literal(out, 257 + index as u16);
+    let extra = length - usize::from(LENGTH_BASES[index]);
+    out.write_bits(extra as u32, LENGTH_EXTRA_BITS[index]);
+}
+
+fn write_distance(out: &mut BitWriter, distance: usize) {
+    let index = DISTANCE_BASES
+        .iter()
+        .rposition(|&base| usize::from(base) <= distance)
+        .expect("distances are at least 1");
+    out.write_code(index as u16, 5);
+    let extra = distance - usize::from(DISTANCE_BASES[index]);
+    out.write_bits(extra as u32, DISTANCE_EXTRA_BITS[index]);
+}
+
+/// Packs bits into bytes starting from the least significant bit, as DEFLATE does.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    count: u8,
+}
+
+impl BitWriter {
+    fn write_bits(&mut self, bits: u32, count: u8) {
+        self.buffer |= u64::from(bits) << self.count;
+        self.count += count;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    /// Huffman codes are packed starting from their most significant bit.
+    fn write_code(&mut self, code: u16, length: u8) {
+        let reversed = code.reverse_bits() >> (16 - length);
+        self.write_bits(u32::from(reversed), length);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// The CRC-32 that gzip uses to check the uncompressed data.
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        CRC_TABLE[usize::from((crc as u8) ^ byte)] ^ (crc >> 8)
+    })
+}
+
+/// The Adler-32 checksum that zlib uses to check the uncompressed data.
+fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    // Summing this many bytes can't overflow before the sums are reduced.
+    let (mut a, mut b) = (1, 0);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += u32::from(byte);
+            b += a;
+        }
+        a %= MODULUS;
+        b %= MODULUS;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decompresses the fixed-Huffman blocks that [`deflate`] produces.
+    fn inflate(compressed: &[u8]) -> Vec<u8> {
+        let mut position = 0;
+        let mut bit = || {
+            let value = compressed[position / 8] >> (position % 8) & 1;
+            position += 1;
+            u16::from(value)
+        };
+        let bits = |count: u8, bit: &mut dyn FnMut() -> u16| {
+            (0..count).fold(0, |value, i| value | bit() << i)
+        };
+
+        assert_eq!(bits(3, &mut bit), 0b011, "a final block with fixed codes");
+        let mut output: Vec<u8> = Vec::new();
+        loop {
+            let mut code = 0;
+            let mut length = 0;
+            let symbol = loop {
+                code = code << 1 | bit();
+                length += 1;
+                match (length, code) {
+                    (7, 0..=0x17) => break code + 256,
+                    (8, 0x30..=0xbf) => break code - 0x30,
+                    (8, 0xc0..=0xc7) => break code - 0xc0 + 280,
+                    (9, 0x190..=0x1ff) => break code - 0x190 + 144,
+                    (9, _) => panic!("invalid code"),
+                    _ => {}
+                }
+            };
+            match symbol {
+                0..=255 => output.push(symbol as u8),
+                256 => return output,
+                _ => {
+                    let index = usize::from(symbol - 257);
+                    let length = LENGTH_BASES[index] + bits(LENGTH_EXTRA_BITS[index], &mut bit);
+                    let index = usize::from((0..5).fold(0, |code, _| code << 1 | bit()));
+                    let distance =
+                        DISTANCE_BASES[index] + bits(DISTANCE_EXTRA_BITS[index], &mut bit);
+                    for _ in 0..length {
+                        output.push(output[output.len() - usize::from(distance)]);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let text = "The quick brown fox jumps over the lazy dog. ".repeat(200);
+        let mut binary: Vec<u8> = (0..=255).collect();
+        binary.extend((0..5000u32).map(|i| (i * i % 251) as u8));
+        for data in [
+            &[][..],
+            b"a",
+            b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            text.as_bytes(),
+            &binary,
+        ] {
+            let compressed = deflate(data);
+            assert_eq!(inflate(&compressed), data);
+        }
+
+        let compressed = deflate(text.as_bytes());
+        assert!(compressed.len() < text.len() / 20, "{}", compressed.len());
+    }
+
+    #[test]
+    fn gzip_member() {
+        // The check value from the CRC catalogue.
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let gzip = gzip(b"hello hello hello");
+        assert_eq!(gzip[..4], [0x1f, 0x8b, 8, 0]);
+        let trailer = &gzip[gzip.len() - 8..];
+        assert_eq!(trailer[..4], crc32(b"hello hello hello").to_le_bytes());
+        assert_eq!(trailer[4..], 17u32.to_le_bytes());
+        assert_eq!(inflate(&gzip[10..gzip.len() - 8]), b"hello hello hello");
+    }
+
+    #[test]
+    fn zlib_stream() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(adler32(&[0xff; 100_000]), 0x149a_302c);
+
+        let zlib = zlib(b"hello hello hello");
+        assert_eq!(u16::from_be_bytes([zlib[0], zlib[1]]) % 31, 0);
+        let (stream, trailer) = zlib[2..].split_at(zlib.len() - 6);
+        assert_eq!(inflate(stream), b"hello hello hello");
+        assert_eq!(trailer, adler32(b"hello hello hello").to_be_bytes());
+    }
+
+    #[test]
+    fn negotiation() {
+        let negotiate = |value: &str| Coding::negotiate([value]);
+        assert_eq!(Coding::negotiate([]), None);
+        assert_eq!(negotiate(""), None);
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Coding::Gzip));
+        assert_eq!(negotiate("deflate, gzip"), Some(Coding::Gzip));
+        assert_eq!(negotiate("x-gzip"), Some(Coding::Gzip));
+        assert_eq!(negotiate("GZIP;Q=0.5"), Some(Coding::Gzip));
+        assert_eq!(negotiate("gzip;q=0.5, deflate"), Some(Coding::Deflate));
+        assert_eq!(negotiate("gzip;q=0, deflate;q=0.1"), Some(Coding::Deflate));
+        assert_eq!(negotiate("*"), Some(Coding::Gzip));
+        assert_eq!(negotiate("gzip;q=0, *;q=0.2"), Some(Coding::Deflate));
+        assert_eq!(negotiate("gzip;q=0, deflate;q=0"), None);
+        assert_eq!(negotiate("br"), None);
+        assert_eq!(negotiate("gzip;q=0.5, identity"), None);
+        assert_eq!(negotiate("gzip;q=2, deflate;q=abc"), None);
+        // Field values are combined as if they had been sent as one list.
+        assert_eq!(
+            Coding::negotiate(["gzip;q=0.1", "deflate;q=0.2"]),
+            Some(Coding::Deflate)
+        );
+    }
+}
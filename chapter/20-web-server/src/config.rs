@@ -0,0 +1,1110 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs,
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    log::{AccessLogFormat, LogFormat, LogLevel},
+    server::IoMode,
+};
+
+/// Every setting of the server, along with where it came from.
+///
+/// Settings are read from (in increasing order of precedence) built-in defaults, a TOML file,
+/// environment variables and command-line flags. Each setting has the same name everywhere, give
+/// or take the conventions of the source: `keep_alive_timeout` in the file is
+/// `WEB_SERVER_KEEP_ALIVE_TIMEOUT` in the environment and `--keep-alive-timeout` on the command line.
+///
+/// ```
+/// use web_server::config::ServerConfig;
+///
+/// let args = ["--workers", "8", "--bind", "127.0.0.1:8080"].map(String::from);
+/// let env = [(String::from("WEB_SERVER_LOG_LEVEL"), String::from("debug"))];
+/// let config = ServerConfig::load(args, env).unwrap();
+///
+/// assert_eq!(config.workers, 8);
+/// assert_eq!(config.bind, vec!["127.0.0.1:8080".parse().unwrap()]);
+/// ```
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// The addresses to listen on.
+    pub bind: Vec<SocketAddr>,
+    /// The addresses to listen on for HTTPS, which needs the `tls` feature.
+    pub tls_bind: Vec<SocketAddr>,
+    /// The PEM file with the certificate chain for HTTPS.
+    pub tls_cert: Option<PathBuf>,
+    /// The PEM file with the private key for HTTPS.
+    pub tls_key: Option<PathBuf>,
+    /// Whether the `bind` addresses only redirect to HTTPS, rather than serving the site.
+    pub https_redirect: bool,
+    /// The number of threads in the pool.
+    pub workers: usize,
+    /// The number of threads the pool can grow to while every thread is busy, if more than
+    /// `workers`.
+    pub max_workers: Option<usize>,
+    /// How long threads beyond `workers` wait for a job before exiting.
+    pub worker_idle_timeout: Duration,
+    /// How many accepted connections can wait for a thread before more are turned away.
+    pub queue_capacity: usize,
+    /// Whether each connection has a thread to itself, or an event loop waits on them all.
+    pub io_mode: IoMode,
+    /// The directory that files are served from.
+    pub document_root: PathBuf,
+    /// How long an idle persistent connection is kept open.
+    pub keep_alive_timeout: Duration,
+    /// How long a client has to send a whole request once it has started.
+    pub request_timeout: Duration,
+    /// How long writing a response may stall before the connection is dropped.
+    pub write_timeout: Duration,
+    pub max_requests_per_connection: usize,
+    /// How long requests that are in flight at shutdown are given to finish.
+    pub shutdown_timeout: Duration,
+    pub max_request_line_size: usize,
+    /// The most bytes that the request line and header fields may take up together.
+    pub max_header_size: usize,
+    pub max_headers: usize,
+    pub max_body_size: u64,
+    /// How long a client that was turned away is asked to wait before trying again.
+    pub retry_after: Duration,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
+    /// The file to log to, rather than standard output.
+    pub log_file: Option<PathBuf>,
+    /// How big the log file can grow before it is rotated.
+    pub log_max_size: u64,
+    /// How many rotated log files are kept.
+    pub log_max_files: usize,
+    pub access_log: AccessLogFormat,
+    /// Whether responses are compressed for clients that accept it.
+    pub compression: bool,
+    /// The smallest body worth compressing.
+    pub compression_min_size: u64,
+    /// Whether the thread pool's stats are served at `/metrics` for Prometheus.
+    pub metrics: bool,
+    origins: HashMap<&'static str, Origin>,
+}
+
+/// The settings that can be configured, in the order they are listed by `--help`.
+const KEYS: &[(&str, &str)] = &[
+    (
+        "bind",
+        "address(es) to listen on, comma-separated [127.0.0.1:7878]",
+    ),
+    (
+        "tls_bind",
+        "address(es) to listen on for HTTPS; needs the tls feature",
+    ),
+    ("tls_cert", "PEM file with the HTTPS certificate chain"),
+    ("tls_key", "PEM file with the HTTPS private key"),
+    (
+        "https_redirect",
+        "redirect everything on `bind` to the first HTTPS port [false]",
+    ),
+    ("workers", "number of worker threads [4]"),
+    (
+        "max_workers",
+        "number of threads to grow to while all are busy [workers]",
+    ),
+    (
+        "worker_idle_timeout",
+        "how long extra threads wait for work before exiting [60s]",
+    ),
+    (
+        "queue_capacity",
+        "connections that can wait for a worker before getting a 503 [1024]",
+    ),
+    (
+        "io_mode",
+        "blocking, or event-loop to wait on idle connections with epoll [blocking]",
+    ),
+    (
+        "document_root",
+        "directory to serve files from [the crate's `public`]",
+    ),
+    (
+        "keep_alive_timeout",
+        "how long idle connections stay open [5s]",
+    ),
+    (
+        "request_timeout",
+        "how long clients get to send a request before a 408 [30s]",
+    ),
+    (
+        "write_timeout",
+        "how long writing a response may stall [30s]",
+    ),
+    (
+        "max_requests_per_connection",
+        "requests served before a connection is closed [100]",
+    ),
+    (
+        "shutdown_timeout",
+        "how long in-flight requests get at shutdown [10s]",
+    ),
+    (
+        "max_request_line_size",
+        "longest request line, beyond which a 414 [8KiB]",
+    ),
+    (
+        "max_header_size",
+        "largest request line and headers [16KiB]",
+    ),
+    ("max_headers", "most header fields in a request [100]"),
+    ("max_body_size", "largest request body [1MiB]"),
+    (
+        "retry_after",
+        "how long clients that got a 503 are asked to wait [1s]",
+    ),
+    ("log_level", "off, error, warn, info, debug or trace [info]"),
+    ("log_format", "text or json (one object per line) [text]"),
+    ("log_file", "file to log to instead of standard output"),
+    ("log_max_size", "size a log file is rotated at [10MiB]"),
+    ("log_max_files", "rotated log files to keep [5]"),
+    (
+        "access_log",
+        "log requests as off, common or combined [combined]",
+    ),
+    (
+        "compression",
+        "compress text responses with gzip or deflate [true]",
+    ),
+    (
+        "compression_min_size",
+        "smallest response body to compress [1KiB]",
+    ),
+    ("metrics", "serve the pool's stats at /metrics [false]"),
+];
+
+/// The prefix of the environment variables that configure the server.
+const ENV_PREFIX: &str = "WEB_SERVER_";
+
+/// The longest any of the timeouts can be.
+const MAX_TIMEOUT: Duration = Duration::from_secs(24 * 60 * 60);
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            bind: vec![SocketAddr::from(([127, 0, 0, 1], 7878))],
+            tls_bind: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            https_redirect: false,
+            workers: 4,
+            max_workers: None,
+            worker_idle_timeout: Duration::from_secs(60),
+            queue_capacity: 1024,
+            io_mode: IoMode::Blocking,
+            // The pages that ship with this crate, wherever the server is run from.
+            document_root: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("public"),
+            keep_alive_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_requests_per_connection: 100,
+            shutdown_timeout: Duration::from_secs(10),
+            max_request_line_size: 8 * 1024,
+            max_header_size: 16 * 1024,
+            max_headers: 100,
+            max_body_size: 1024 * 1024,
+            retry_after: Duration::from_secs(1),
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Text,
+            log_file: None,
+            log_max_size: 10 * 1024 * 1024,
+            log_max_files: 5,
+            access_log: AccessLogFormat::Combined,
+            compression: true,
+            compression_min_size: 1024,
+            metrics: false,
+            origins: HashMap::new(),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Loads the configuration from command-line arguments (without the program name) and
+    /// environment variables, and the TOML file either of them names with `config`.
+    pub fn load<A, E>(args: A, env: E) -> Result<ServerConfig, ConfigError>
+    where
+        A: IntoIterator<Item = String>,
+        E: IntoIterator<Item = (String, String)>,
+    {
+        let flags = parse_args(args)?;
+        let env: Vec<(String, String)> = env
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+
+        let mut config = ServerConfig::default();
+
+        let config_file = flags
+            .iter()
+            .find(|(key, _, _)| key == "config")
+            .map(|(_, value, origin)| (value, origin.clone()))
+            .or_else(|| {
+                env.iter()
+                    .find(|(name, _)| name == "WEB_SERVER_CONFIG")
+                    .map(|(name, value)| (value, Origin::Env(name.clone())))
+            });
+        if let Some((path, origin)) = config_file {
+            let source = fs::read_to_string(path).map_err(|e| ConfigError {
+                origin,
+                key: String::from("config"),
+                message: format!("could not read {path}: {e}"),
+            })?;
+            config.apply_toml(&source, Path::new(path))?;
+        }
+
+        for (name, value) in &env {
+            if name == "WEB_SERVER_CONFIG" {
+                continue;
+            }
+            let origin = Origin::Env(name.clone());
+            let key = name[ENV_PREFIX.len()..].to_ascii_lowercase();
+            config.set(&key, &Value::String(value.clone()), origin)?;
+        }
+
+        for (key, value, origin) in flags {
+            if key != "config" {
+                config.set(&key, &Value::String(value), origin)?;
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Applies every setting in `source`, a TOML document that was read from `path`.
+    pub fn apply_toml(&mut self, source: &str, path: &Path) -> Result<(), ConfigError> {
+        for (key, value, line) in parse_toml(source, path)? {
+            let origin = Origin::File {
+                path: path.to_path_buf(),
+                line: Some(line),
+            };
+            self.set(&key, &value, origin)?;
+        }
+        Ok(())
+    }
+
+    /// Sets one setting, given by the name it has in a config file.
+    pub fn set(&mut self, key: &str, value: &Value, origin: Origin) -> Result<(), ConfigError> {
+        let Some(&(key, _)) = KEYS.iter().find(|(k, _)| *k == key) else {
+            return Err(ConfigError {
+                origin,
+                key: key.to_string(),
+                message: String::from("unknown setting"),
+            });
+        };
+        let error = |message: String| ConfigError {
+            origin: origin.clone(),
+            key: key.to_string(),
+            message,
+        };
+
+        match key {
+            "bind" => self.bind = value.to_addresses().map_err(error)?,
+            "tls_bind" => self.tls_bind = value.to_addresses().map_err(error)?,
+            "tls_cert" => {
+                let path = value.to_path(&origin).map_err(error)?;
+                self.tls_cert = (!value.to_str().map_err(error)?.is_empty()).then_some(path);
+            }
+            "tls_key" => {
+                let path = value.to_path(&origin).map_err(error)?;
+                self.tls_key = (!value.to_str().map_err(error)?.is_empty()).then_some(path);
+            }
+            "https_redirect" => self.https_redirect = value.to_bool().map_err(error)?,
+            "workers" => self.workers = value.to_number().map_err(error)?,
+            "max_workers" => self.max_workers = Some(value.to_number().map_err(error)?),
+            "worker_idle_timeout" => {
+                self.worker_idle_timeout = value.to_duration().map_err(error)?
+            }
+            "document_root" => {
+                self.document_root = value.to_path(&origin).map_err(error)?;
+            }
+            "keep_alive_timeout" => self.keep_alive_timeout = value.to_duration().map_err(error)?,
+            "request_timeout" => self.request_timeout = value.to_duration().map_err(error)?,
+            "write_timeout" => self.write_timeout = value.to_duration().map_err(error)?,
+            "max_requests_per_connection" => {
+                self.max_requests_per_connection = value.to_number().map_err(error)?
+            }
+            "shutdown_timeout" => self.shutdown_timeout = value.to_duration().map_err(error)?,
+            "max_request_line_size" => {
+                self.max_request_line_size = value.to_size().map_err(error)? as usize
+            }
+            "max_header_size" => self.max_header_size = value.to_size().map_err(error)? as usize,
+            "max_headers" => self.max_headers = value.to_number().map_err(error)?,
+            "max_body_size" => self.max_body_size = value.to_size().map_err(error)?,
+            "queue_capacity" => self.queue_capacity = value.to_number().map_err(error)?,
+            "io_mode" => self.io_mode = value.to_str().map_err(error)?.parse().map_err(error)?,
+            "retry_after" => self.retry_after = value.to_duration().map_err(error)?,
+            "log_level" => {
+                self.log_level = value.to_str().map_err(error)?.parse().map_err(error)?
+            }
+            "log_format" => {
+                self.log_format = value.to_str().map_err(error)?.parse().map_err(error)?
+            }
+            // An empty path logs to standard output, so that the file can be overridden.
+            "log_file" => {
+                let path = value.to_path(&origin).map_err(error)?;
+                self.log_file = (!value.to_str().map_err(error)?.is_empty()).then_some(path);
+            }
+            "log_max_size" => self.log_max_size = value.to_size().map_err(error)?,
+            "log_max_files" => self.log_max_files = value.to_number().map_err(error)?,
+            "access_log" => {
+                self.access_log = value.to_str().map_err(error)?.parse().map_err(error)?
+            }
+            "compression" => self.compression = value.to_bool().map_err(error)?,
+            "compression_min_size" => self.compression_min_size = value.to_size().map_err(error)?,
+            "metrics" => self.metrics = value.to_bool().map_err(error)?,
+            _ => unreachable!("every key in KEYS is handled"),
+        }
+        self.origins.insert(key, origin);
+        Ok(())
+    }
+
+    /// Checks that the settings make sense together, blaming whichever source set a bad one.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let error = |key: &'static str, message: &str| ConfigError {
+            origin: self.origins.get(key).cloned().unwrap_or(Origin::Default),
+            key: key.to_string(),
+            message: message.to_string(),
+        };
+
+        if self.bind.is_empty() && self.tls_bind.is_empty() {
+            return Err(error("bind", "at least one address is required"));
+        }
+        if !self.tls_bind.is_empty() {
+            if !cfg!(feature = "tls") {
+                let message = "HTTPS needs the server to be built with `--features tls`";
+                return Err(error("tls_bind", message));
+            }
+            if self.tls_cert.is_none() {
+                return Err(error("tls_cert", "is required to listen for HTTPS"));
+            }
+            if self.tls_key.is_none() {
+                return Err(error("tls_key", "is required to listen for HTTPS"));
+            }
+        }
+        if self.https_redirect && self.tls_bind.is_empty() {
+            return Err(error("https_redirect", "needs tls_bind to redirect to"));
+        }
+        if self.workers == 0 {
+            return Err(error("workers", "must be at least 1"));
+        }
+        if self.max_workers.is_some_and(|max| max < self.workers) {
+            return Err(error("max_workers", "must be at least as many as workers"));
+        }
+        if self.queue_capacity == 0 {
+            return Err(error("queue_capacity", "must be at least 1"));
+        }
+        if self.io_mode == IoMode::EventLoop && !cfg!(target_os = "linux") {
+            return Err(error(
+                "io_mode",
+                "the event loop is only supported on Linux",
+            ));
+        }
+        if !self.document_root.is_dir() {
+            let message = format!("{} is not a directory", self.document_root.display());
+            return Err(error("document_root", &message));
+        }
+        if self.keep_alive_timeout.is_zero() {
+            return Err(error("keep_alive_timeout", "must be longer than zero"));
+        }
+        // A zero timeout would mean waiting forever, which is what these are meant to prevent.
+        if self.request_timeout.is_zero() {
+            return Err(error("request_timeout", "must be longer than zero"));
+        }
+        if self.write_timeout.is_zero() {
+            return Err(error("write_timeout", "must be longer than zero"));
+        }
+        // Anything longer is as good as forever, and too long to add to the clock.
+        for (key, timeout) in [
+            ("worker_idle_timeout", self.worker_idle_timeout),
+            ("keep_alive_timeout", self.keep_alive_timeout),
+            ("request_timeout", self.request_timeout),
+            ("write_timeout", self.write_timeout),
+            ("shutdown_timeout", self.shutdown_timeout),
+        ] {
+            if timeout > MAX_TIMEOUT {
+                return Err(error(key, "must be at most a day"));
+            }
+        }
+        if self.max_requests_per_connection == 0 {
+            return Err(error("max_requests_per_connection", "must be at least 1"));
+        }
+        if self.log_max_size == 0 {
+            return Err(error("log_max_size", "must be at least 1 byte"));
+        }
+        // Anything shorter than a request line and a `Host` header can't be a real request.
+        if self.max_header_size < 64 {
+            return Err(error("max_header_size", "must be at least 64 bytes"));
+        }
+        if self.max_request_line_size < 16 {
+            return Err(error("max_request_line_size", "must be at least 16 bytes"));
+        }
+        // An HTTP/1.1 request has to have a `Host` header.
+        if self.max_headers == 0 {
+            return Err(error("max_headers", "must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// Describes the command-line flags and environment variables.
+    pub fn usage() -> String {
+        let mut usage = String::from(
+            "Usage: web-server [--config <file.toml>] [--<setting> <value>]...\n\nSettings:\n",
+        );
+        for (key, description) in KEYS {
+            usage.push_str(&format!("  --{:<30}{description}\n", key.replace('_', "-")));
+        }
+        usage.push_str(&format!(
+            "\nEach setting can also be given as an environment variable (such as {ENV_PREFIX}WORKERS),\n\
+             or as a key in the file named by --config or {ENV_PREFIX}CONFIG. Flags take precedence over\n\
+             the environment, which takes precedence over the file.\n"
+        ));
+        usage
+    }
+}
+
+/// Where a setting came from, so that an error can point at it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    Default,
+    File { path: PathBuf, line: Option<usize> },
+    Env(String),
+    Flag(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Default => f.write_str("default"),
+            Origin::File {
+                path,
+                line: Some(line),
+            } => write!(f, "{}:{line}", path.display()),
+            Origin::File { path, line: None } => write!(f, "{}", path.display()),
+            Origin::Env(name) => write!(f, "environment variable {name}"),
+            Origin::Flag(flag) => write!(f, "flag {flag}"),
+        }
+    }
+}
+
+/// A setting that is missing, malformed or out of range.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub origin: Origin,
+    /// The setting at fault, as it is named in a config file; empty for a syntax error.
+    pub key: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}: {}", self.origin, self.message)
+        } else {
+            write!(f, "{}: {}: {}", self.origin, self.key, self.message)
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// A value from a config file, or (always as a string) from the environment or command line.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn to_str(&self) -> Result<&str, String> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(String::from("expected a string")),
+        }
+    }
+
+    fn to_number<T: TryFrom<i64> + FromStr>(&self) -> Result<T, String> {
+        let number = match self {
+            Value::Integer(i) => T::try_from(*i).ok(),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+        number.ok_or_else(|| String::from("expected a non-negative integer"))
+    }
+
+    /// A relative path in a config file is relative to the file, not whoever ran the server.
+    fn to_path(&self, origin: &Origin) -> Result<PathBuf, String> {
+        let path = PathBuf::from(self.to_str()?);
+        Ok(match origin {
+            Origin::File { path: file, .. } => file.parent().unwrap_or(Path::new("")).join(path),
+            _ => path,
+        })
+    }
+
+    fn to_bool(&self) -> Result<bool, String> {
+        match self {
+            Value::Boolean(b) => Ok(*b),
+            Value::String(s) if s == "true" => Ok(true),
+            Value::String(s) if s == "false" => Ok(false),
+            _ => Err(String::from("expected true or false")),
+        }
+    }
+
+    /// Accepts a number of seconds, or a number with a unit of `ms`, `s`, `m` or `h`.
+    fn to_duration(&self) -> Result<Duration, String> {
+        let error = || String::from("expected a duration, such as 30, \"30s\" or \"500ms\"");
+        let text = match self {
+            Value::Integer(_) => {
+                return Ok(Duration::from_secs(self.to_number().map_err(|_| error())?))
+            }
+            Value::String(s) => s.trim(),
+            _ => return Err(error()),
+        };
+        let split = text
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len());
+        let amount: u64 = text[..split].parse().map_err(|_| error())?;
+        let seconds = |unit: u64| amount.checked_mul(unit).map(Duration::from_secs);
+        match text[split..].trim() {
+            "ms" => Some(Duration::from_millis(amount)),
+            "" | "s" => seconds(1),
+            "m" => seconds(60),
+            "h" => seconds(60 * 60),
+            _ => None,
+        }
+        .ok_or_else(error)
+    }
+
+    /// Accepts a number of bytes, or a number with a unit of `KiB`, `MiB` or `GiB` (or `K`, `M`, `G`).
+    fn to_size(&self) -> Result<u64, String> {
+        let error = || String::from("expected a size, such as 8192 or \"8KiB\"");
+        let text = match self {
+            Value::Integer(_) => return self.to_number().map_err(|_| error()),
+            Value::String(s) => s.trim(),
+            _ => return Err(error()),
+        };
+        let split = text
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(text.len());
+        let amount: u64 = text[..split].parse().map_err(|_| error())?;
+        let unit: u64 = match text[split..].trim().to_ascii_lowercase().as_str() {
+            "" | "b" => 1,
+            "k" | "kib" | "kb" => 1 << 10,
+            "m" | "mib" | "mb" => 1 << 20,
+            "g" | "gib" | "gb" => 1 << 30,
+            _ => return Err(error()),
+        };
+        amount.checked_mul(unit).ok_or_else(error)
+    }
+
+    /// Accepts an array of addresses, or a single string of comma-separated ones.
+    fn to_addresses(&self) -> Result<Vec<SocketAddr>, String> {
+        let addresses: Vec<&str> = match self {
+            Value::String(s) => s
+                .split(',')
+                .map(str::trim)
+                .filter(|a| !a.is_empty())
+                .collect(),
+            Value::Array(values) => values.iter().map(Value::to_str).collect::<Result<_, _>>()?,
+            _ => return Err(String::from("expected an address or a list of addresses")),
+        };
+
+        let mut resolved = Vec::new();
+        for address in addresses {
+            let error = |e: String| format!("{address:?} is not a valid address: {e}");
+            let mut found = address
+                .to_socket_addrs()
+                .map_err(|e| error(e.to_string()))?
+                .peekable();
+            if found.peek().is_none() {
+                return Err(error(String::from("it did not resolve")));
+            }
+            resolved.extend(found);
+        }
+        Ok(resolved)
+    }
+}
+
+/// Splits `--key value` and `--key=value` flags into keys (as they're named in a config file) and
+/// values. Repeating a flag that takes a list, such as `--bind` or `--tls-bind`, adds to the list.
+fn parse_args<A>(args: A) -> Result<Vec<(String, String, Origin)>, ConfigError>
+where
+    A: IntoIterator<Item = String>,
+{
+    let mut flags: Vec<(String, String, Origin)> = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(ConfigError {
+                origin: Origin::Flag(arg.clone()),
+                key: String::new(),
+                message: String::from("expected a flag starting with --; see --help"),
+            });
+        };
+        let (name, value) = match flag.split_once('=') {
+            Some((name, value)) => (name.to_string(), Some(value.to_string())),
+            None => (flag.to_string(), None),
+        };
+
+        let origin = Origin::Flag(format!("--{name}"));
+        let key = name.replace('-', "_");
+        let Some(value) = value.or_else(|| args.next()) else {
+            return Err(ConfigError {
+                origin,
+                key,
+                message: String::from("expected a value"),
+            });
+        };
+
+        match flags.iter_mut().find(|(k, _, _)| *k == key) {
+            Some((_, existing, _)) if key == "bind" || key == "tls_bind" => {
+                existing.push(',');
+                existing.push_str(&value);
+            }
+            Some(existing) => *existing = (key, value, origin),
+            None => flags.push((key, value, origin)),
+        }
+    }
+    Ok(flags)
+}
+
+/// Parses the subset of TOML that a config file needs: comments, `[table]` headers (whose name
+/// prefixes the keys beneath them, as in `table.key`), and `key = value` pairs whose values are
+/// strings, integers, booleans or arrays of those.
+///
+/// Returns each key with its value and the line it was on.
+fn parse_toml(source: &str, path: &Path) -> Result<Vec<(String, Value, usize)>, ConfigError> {
+    let mut entries = Vec::new();
+    let mut table = String::new();
+    let mut lines = source.lines().enumerate().map(|(i, line)| (i + 1, line));
+
+    while let Some((number, line)) = lines.next() {
+        let error = |message: &str| ConfigError {
+            origin: Origin::File {
+                path: path.to_path_buf(),
+                line: Some(number),
+            },
+            key: String::new(),
+            message: message.to_string(),
+        };
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let (name, rest) = name
+                .split_once(']')
+                .ok_or_else(|| error("unterminated table header"))?;
+            if !strip_trailing_comment(rest).trim().is_empty() {
+                return Err(error("unexpected characters after table header"));
+            }
+            if !is_bare_key(name.trim()) {
+                return Err(error("invalid table name"));
+            }
+            table = format!("{}.", name.trim());
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error("expected key = value"))?;
+        let key = key.trim();
+        if !is_bare_key(key) {
+            return Err(error("invalid key"));
+        }
+        let key = format!("{table}{key}");
+        if entries.iter().any(|(k, _, _)| *k == key) {
+            return Err(error(&format!("{key} is set more than once")));
+        }
+
+        // An array may continue over several lines, until its brackets balance.
+        let mut value = strip_trailing_comment(value).trim().to_string();
+        while value.starts_with('[') && !is_balanced(&value) {
+            let (_, next) = lines.next().ok_or_else(|| error("unterminated array"))?;
+            value.push(' ');
+            value.push_str(strip_trailing_comment(next).trim());
+        }
+
+        let mut chars = value.trim().chars().peekable();
+        let parsed = parse_value(&mut chars).map_err(|e| error(&e))?;
+        if chars.any(|c| !c.is_whitespace()) {
+            return Err(error("unexpected characters after value"));
+        }
+        entries.push((key, parsed, number));
+    }
+    Ok(entries)
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Removes a trailing `# comment` from a line, unless the `#` is inside a string.
+fn strip_trailing_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// Whether every `[` in a value, with its comments removed, has a matching `]`.
+fn is_balanced(value: &str) -> bool {
+    let mut chars = value.chars().peekable();
+    let mut depth = 0;
+    while let Some(c) = chars.next() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            // Skip over strings, so that brackets inside them don't count.
+            '"' | '\'' => {
+                let mut escaped = false;
+                for d in chars.by_ref() {
+                    if d == c && !escaped {
+                        break;
+                    }
+                    escaped = c == '"' && d == '\\' && !escaped;
+                }
+            }
+            _ => {}
+        }
+    }
+    depth <= 0
+}
+
+fn parse_value(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Value, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('"') => {
+            chars.next();
+            let mut string = String::new();
+            loop {
+                match chars.next().ok_or("unterminated string")? {
+                    '"' => return Ok(Value::String(string)),
+                    '\\' => string.push(match chars.next().ok_or("unterminated string")? {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        '"' => '"',
+                        '\\' => '\\',
+                        'u' => {
+                            let hex: String = chars.by_ref().take(4).collect();
+                            u32::from_str_radix(&hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or("invalid unicode escape")?
+                        }
+                        _ => return Err(String::from("invalid escape sequence")),
+                    }),
+                    c => string.push(c),
+                }
+            }
+        }
+        Some('\'') => {
+            chars.next();
+            let string: String = chars.by_ref().take_while(|c| *c != '\'').collect();
+            Ok(Value::String(string))
+        }
+        Some('[') => {
+            chars.next();
+            let mut values = Vec::new();
+            loop {
+                skip_whitespace(chars);
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    return Ok(Value::Array(values));
+                }
+                values.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => {}
+                    Some(']') => return Ok(Value::Array(values)),
+                    _ => return Err(String::from("expected , or ] in array")),
+                }
+            }
+        }
+        Some(_) => {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() || c == ',' || c == ']' || c == '#' {
+                    break;
+                }
+                token.push(c);
+                chars.next();
+            }
+            match token.as_str() {
+                "true" => Ok(Value::Boolean(true)),
+                "false" => Ok(Value::Boolean(false)),
+                _ => token
+                    .replace('_', "")
+                    .parse()
+                    .map(Value::Integer)
+                    .map_err(|_| format!("invalid value {token:?}")),
+            }
+        }
+        None => Err(String::from("expected a value")),
+    }
+}
+
+fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Deref;
+
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    fn env(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    /// A config file in the system's temporary directory, which is removed when it is dropped.
+    struct ConfigFile(PathBuf);
+
+    impl Deref for ConfigFile {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// Writes `contents` to a config file.
+    fn config_file(name: &str, contents: &str) -> ConfigFile {
+        let path =
+            std::env::temp_dir().join(format!("web-server-{name}-{}.toml", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        ConfigFile(path)
+    }
+
+    #[test]
+    fn defaults() {
+        let config = ServerConfig::load(args(&[]), env(&[])).unwrap();
+        assert_eq!(config.bind, vec![SocketAddr::from(([127, 0, 0, 1], 7878))]);
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.log_level, LogLevel::Info);
+    }
+
+    #[test]
+    fn precedence() {
+        let file = config_file(
+            "precedence",
+            "workers = 2\nlog_level = \"warn\"\nmax_body_size = 10\n",
+        );
+        let file = file.to_str().unwrap();
+        let config = ServerConfig::load(
+            args(&["--config", file, "--workers=8"]),
+            env(&[
+                ("WEB_SERVER_WORKERS", "6"),
+                ("WEB_SERVER_LOG_LEVEL", "debug"),
+                ("PATH", "/bin"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(config.max_body_size, 10);
+    }
+
+    #[test]
+    fn toml_values() {
+        let file = config_file(
+            "values",
+            "# A comment.\n\
+             bind = [\n  \"127.0.0.1:8080\", # The first.\n  '[::1]:8080',\n]\n\
+             keep_alive_timeout = \"500ms\"\n\
+             shutdown_timeout = 30 # Seconds.\n\
+             max_header_size = \"8KiB\"\n\
+             max_requests_per_connection = 1_000\n\
+             document_root = \".\"\n\
+             compression_min_size = \"2KiB\"\n\
+             metrics = true\n",
+        );
+        let config =
+            ServerConfig::load(args(&["--config", file.to_str().unwrap()]), env(&[])).unwrap();
+        assert_eq!(
+            config.bind,
+            vec![
+                "127.0.0.1:8080".parse().unwrap(),
+                "[::1]:8080".parse().unwrap()
+            ]
+        );
+        assert_eq!(config.keep_alive_timeout, Duration::from_millis(500));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(30));
+        assert_eq!(config.max_header_size, 8192);
+        assert_eq!(config.max_requests_per_connection, 1000);
+        assert_eq!(config.document_root, file.parent().unwrap().join("."));
+        assert_eq!(config.compression_min_size, 2048);
+        assert!(config.metrics);
+    }
+
+    #[test]
+    fn repeated_bind_flags() {
+        let config = ServerConfig::load(
+            args(&["--bind", "127.0.0.1:1", "--bind=127.0.0.1:2"]),
+            env(&[]),
+        )
+        .unwrap();
+        assert_eq!(config.bind.len(), 2);
+    }
+
+    #[test]
+    fn errors_point_at_the_bad_key() {
+        let error = ServerConfig::load(args(&["--workers", "0"]), env(&[])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "flag --workers: workers: must be at least 1"
+        );
+        let error =
+            ServerConfig::load(args(&[]), env(&[("WEB_SERVER_MAX_HEADERS", "0")])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "environment variable WEB_SERVER_MAX_HEADERS: max_headers: must be at least 1"
+        );
+
+        let error = ServerConfig::load(
+            args(&["--max-workers", "2"]),
+            env(&[("WEB_SERVER_WORKERS", "3")]),
+        )
+        .unwrap_err();
+        assert_eq!(error.origin, Origin::Flag(String::from("--max-workers")));
+
+        let error = ServerConfig::load(args(&["--io-mode", "threads"]), env(&[])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "flag --io-mode: io_mode: expected blocking or event-loop"
+        );
+
+        let error = ServerConfig::load(args(&[]), env(&[("WEB_SERVER_WROKERS", "2")])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "environment variable WEB_SERVER_WROKERS: wrokers: unknown setting"
+        );
+
+        let error =
+            ServerConfig::load(args(&["--keep-alive-timeout", "soon"]), env(&[])).unwrap_err();
+        assert_eq!(error.key, "keep_alive_timeout");
+        assert_eq!(
+            error.origin,
+            Origin::Flag(String::from("--keep-alive-timeout"))
+        );
+        let error = ServerConfig::load(
+            args(&["--request-timeout", "18446744073709551615"]),
+            env(&[]),
+        )
+        .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "flag --request-timeout: request_timeout: must be at most a day"
+        );
+
+        // Too many hours to count in seconds.
+        let error = ServerConfig::load(
+            args(&["--keep-alive-timeout", "9999999999999999h"]),
+            env(&[]),
+        )
+        .unwrap_err();
+        assert_eq!(error.key, "keep_alive_timeout");
+        assert!(error.to_string().contains("expected a duration"), "{error}");
+
+        let file = config_file("errors", "workers = 4\n\nlog_level = \"loud\"\n");
+        let error =
+            ServerConfig::load(args(&["--config", file.to_str().unwrap()]), env(&[])).unwrap_err();
+        assert_eq!(
+            error.origin,
+            Origin::File {
+                path: file.to_path_buf(),
+                line: Some(3)
+            }
+        );
+        assert_eq!(error.key, "log_level");
+
+        let file = config_file("syntax", "workers = 4\nworkers 5\n");
+        let error =
+            ServerConfig::load(args(&["--config", file.to_str().unwrap()]), env(&[])).unwrap_err();
+        assert_eq!(
+            error.origin,
+            Origin::File {
+                path: file.to_path_buf(),
+                line: Some(2)
+            }
+        );
+        assert!(error.key.is_empty());
+
+        let error =
+            ServerConfig::load(args(&["--document-root", "/no/such/dir"]), env(&[])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "flag --document-root: document_root: /no/such/dir is not a directory"
+        );
+    }
+
+    #[test]
+    fn https_settings() {
+        let error = ServerConfig::load(args(&["--tls-bind", "127.0.0.1:7879"]), env(&[]));
+        if cfg!(feature = "tls") {
+            assert_eq!(error.unwrap_err().key, "tls_cert");
+            let config = ServerConfig::load(
+                args(&[
+                    "--bind=",
+                    "--tls-bind=127.0.0.1:7879",
+                    "--tls-bind=127.0.0.1:7880",
+                    "--tls-cert=cert.pem",
+                    "--tls-key=key.pem",
+                ]),
+                env(&[]),
+            )
+            .unwrap();
+            assert!(config.bind.is_empty());
+            assert_eq!(config.tls_bind.len(), 2);
+            assert_eq!(config.tls_cert, Some(PathBuf::from("cert.pem")));
+        } else {
+            assert!(error.unwrap_err().message.contains("--features tls"));
+        }
+
+        let error = ServerConfig::load(args(&["--https-redirect", "true"]), env(&[])).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "flag --https-redirect: https_redirect: needs tls_bind to redirect to"
+        );
+    }
+}
@@ -0,0 +1,595 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::{Component, Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    date::{format_http_date, parse_http_date},
+    range::{self, Ranges},
+    request::{Method, Request},
+    response::{Body, Response, StatusCode},
+};
+
+/// Serves files from beneath a document root.
+///
+/// Paths that would leave the root, either with `..` or by following a symbolic link, are refused
+/// with `403 Forbidden`. A request for a directory is answered with its `index.html`. Files are
+/// streamed rather than read into memory, and `Range` requests are answered with just the bytes
+/// asked for, so large files can be downloaded in pieces and resumed.
+///
+/// ```no_run
+/// use web_server::{files::StaticFiles, router::Router};
+///
+/// let files = StaticFiles::new("public").unwrap();
+/// let router = Router::new().get("/*path", move |request| {
+///     files.serve(request, request.param("path").unwrap())
+/// });
+/// ```
+#[derive(Clone, Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+    not_found_page: Option<PathBuf>,
+}
+
+impl StaticFiles {
+    /// Creates a server for the files beneath `root`, which must be an existing directory.
+    pub fn new(root: impl AsRef<Path>) -> io::Result<StaticFiles> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(StaticFiles {
+            root,
+            not_found_page: None,
+        })
+    }
+
+    /// Uses the file at `path` (relative to the root) as the body of `404 Not Found` responses.
+    pub fn with_not_found_page(mut self, path: impl AsRef<Path>) -> StaticFiles {
+        self.not_found_page = Some(self.root.join(path));
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Answers `request` with the file at `path`, which is relative to the root and already
+    /// percent-decoded (as a route's wildcard parameter is).
+    ///
+    /// A directory asked for without a trailing slash is redirected to `/{path}/`, so the files
+    /// are expected to be served from the top of the site.
+    pub fn serve(&self, request: &Request, path: &str) -> Response {
+        let Some(relative) = sanitize(path) else {
+            return Response::new(StatusCode::Forbidden);
+        };
+        let mut file_path = match self.resolve(&self.root.join(&relative)) {
+            Ok(file_path) => file_path,
+            Err(response) => return response,
+        };
+
+        if file_path.is_dir() {
+            // Without the trailing slash, relative links in the index page would resolve against
+            // the parent directory. The location is built from the sanitized path rather than the
+            // request's, which may start with `//` and so name another host.
+            if !request.path().ends_with('/') {
+                let mut location = String::from("/");
+                for part in relative.iter() {
+                    percent_encode_into(&mut location, &part.to_string_lossy());
+                    location.push('/');
+                }
+                if let Some(query) = request.query() {
+                    location.push('?');
+                    location.push_str(query);
+                }
+                return Response::builder()
+                    .status(StatusCode::MovedPermanently)
+                    .header("Location", location)
+                    .build();
+            }
+            // The index may be a link of its own.
+            file_path = match self.resolve(&file_path.join("index.html")) {
+                Ok(file_path) => file_path,
+                Err(response) => return response,
+            };
+        }
+
+        match self.open(request, &file_path) {
+            Ok(response) => response,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => self.not_found(),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => {
+                Response::new(StatusCode::Forbidden)
+            }
+            Err(_) => Response::new(StatusCode::InternalServerError),
+        }
+    }
+
+    /// Follows the links in `path`, refusing it if it ends up outside of the root.
+    fn resolve(&self, path: &Path) -> Result<PathBuf, Response> {
+        let Ok(path) = fs::canonicalize(path) else {
+            return Err(self.not_found());
+        };
+        if !path.starts_with(&self.root) {
+            return Err(Response::new(StatusCode::Forbidden));
+        }
+        Ok(path)
+    }
+
+    /// A `404 Not Found` response, using the not-found page if one was configured.
+    pub fn not_found(&self) -> Response {
+        let page = self
+            .not_found_page
+            .as_ref()
+            .and_then(|path| fs::read(path).ok());
+        match page {
+            Some(page) => Response::builder()
+                .status(StatusCode::NotFound)
+                .content_type(content_type(self.not_found_page.as_ref().unwrap()))
+                .body(page)
+                .build(),
+            None => Response::new(StatusCode::NotFound),
+        }
+    }
+
+    fn open(&self, request: &Request, path: &Path) -> io::Result<Response> {
+        let file = File::open(path)?;
+        let metadata = file.metadata()?;
+        if !metadata.is_file() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+
+        // HTTP dates only have a resolution of one second, so neither should our validators.
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|m| UNIX_EPOCH + Duration::from_secs(m.as_secs()));
+        let etag = modified.map(|m| etag(m, metadata.len()));
+
+        let mut builder = Response::builder().header("Accept-Ranges", "bytes");
+        if let Some(modified) = modified {
+            builder = builder.header("Last-Modified", format_http_date(modified));
+        }
+        if let Some(etag) = &etag {
+            builder = builder.header("ETag", etag.as_str());
+        }
+
+        if is_not_modified(request, etag.as_deref(), modified) {
+            return Ok(builder.status(StatusCode::NotModified).build());
+        }
+        let length = metadata.len();
+        match requested_ranges(request, etag.as_deref(), modified, length) {
+            Ranges::Ignored => Ok(builder
+                .content_type(content_type(path))
+                .body(Body::from_reader(file, Some(length)))
+                .build()),
+            Ranges::Unsatisfiable => Ok(builder
+                .status(StatusCode::RangeNotSatisfiable)
+                .header("Content-Range", format!("bytes */{length}"))
+                .build()),
+            Ranges::Satisfiable(ranges) => {
+                range::partial_content(builder, file, content_type(path), length, &ranges)
+            }
+        }
+    }
+}
+
+/// Turns a request path into a relative path with no `..`, root or prefix components.
+fn sanitize(path: &str) -> Option<PathBuf> {
+    if path.contains('\0') || path.contains('\\') {
+        return None;
+    }
+    let mut relative = PathBuf::new();
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(relative)
+}
+
+/// Appends `segment` to a URL path, percent-encoding everything but unreserved characters and
+/// the sub-delimiters, `:` and `@` that a path segment may hold (RFC 3986, 3.3).
+fn percent_encode_into(url: &mut String, segment: &str) {
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~!$&'()*+,;=:@".contains(&byte) {
+            url.push(byte as char);
+        } else {
+            url.push_str(&format!("%{byte:02X}"));
+        }
+    }
+}
+
+/// A validator that changes whenever the file is modified, in the same style as nginx.
+fn etag(modified: SystemTime, len: u64) -> String {
+    let secs = modified
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!("\"{secs:x}-{len:x}\"")
+}
+
+/// Evaluates `If-None-Match` and `If-Modified-Since`, in that order of precedence (RFC 9110, 13.2.2).
+fn is_not_modified(request: &Request, etag: Option<&str>, modified: Option<SystemTime>) -> bool {
+    if !matches!(request.method(), Method::Get | Method::Head) {
+        return false;
+    }
+
+    if request.headers().contains("If-None-Match") {
+        let Some(etag) = etag else {
+            return false;
+        };
+        // The comparison is weak, so `W/"x"` matches `"x"` (RFC 9110, 8.8.3.2).
+        return request
+            .headers()
+            .get_all("If-None-Match")
+            .flat_map(|v| v.split(','))
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag);
+    }
+
+    let since = request
+        .header("If-Modified-Since")
+        .and_then(parse_http_date);
+    match (since, modified) {
+        (Some(since), Some(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// The ranges of a file that a `GET` asks for, unless its `If-Range` shows that the client's copy
+/// is out of date and needs replacing as a whole (RFC 9110, 13.1.5).
+fn requested_ranges(
+    request: &Request,
+    etag: Option<&str>,
+    modified: Option<SystemTime>,
+    length: u64,
+) -> Ranges {
+    let Some(range) = request.header("Range") else {
+        return Ranges::Ignored;
+    };
+    if !matches!(request.method(), Method::Get) {
+        return Ranges::Ignored;
+    }
+    if let Some(if_range) = request.header("If-Range").map(str::trim) {
+        // Unlike for If-None-Match, the comparison is strong, so a weak tag never matches.
+        let unchanged = if if_range.starts_with('"') || if_range.starts_with("W/") {
+            etag == Some(if_range)
+        } else {
+            parse_http_date(if_range).is_some_and(|date| modified == Some(date))
+        };
+        if !unchanged {
+            return Ranges::Ignored;
+        }
+    }
+    Ranges::parse(range, length)
+}
+
+/// Guesses a `Content-Type` from a file's extension.
+pub fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" | "log" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, ops::Deref};
+
+    use super::*;
+    use crate::{request::Version, router::Router};
+
+    /// A document root in the system's temporary directory, which is removed when it is dropped.
+    struct DocumentRoot(PathBuf);
+
+    impl Deref for DocumentRoot {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl AsRef<Path> for DocumentRoot {
+        fn as_ref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for DocumentRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A fresh document root with a few files in it.
+    fn document_root(name: &str) -> DocumentRoot {
+        let root =
+            std::env::temp_dir().join(format!("web-server-files-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("index.html"), "<h1>home</h1>").unwrap();
+        fs::write(root.join("docs/index.html"), "<h1>docs</h1>").unwrap();
+        fs::write(root.join("logo.png"), [0x89, b'P', b'N', b'G']).unwrap();
+        fs::write(root.join("digits.txt"), "0123456789").unwrap();
+        DocumentRoot(root)
+    }
+
+    fn request(method: &str, path: &str, headers: &str) -> Request {
+        let raw = format!("{method} {path} HTTP/1.1\r\n{headers}\r\n");
+        Request::read(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn serve(files: &StaticFiles, path: &str, headers: &str) -> Response {
+        let request = request("GET", path, headers);
+        files.serve(&request, &crate::request::percent_decode(&path[1..]))
+    }
+
+    #[test]
+    fn serves_files_with_content_type() {
+        let root = document_root("types");
+        let files = StaticFiles::new(&root).unwrap();
+        let response = serve(&files, "/logo.png", "");
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.headers().get("Content-Type"), Some("image/png"));
+        assert_eq!(response.body().len(), Some(4));
+        assert!(response.headers().contains("ETag"));
+        assert!(response.headers().contains("Last-Modified"));
+    }
+
+    #[test]
+    fn directories_serve_index() {
+        let root = document_root("index");
+        let files = StaticFiles::new(&root).unwrap();
+        assert_eq!(serve(&files, "/", "").body().len(), Some(13));
+        assert_eq!(serve(&files, "/docs/", "").body().len(), Some(13));
+
+        let response = serve(&files, "/docs", "");
+        assert_eq!(response.status(), StatusCode::MovedPermanently);
+        assert_eq!(response.headers().get("Location"), Some("/docs/"));
+    }
+
+    #[test]
+    fn directory_redirects_stay_on_the_host() {
+        let root = document_root("redirect");
+        fs::create_dir_all(root.join("new docs")).unwrap();
+        let files = StaticFiles::new(&root).unwrap();
+        let router = Router::new().get("/*path", move |request| {
+            files.serve(request, request.param("path").unwrap())
+        });
+
+        for (target, location) in [
+            ("//docs", "/docs/"),
+            ("///docs?page=2", "/docs/?page=2"),
+            ("/./docs", "/docs/"),
+            ("/new%20docs", "/new%20docs/"),
+        ] {
+            let response = router.handle(request("GET", target, ""));
+            assert_eq!(response.status(), StatusCode::MovedPermanently, "{target}");
+            assert_eq!(
+                response.headers().get("Location"),
+                Some(location),
+                "{target}"
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_to_escape_root() {
+        let root = document_root("escape");
+        let files = StaticFiles::new(root.join("docs")).unwrap();
+        assert_eq!(
+            serve(&files, "/../index.html", "").status(),
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            serve(&files, "/%2e%2e/index.html", "").status(),
+            StatusCode::Forbidden
+        );
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("logo.png"), root.join("docs/link.png")).unwrap();
+            assert_eq!(
+                serve(&files, "/link.png", "").status(),
+                StatusCode::Forbidden
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_index_links_out_of_root() {
+        let root = document_root("index-link");
+        let files = StaticFiles::new(root.join("docs")).unwrap();
+        fs::create_dir(root.join("docs/linked")).unwrap();
+        std::os::unix::fs::symlink(root.join("index.html"), root.join("docs/linked/index.html"))
+            .unwrap();
+        let response = serve(&files, "/linked/", "");
+        assert_eq!(response.status(), StatusCode::Forbidden);
+        assert_eq!(response.body().len(), Some(0));
+
+        // A link that stays within the root is followed.
+        fs::create_dir(root.join("docs/inside")).unwrap();
+        std::os::unix::fs::symlink(
+            root.join("docs/index.html"),
+            root.join("docs/inside/index.html"),
+        )
+        .unwrap();
+        assert_eq!(serve(&files, "/inside/", "").status(), StatusCode::Ok);
+    }
+
+    #[test]
+    fn missing_files() {
+        let root = document_root("missing");
+        fs::write(root.join("404.html"), "gone").unwrap();
+        let files = StaticFiles::new(&root).unwrap();
+        assert_eq!(
+            serve(&files, "/nope.html", "").status(),
+            StatusCode::NotFound
+        );
+        assert_eq!(serve(&files, "/nope.html", "").body().len(), Some(0));
+
+        let files = files.with_not_found_page("404.html");
+        let response = serve(&files, "/nope.html", "");
+        assert_eq!(response.status(), StatusCode::NotFound);
+        assert_eq!(response.body().len(), Some(4));
+        assert_eq!(
+            response.headers().get("Content-Type"),
+            Some("text/html; charset=utf-8")
+        );
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let root = document_root("conditional");
+        let files = StaticFiles::new(&root).unwrap();
+        let response = serve(&files, "/logo.png", "");
+        let etag = response.headers().get("ETag").unwrap().to_string();
+        let modified = response.headers().get("Last-Modified").unwrap().to_string();
+
+        let matching = format!("If-None-Match: \"nope\", W/{etag}\r\n");
+        assert_eq!(
+            serve(&files, "/logo.png", &matching).status(),
+            StatusCode::NotModified
+        );
+        let stale = "If-None-Match: \"nope\"\r\n";
+        assert_eq!(serve(&files, "/logo.png", stale).status(), StatusCode::Ok);
+
+        let since = format!("If-Modified-Since: {modified}\r\n");
+        assert_eq!(
+            serve(&files, "/logo.png", &since).status(),
+            StatusCode::NotModified
+        );
+        let before = "If-Modified-Since: Thu, 01 Jan 1970 00:00:00 GMT\r\n";
+        assert_eq!(serve(&files, "/logo.png", before).status(), StatusCode::Ok);
+
+        // If-None-Match wins over If-Modified-Since when both are sent.
+        let both = format!("{stale}{since}");
+        assert_eq!(serve(&files, "/logo.png", &both).status(), StatusCode::Ok);
+    }
+
+    fn read_body(mut response: Response) -> String {
+        let mut body = String::new();
+        if let Body::Reader { mut reader, .. } = response.take_body() {
+            reader.read_to_string(&mut body).unwrap();
+        }
+        body
+    }
+
+    #[test]
+    fn range_requests() {
+        let root = document_root("ranges");
+        let files = StaticFiles::new(&root).unwrap();
+        let whole = serve(&files, "/digits.txt", "");
+        assert_eq!(whole.headers().get("Accept-Ranges"), Some("bytes"));
+        let etag = whole.headers().get("ETag").unwrap().to_string();
+        let modified = whole.headers().get("Last-Modified").unwrap().to_string();
+
+        let response = serve(&files, "/digits.txt", "Range: bytes=2-4\r\n");
+        assert_eq!(response.status(), StatusCode::PartialContent);
+        assert_eq!(
+            response.headers().get("Content-Range"),
+            Some("bytes 2-4/10")
+        );
+        assert_eq!(response.headers().get("ETag"), Some(etag.as_str()));
+        assert_eq!(read_body(response), "234");
+
+        let response = serve(&files, "/digits.txt", "Range: bytes=0-0,-2\r\n");
+        assert!(response
+            .headers()
+            .get("Content-Type")
+            .unwrap()
+            .starts_with("multipart/byteranges; boundary="));
+        let body = read_body(response);
+        assert!(body.contains("Content-Range: bytes 0-0/10\r\n\r\n0\r\n"));
+        assert!(body.contains("Content-Range: bytes 8-9/10\r\n\r\n89\r\n"));
+
+        let response = serve(&files, "/digits.txt", "Range: bytes=10-\r\n");
+        assert_eq!(response.status(), StatusCode::RangeNotSatisfiable);
+        assert_eq!(response.headers().get("Content-Range"), Some("bytes */10"));
+        let response = serve(&files, "/digits.txt", "Range: lines=1-2\r\n");
+        assert_eq!(response.status(), StatusCode::Ok);
+
+        for (if_range, status) in [
+            (etag.clone(), StatusCode::PartialContent),
+            (format!("W/{etag}"), StatusCode::Ok),
+            ("\"stale\"".to_string(), StatusCode::Ok),
+            (modified, StatusCode::PartialContent),
+            ("Thu, 01 Jan 1970 00:00:00 GMT".to_string(), StatusCode::Ok),
+        ] {
+            let headers = format!("Range: bytes=5-\r\nIf-Range: {if_range}\r\n");
+            let response = serve(&files, "/digits.txt", &headers);
+            assert_eq!(response.status(), status, "{if_range}");
+        }
+    }
+
+    #[test]
+    fn head_requests() {
+        let root = document_root("head");
+        let files = StaticFiles::new(&root).unwrap();
+        let router = Router::new().get("/*path", move |request| {
+            files.serve(request, request.param("path").unwrap())
+        });
+        let get = router.handle(request("GET", "/logo.png", ""));
+        let head = router.handle(request("HEAD", "/logo.png", ""));
+        assert_eq!(head.status(), StatusCode::Ok);
+        for name in ["Content-Type", "ETag", "Last-Modified"] {
+            assert!(head.headers().contains(name), "{name}");
+            assert_eq!(head.headers().get(name), get.headers().get(name), "{name}");
+        }
+        let etag = head.headers().get("ETag").unwrap().to_string();
+
+        let mut bytes = Vec::new();
+        head.write_for(&mut bytes, Method::Head, Version::Http11)
+            .unwrap();
+        let raw = String::from_utf8(bytes).unwrap();
+        assert!(raw.contains("Content-Length: 4\r\n"));
+        assert!(raw.ends_with("\r\n\r\n"));
+
+        let matching = format!("If-None-Match: {etag}\r\n");
+        let response = router.handle(request("HEAD", "/logo.png", &matching));
+        assert_eq!(response.status(), StatusCode::NotModified);
+    }
+}
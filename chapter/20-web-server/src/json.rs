@@ -0,0 +1,273 @@
+//! JSON request bodies and responses, for small APIs, using [serde](https://serde.rs).
+//!
+//! [`Json<T>`] takes a request body apart into a `T`, refusing ones that aren't JSON with a JSON
+//! error of their own, and turns a `T` back into a response. [`handler`] puts the two together:
+//!
+//! ```
+//! use serde::{Deserialize, Serialize};
+//! use web_server::{
+//!     json::{self, Json},
+//!     request::Request,
+//!     response::{Response, StatusCode},
+//!     router::Router,
+//! };
+//!
+//! #[derive(Deserialize)]
+//! struct NewTodo {
+//!     title: String,
+//! }
+//!
+//! #[derive(Serialize)]
+//! struct Todo {
+//!     id: u64,
+//!     title: String,
+//! }
+//!
+//! let router = Router::new().post(
+//!     "/todos",
+//!     json::handler(|_: &Request, Json(todo): Json<NewTodo>| {
+//!         let todo = Todo { id: 1, title: todo.title };
+//!         Json(todo).with_status(StatusCode::Created)
+//!     }),
+//! );
+//!
+//! let raw = b"POST /todos HTTP/1.1\r\nContent-Type: application/json\r\n\
+//!     Content-Length: 17\r\n\r\n{\"title\":\"milk\"}\n";
+//! let request = Request::read(&mut &raw[..]).unwrap().unwrap();
+//! let response = router.handle(request);
+//! assert_eq!(response.status(), StatusCode::Created);
+//! ```
+
+use std::{error::Error, fmt};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    request::Request,
+    response::{Response, StatusCode},
+};
+
+/// How large a body [`Json::from_request`] takes; the server's own
+/// [`max_body_bytes`](crate::request::Limits::max_body_bytes) applies first.
+pub const DEFAULT_LIMIT: usize = 1024 * 1024;
+
+/// A value that is read from, or written as, JSON.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> Json<T> {
+    /// Parses the request body, which must have a JSON `Content-Type` and be at most
+    /// [`DEFAULT_LIMIT`] bytes.
+    pub fn from_request(request: &Request) -> Result<Json<T>, JsonRejection> {
+        Json::from_request_with_limit(request, DEFAULT_LIMIT)
+    }
+
+    /// Like [`from_request`](Json::from_request), but for bodies of at most `limit` bytes.
+    pub fn from_request_with_limit(
+        request: &Request,
+        limit: usize,
+    ) -> Result<Json<T>, JsonRejection> {
+        if !request.header("Content-Type").is_some_and(is_json) {
+            return Err(JsonRejection::UnsupportedMediaType);
+        }
+        if request.body().len() > limit {
+            return Err(JsonRejection::TooLarge { limit });
+        }
+        serde_json::from_slice(request.body())
+            .map(Json)
+            .map_err(|e| JsonRejection::Malformed(e.to_string()))
+    }
+}
+
+impl<T: Serialize> Json<T> {
+    /// A response with the value as its body, and `status`.
+    ///
+    /// A value that can't be serialized, such as a map whose keys aren't strings, is a bug, and
+    /// gets a `500 Internal Server Error`.
+    pub fn with_status(self, status: StatusCode) -> Response {
+        match serde_json::to_string(&self.0) {
+            Ok(body) => Response::json(status, body),
+            Err(e) => {
+                error!("Failed to serialize a JSON response: {e}");
+                error_response(StatusCode::InternalServerError, "Internal Server Error")
+            }
+        }
+    }
+}
+
+/// A `200 OK` with the value as its body.
+impl<T: Serialize> From<Json<T>> for Response {
+    fn from(json: Json<T>) -> Response {
+        json.with_status(StatusCode::Ok)
+    }
+}
+
+/// Why a request body couldn't be taken as JSON.
+///
+/// It turns into a response whose body is a JSON object with the reason as its `error`, such as
+/// `{"error":"expected `,` or `}` at line 1 column 9"}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JsonRejection {
+    /// The `Content-Type` isn't `application/json`, or another type ending in `+json`.
+    UnsupportedMediaType,
+    /// The body is longer than `limit` bytes.
+    TooLarge { limit: usize },
+    /// The body isn't JSON, or doesn't have the shape that was expected, for the given reason.
+    Malformed(String),
+}
+
+impl JsonRejection {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            JsonRejection::UnsupportedMediaType => StatusCode::UnsupportedMediaType,
+            JsonRejection::TooLarge { .. } => StatusCode::PayloadTooLarge,
+            JsonRejection::Malformed(_) => StatusCode::BadRequest,
+        }
+    }
+}
+
+impl fmt::Display for JsonRejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JsonRejection::UnsupportedMediaType => {
+                f.write_str("expected a body with Content-Type: application/json")
+            }
+            JsonRejection::TooLarge { limit } => {
+                write!(f, "the body is larger than {limit} bytes")
+            }
+            JsonRejection::Malformed(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl Error for JsonRejection {}
+
+impl From<JsonRejection> for Response {
+    fn from(rejection: JsonRejection) -> Response {
+        error_response(rejection.status(), &rejection.to_string())
+    }
+}
+
+/// A handler that parses the request body as a `T` before calling `f` with it, and answers for
+/// it if the body won't parse.
+///
+/// `f` returns anything that turns into a response, such as a [`Json`] or a [`Response`].
+pub fn handler<T, R, F>(f: F) -> impl Fn(&Request) -> Response + Send + Sync + 'static
+where
+    T: DeserializeOwned,
+    R: Into<Response>,
+    F: Fn(&Request, Json<T>) -> R + Send + Sync + 'static,
+{
+    move |request| match Json::from_request(request) {
+        Ok(json) => f(request, json).into(),
+        Err(rejection) => rejection.into(),
+    }
+}
+
+/// Whether a `Content-Type` is JSON: `application/json`, or a type like
+/// `application/problem+json`.
+fn is_json(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence == "application/json"
+        || essence
+            .strip_prefix("application/")
+            .is_some_and(|subtype| subtype.ends_with("+json"))
+}
+
+fn error_response(status: StatusCode, reason: &str) -> Response {
+    Response::json(status, serde_json::json!({ "error": reason }).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::response::Body;
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    fn request(content_type: &str, body: &str) -> Request {
+        let raw = format!(
+            "POST / HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n{body}",
+            body.len()
+        );
+        Request::read(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn body(response: &Response) -> &str {
+        match response.body() {
+            Body::Bytes(bytes) => std::str::from_utf8(bytes).unwrap(),
+            body => panic!("unexpected body {body:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_bodies() {
+        let Json(point) =
+            Json::<Point>::from_request(&request("application/json", r#"{"x":1,"y":2}"#)).unwrap();
+        assert_eq!(point, Point { x: 1, y: 2 });
+        let charset = request("Application/JSON; charset=utf-8", r#"{"x":1,"y":2}"#);
+        assert!(Json::<Point>::from_request(&charset).is_ok());
+        let problem = request("application/problem+json", r#"{"x":1,"y":2}"#);
+        assert!(Json::<Point>::from_request(&problem).is_ok());
+    }
+
+    #[test]
+    fn rejects_bodies() {
+        let text = request("text/plain", r#"{"x":1,"y":2}"#);
+        assert_eq!(
+            Json::<Point>::from_request(&text),
+            Err(JsonRejection::UnsupportedMediaType)
+        );
+
+        let big = request("application/json", r#"{"x":1,"y":2}"#);
+        let rejection = Json::<Point>::from_request_with_limit(&big, 8).unwrap_err();
+        assert_eq!(rejection, JsonRejection::TooLarge { limit: 8 });
+        assert_eq!(
+            Response::from(rejection).status(),
+            StatusCode::PayloadTooLarge
+        );
+
+        for malformed in [r#"{"x":1,"#, r#"{"x":1}"#, r#"{"x":"one","y":2}"#] {
+            let rejection =
+                Json::<Point>::from_request(&request("application/json", malformed)).unwrap_err();
+            assert!(matches!(rejection, JsonRejection::Malformed(_)));
+            let response = Response::from(rejection);
+            assert_eq!(response.status(), StatusCode::BadRequest);
+            assert_eq!(
+                response.headers().get("Content-Type"),
+                Some("application/json")
+            );
+            let error: serde_json::Value = serde_json::from_str(body(&response)).unwrap();
+            assert!(error["error"].is_string(), "{error}");
+        }
+    }
+
+    #[test]
+    fn typed_handlers() {
+        let flip = handler(|_, Json(point): Json<Point>| {
+            Json(Point {
+                x: point.y,
+                y: point.x,
+            })
+        });
+        let response = flip(&request("application/json", r#"{"x":1,"y":2}"#));
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(body(&response), r#"{"x":2,"y":1}"#);
+
+        let response = flip(&request("application/json", "null"));
+        assert_eq!(response.status(), StatusCode::BadRequest);
+        let response = flip(&request("text/plain", r#"{"x":1,"y":2}"#));
+        assert_eq!(response.status(), StatusCode::UnsupportedMediaType);
+    }
+}
@@ -1,11 +1,94 @@
 use std::{
-    sync::{mpsc, Arc, Mutex},
+    any::Any,
+    error::Error,
+    fmt, io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex, MutexGuard, PoisonError,
+    },
     thread,
+    time::{Duration, Instant},
 };
 
+// First, so that its macros can be used in the modules after it.
+#[macro_use]
+pub mod log;
+
+mod base64;
+mod compress;
+pub mod config;
+pub mod date;
+pub mod files;
+#[cfg(feature = "json")]
+pub mod json;
+pub mod middleware;
+mod poll;
+mod queue;
+pub mod range;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+mod sha1;
+pub mod signal;
+pub mod sse;
+pub mod stats;
+mod stealing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod websocket;
+
+use queue::{JobQueue, Pop};
+use stats::{PoolStats, Recorder};
+
+/// Runs jobs on a pool of threads.
+///
+/// By default the pool has a fixed number of threads, but it can be allowed to grow when every
+/// thread is busy, shrinking back once threads have been idle for a while, with
+/// [`ThreadPoolBuilder::max_threads`]. It can also be resized at any time with
+/// [`ThreadPool::resize`].
+///
+/// A job that panics doesn't take its thread down with it: the panic is caught, counted and passed
+/// to the pool's panic hook, if it has one. Should a worker thread die anyway, it is replaced.
+///
+/// Jobs wait in a queue until a thread is free. By default the queue can grow without limit, but
+/// it can be given a capacity and an [`OverflowPolicy`] with [`ThreadPool::builder`]. How the
+/// queue is organized is up to the pool's [`Scheduler`].
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<Shared>,
+    overflow_policy: OverflowPolicy,
+}
+
+/// What the pool and its worker threads share.
+struct Shared {
+    queue: JobQueue,
+    workers: Mutex<Vec<Slot>>,
+    /// How many workers there are, and how many there may be. These only change while `workers`
+    /// is locked, but can be read without it.
+    live: AtomicUsize,
+    min_threads: AtomicUsize,
+    max_threads: AtomicUsize,
+    /// How many workers are running a job.
+    busy: AtomicUsize,
+    /// How long a worker beyond the minimum waits for a job before exiting.
+    idle_timeout: Duration,
+    /// Worker threads are named this, followed by their id.
+    thread_name: String,
+    completed: AtomicU64,
+    panics: AtomicUsize,
+    panic_hook: Option<PanicHook>,
+    wait_time: Recorder,
+    run_time: Recorder,
+}
+
+type PanicHook = Box<dyn Fn(&JobPanic) + Send + Sync + 'static>;
+
+/// A worker's place in the pool, indexed by its id. A respawned worker takes over its
+/// predecessor's slot, and a new worker takes the first slot that isn't active.
+struct Slot {
+    thread: Option<thread::JoinHandle<()>>,
+    active: bool,
 }
 
 impl ThreadPool {
@@ -15,71 +98,1008 @@ impl ThreadPool {
     ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero.
+    /// The `new` function will panic if the size is zero, or if a thread can't be spawned. Use
+    /// [`ThreadPool::build`] to handle those errors instead.
     pub fn new(size: usize) -> ThreadPool {
-        assert!(size > 0);
+        ThreadPool::build(size).unwrap_or_else(|e| panic!("could not create thread pool: {e}"))
+    }
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(size);
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
+    /// Creates a new `ThreadPool` with `size` threads, or returns why it couldn't.
+    ///
+    /// If only some of the threads could be spawned, those are shut down again before this
+    /// returns.
+    pub fn build(size: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::builder(size).build()
+    }
 
-        ThreadPool {
-            workers,
-            sender: Some(sender),
+    /// Starts configuring a `ThreadPool` with `size` threads.
+    pub fn builder(size: usize) -> ThreadPoolBuilder {
+        ThreadPoolBuilder {
+            size,
+            max_threads: None,
+            idle_timeout: Duration::from_secs(60),
+            thread_name: String::from("worker"),
+            scheduler: Scheduler::WorkStealing,
+            queue_capacity: None,
+            overflow_policy: OverflowPolicy::Block,
+            panic_hook: None,
         }
     }
 
-    pub fn execute<F>(&self, f: F)
+    /// Queues `f` to run on one of the pool's threads.
+    ///
+    /// If the queue is full, what happens depends on the pool's [`OverflowPolicy`]. When the job
+    /// can't be queued, it is handed back in the error.
+    pub fn execute<F>(&self, f: F) -> Result<(), ExecuteError>
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        let job = Job {
+            run: Box::new(f),
+            queued_at: Instant::now(),
+        };
+
+        match self.shared.queue.push(job, self.overflow_policy) {
+            Ok(()) => {
+                self.grow_if_busy();
+                Ok(())
+            }
+            Err((job, ExecuteErrorKind::Full))
+                if self.overflow_policy == OverflowPolicy::CallerRuns =>
+            {
+                (job.run)();
+                Ok(())
+            }
+            Err((job, kind)) => Err(ExecuteError { job: job.run, kind }),
+        }
+    }
+
+    /// Queues `f` to run on one of the pool's threads, returning a handle to wait for its result.
+    ///
+    /// If `f` panics, the panic is returned by the handle, and isn't counted by
+    /// [`ThreadPool::panic_count`] or passed to the panic hook.
+    ///
+    /// ```
+    /// use web_server::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let handles: Vec<_> = (1..=3).map(|n| pool.spawn(move || n * n)).collect();
+    /// let squares: Vec<u32> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+    /// assert_eq!(squares, [1, 4, 9]);
+    /// ```
+    pub fn spawn<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let job = move || {
+            // The handle may have been dropped, in which case nobody wants the result.
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(f)));
+        };
+        // If the job can't be queued, or is evicted from the queue later, it's dropped along with
+        // its sender, so the handle reports it as cancelled.
+        let _ = self.execute(job);
+
+        JobHandle { receiver }
+    }
+
+    /// How many jobs have panicked since the pool was created.
+    pub fn panic_count(&self) -> usize {
+        self.shared.panics.load(Ordering::Relaxed)
+    }
+
+    /// Takes a snapshot of what the pool is doing.
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
 
-        self.sender.as_ref().unwrap().send(job).unwrap();
+    /// Returns a handle for taking snapshots of the pool's stats, which can be cloned and sent to
+    /// other threads, such as to serve them.
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// How many threads the pool has right now.
+    pub fn size(&self) -> usize {
+        self.shared.live.load(Ordering::SeqCst)
+    }
+
+    /// Changes the pool to have exactly `size` threads from now on, so it no longer grows or
+    /// shrinks by itself.
+    ///
+    /// New threads are started before this returns. Surplus threads exit once they finish their
+    /// current job, so [`ThreadPool::size`] may take a moment to come down.
+    pub fn resize(&self, size: usize) -> Result<(), PoolCreationError> {
+        if size == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+
+        let mut workers = self.shared.workers();
+        self.shared.min_threads.store(size, Ordering::SeqCst);
+        self.shared.max_threads.store(size, Ordering::SeqCst);
+        while self.shared.live.load(Ordering::SeqCst) < size {
+            Worker::spawn(&self.shared, &mut workers).map_err(PoolCreationError::Spawn)?;
+        }
+        drop(workers);
+        // Idle workers only notice they're surplus when they wake up.
+        self.shared.queue.wake_all();
+        Ok(())
+    }
+
+    /// Adds a thread if there are more jobs than threads that aren't busy, and the pool may grow.
+    fn grow_if_busy(&self) {
+        let shared = &self.shared;
+        let needs_thread = || {
+            let live = shared.live.load(Ordering::SeqCst);
+            live < shared.max_threads.load(Ordering::SeqCst)
+                && shared.busy.load(Ordering::SeqCst) + shared.queue.len() > live
+        };
+        if !needs_thread() {
+            return;
+        }
+
+        let mut workers = shared.workers();
+        // Another thread may have added one in the meantime.
+        if needs_thread() {
+            if let Err(e) = Worker::spawn(shared, &mut workers) {
+                error!("Could not add a worker: {e}");
+            }
+        }
     }
 }
 
 impl Drop for ThreadPool {
     fn drop(&mut self) {
-        drop(self.sender.take());
+        self.shared.queue.close();
+
+        let size = self.shared.workers().len();
+        for id in 0..size {
+            // A worker that dies now is still replaced, and the replacement must be joined too. It
+            // is always in place by the time the thread it replaces has finished.
+            loop {
+                // Not in the loop's condition, where the lock would be held while joining.
+                let thread = self.shared.workers()[id].thread.take();
+                let Some(thread) = thread else {
+                    break;
+                };
+                debug!("Shutting down worker {id}");
+                if thread.join().is_err() {
+                    warn!("Worker {id} had panicked");
+                }
+            }
+        }
+    }
+}
+
+/// Takes snapshots of a [`ThreadPool`]'s stats, from [`ThreadPool::stats_handle`].
+#[derive(Clone)]
+pub struct StatsHandle {
+    shared: Arc<Shared>,
+}
+
+impl StatsHandle {
+    /// Takes a snapshot of what the pool is doing. After the pool has been dropped, this is what it
+    /// was doing at the end.
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+}
+
+/// Configures a [`ThreadPool`] before it's created, with [`ThreadPool::builder`].
+pub struct ThreadPoolBuilder {
+    size: usize,
+    max_threads: Option<usize>,
+    idle_timeout: Duration,
+    thread_name: String,
+    scheduler: Scheduler,
+    queue_capacity: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    panic_hook: Option<PanicHook>,
+}
+
+impl ThreadPoolBuilder {
+    /// Lets the pool grow to `max` threads while every thread is busy. The extra threads exit again
+    /// once they have been idle for the [idle timeout](ThreadPoolBuilder::idle_timeout), down to
+    /// the size the pool was created with.
+    pub fn max_threads(mut self, max: usize) -> ThreadPoolBuilder {
+        self.max_threads = Some(max);
+        self
+    }
+
+    /// How long a thread beyond the pool's minimum size waits for a job before exiting; the
+    /// default is a minute.
+    pub fn idle_timeout(mut self, timeout: Duration) -> ThreadPoolBuilder {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Names the threads `name` followed by their id, such as `worker-0`, which is the default.
+    /// The names show up in panic messages and debuggers.
+    pub fn thread_name(mut self, name: impl Into<String>) -> ThreadPoolBuilder {
+        self.thread_name = name.into();
+        self
+    }
+
+    /// Decides how jobs are handed out to the threads; the default is work stealing.
+    pub fn scheduler(mut self, scheduler: Scheduler) -> ThreadPoolBuilder {
+        self.scheduler = scheduler;
+        self
+    }
+
+    /// Limits how many jobs can wait for a thread. What happens to a job queued beyond that is
+    /// decided by the [`OverflowPolicy`].
+    pub fn queue_capacity(mut self, capacity: usize) -> ThreadPoolBuilder {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// Decides what [`ThreadPool::execute`] does when the queue is full; the default is to block.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> ThreadPoolBuilder {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Calls `hook` on the worker thread whenever a job panics, after the panic has been caught.
+    ///
+    /// ```
+    /// use std::sync::mpsc;
+    /// use web_server::ThreadPool;
+    ///
+    /// let (sender, receiver) = mpsc::channel();
+    /// let sender = std::sync::Mutex::new(sender);
+    /// let pool = ThreadPool::builder(1)
+    ///     .on_panic(move |panic| sender.lock().unwrap().send(panic.message.clone()).unwrap())
+    ///     .build()
+    ///     .unwrap();
+    ///
+    /// pool.execute(|| panic!("oops")).unwrap();
+    /// assert_eq!(receiver.recv().unwrap(), "oops");
+    /// ```
+    pub fn on_panic<F>(mut self, hook: F) -> ThreadPoolBuilder
+    where
+        F: Fn(&JobPanic) + Send + Sync + 'static,
+    {
+        self.panic_hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Creates the pool, or returns why it couldn't.
+    ///
+    /// If only some of the threads could be spawned, those are shut down again before this
+    /// returns.
+    pub fn build(self) -> Result<ThreadPool, PoolCreationError> {
+        if self.size == 0 {
+            return Err(PoolCreationError::ZeroSize);
+        }
+        if self.queue_capacity == Some(0) {
+            return Err(PoolCreationError::ZeroCapacity);
+        }
+        let max_threads = self.max_threads.unwrap_or(self.size);
+        if max_threads < self.size {
+            return Err(PoolCreationError::MaxBelowSize);
+        }
+
+        // Dropping this on an error closes the queue and joins the workers spawned so far.
+        let pool = ThreadPool {
+            shared: Arc::new(Shared {
+                queue: JobQueue::new(self.scheduler, max_threads, self.queue_capacity),
+                workers: Mutex::new(Vec::with_capacity(max_threads)),
+                live: AtomicUsize::new(0),
+                min_threads: AtomicUsize::new(self.size),
+                max_threads: AtomicUsize::new(max_threads),
+                busy: AtomicUsize::new(0),
+                idle_timeout: self.idle_timeout,
+                thread_name: self.thread_name,
+                completed: AtomicU64::new(0),
+                panics: AtomicUsize::new(0),
+                panic_hook: self.panic_hook,
+                wait_time: Recorder::new(),
+                run_time: Recorder::new(),
+            }),
+            overflow_policy: self.overflow_policy,
+        };
+        for _ in 0..self.size {
+            let mut workers = pool.shared.workers();
+            Worker::spawn(&pool.shared, &mut workers).map_err(PoolCreationError::Spawn)?;
+        }
+
+        Ok(pool)
+    }
+}
+
+/// How a [`ThreadPool`] hands out jobs to its threads.
+///
+/// Both behave the same as far as [`ThreadPool::execute`] is concerned, including the capacity
+/// and [`OverflowPolicy`]. The `scheduler` benchmark compares them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scheduler {
+    /// A single queue, which every thread takes the oldest job from. Jobs start strictly in the
+    /// order they were queued, but every thread contends on one lock to take them.
+    SharedQueue,
+    /// A queue for each thread, which jobs are dealt out to in turn. A thread that runs out of
+    /// jobs steals from the others, so they rarely contend, at the cost of jobs not starting
+    /// strictly in order. A job queued from one of the pool's threads goes on that thread's queue.
+    WorkStealing,
+}
+
+/// What [`ThreadPool::execute`] does with a job when the pool's queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait until a thread takes a job off the queue.
+    Block,
+    /// Return an [`ExecuteError`] of kind [`ExecuteErrorKind::Full`], handing the job back.
+    Reject,
+    /// Drop the job that has waited longest to make room.
+    DropOldest,
+    /// Run the job right away, on the thread that called `execute`. This slows the caller down
+    /// to the pace of the pool, and a panic in the job is the caller's.
+    CallerRuns,
+}
+
+/// A job that panicked, as passed to the hook set with [`ThreadPoolBuilder::on_panic`].
+#[derive(Clone, Debug)]
+pub struct JobPanic {
+    /// The id of the worker that ran the job.
+    pub worker: usize,
+    /// The message the job panicked with, if it was a string.
+    pub message: String,
+}
+
+impl JobPanic {
+    fn new(worker: usize, payload: &(dyn Any + Send)) -> JobPanic {
+        JobPanic {
+            worker,
+            message: panic_message(payload),
+        }
+    }
+}
+
+/// The message that `panic!` was called with, which is almost always a string.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("Box<dyn Any>")
+    }
+}
+
+/// Waits for the result of a job queued with [`ThreadPool::spawn`].
+///
+/// Dropping the handle doesn't cancel the job; its result is just thrown away.
+#[derive(Debug)]
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<thread::Result<T>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Blocks until the job has finished, and returns its result.
+    pub fn join(self) -> Result<T, JoinError> {
+        match self.receiver.recv() {
+            Ok(result) => result.map_err(JoinError::Panicked),
+            Err(mpsc::RecvError) => Err(JoinError::Cancelled),
+        }
+    }
+
+    /// Returns the job's result if it has finished, or else gives the handle back.
+    pub fn try_join(self) -> Result<T, TryJoinError<T>> {
+        match self.receiver.try_recv() {
+            Ok(result) => result.map_err(|e| TryJoinError::Failed(JoinError::Panicked(e))),
+            Err(mpsc::TryRecvError::Empty) => Err(TryJoinError::Pending(self)),
+            Err(mpsc::TryRecvError::Disconnected) => {
+                Err(TryJoinError::Failed(JoinError::Cancelled))
+            }
+        }
+    }
+
+    /// Blocks until the job has finished, but for no longer than `timeout`, after which the
+    /// handle is given back.
+    pub fn join_timeout(self, timeout: Duration) -> Result<T, TryJoinError<T>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => result.map_err(|e| TryJoinError::Failed(JoinError::Panicked(e))),
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(TryJoinError::Pending(self)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err(TryJoinError::Failed(JoinError::Cancelled))
+            }
+        }
+    }
+}
+
+/// Why a job queued with [`ThreadPool::spawn`] has no result.
+#[derive(Debug)]
+pub enum JoinError {
+    /// The job panicked, with this payload. It can be passed to [`std::panic::resume_unwind`] to
+    /// carry on panicking in the thread that joined the job.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The job was dropped without running, because the pool's queue was full or it was shutting
+    /// down.
+    Cancelled,
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(payload) => {
+                write!(f, "job panicked: {}", panic_message(&**payload))
+            }
+            JoinError::Cancelled => f.write_str("job was cancelled before it ran"),
+        }
+    }
+}
+
+impl Error for JoinError {}
+
+/// Why [`JobHandle::try_join`] or [`JobHandle::join_timeout`] returned without a result.
+#[derive(Debug)]
+pub enum TryJoinError<T> {
+    /// The job hasn't finished yet, so here's the handle back to wait for it again.
+    Pending(JobHandle<T>),
+    /// The job won't ever have a result.
+    Failed(JoinError),
+}
+
+impl<T> fmt::Display for TryJoinError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryJoinError::Pending(_) => f.write_str("job has not finished yet"),
+            TryJoinError::Failed(e) => e.fmt(f),
+        }
+    }
+}
 
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+impl<T: fmt::Debug> Error for TryJoinError<T> {}
+
+/// Why a [`ThreadPool`] couldn't be created.
+#[derive(Debug)]
+pub enum PoolCreationError {
+    /// A pool needs at least one thread.
+    ZeroSize,
+    /// A bounded queue needs room for at least one job.
+    ZeroCapacity,
+    /// The maximum number of threads was less than the number the pool starts with.
+    MaxBelowSize,
+    /// The operating system refused to spawn a thread.
+    Spawn(io::Error),
+}
+
+impl fmt::Display for PoolCreationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolCreationError::ZeroSize => f.write_str("a thread pool needs at least one thread"),
+            PoolCreationError::ZeroCapacity => {
+                f.write_str("a thread pool's queue needs room for at least one job")
             }
+            PoolCreationError::MaxBelowSize => {
+                f.write_str("a thread pool can't have fewer threads at most than it starts with")
+            }
+            PoolCreationError::Spawn(e) => write!(f, "could not spawn a worker thread: {e}"),
+        }
+    }
+}
+
+impl Error for PoolCreationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PoolCreationError::ZeroSize
+            | PoolCreationError::ZeroCapacity
+            | PoolCreationError::MaxBelowSize => None,
+            PoolCreationError::Spawn(e) => Some(e),
+        }
+    }
+}
+
+/// A job that couldn't be queued.
+pub struct ExecuteError {
+    job: Box<dyn FnOnce() + Send + 'static>,
+    kind: ExecuteErrorKind,
+}
+
+/// Why a job couldn't be queued.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExecuteErrorKind {
+    /// The queue is full, and the pool's [`OverflowPolicy`] is to reject the job.
+    Full,
+    /// The pool is shutting down.
+    ShutDown,
+}
+
+impl ExecuteError {
+    pub fn kind(&self) -> ExecuteErrorKind {
+        self.kind
+    }
+
+    /// Takes back the job, to run it some other way or to drop it.
+    pub fn into_job(self) -> Box<dyn FnOnce() + Send + 'static> {
+        self.job
+    }
+}
+
+impl fmt::Debug for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExecuteError")
+            .field("kind", &self.kind)
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ExecuteErrorKind::Full => f.write_str("the thread pool's queue is full"),
+            ExecuteErrorKind::ShutDown => f.write_str("the thread pool is shutting down"),
         }
     }
 }
 
+impl Error for ExecuteError {}
+
+impl Shared {
+    /// Worker threads don't panic while holding this lock, but if one ever did the slots would
+    /// still be consistent, so poisoning is ignored.
+    fn workers(&self) -> MutexGuard<'_, Vec<Slot>> {
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn stats(&self) -> PoolStats {
+        let workers = self.live.load(Ordering::SeqCst);
+        let busy_workers = self.busy.load(Ordering::SeqCst);
+        PoolStats {
+            workers,
+            busy_workers,
+            idle_workers: workers.saturating_sub(busy_workers),
+            queued: self.queue.len(),
+            completed: self.completed.load(Ordering::Relaxed),
+            panicked: self.panics.load(Ordering::Relaxed) as u64,
+            wait_time: self.wait_time.snapshot(),
+            run_time: self.run_time.snapshot(),
+        }
+    }
+}
+
+/// Lives on a worker thread, to replace the thread if it dies.
 struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+    shared: Arc<Shared>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            match receiver.lock().unwrap().recv() {
-                Ok(job) => {
-                    println!("Worker {id} got a job; executing.");
-                    job();
+    /// Starts a worker in the first free slot.
+    fn spawn(shared: &Arc<Shared>, workers: &mut Vec<Slot>) -> io::Result<()> {
+        let id = match workers.iter().position(|slot| !slot.active) {
+            Some(id) => id,
+            None => {
+                workers.push(Slot {
+                    thread: None,
+                    active: false,
+                });
+                workers.len() - 1
+            }
+        };
+        // The slot's previous worker has exited, or is just about to.
+        if let Some(thread) = workers[id].thread.take() {
+            let _ = thread.join();
+        }
+
+        workers[id].thread = Some(Worker::start(id, shared)?);
+        workers[id].active = true;
+        shared.live.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn start(id: usize, shared: &Arc<Shared>) -> io::Result<thread::JoinHandle<()>> {
+        let worker = Worker {
+            id,
+            shared: Arc::clone(shared),
+        };
+        thread::Builder::new()
+            .name(format!("{}-{id}", shared.thread_name))
+            .spawn(move || worker.run())
+    }
+
+    fn run(&self) {
+        let id = self.id;
+        let shared = &self.shared;
+        shared.queue.enter(id);
+        let mut idle_since = Instant::now();
+        loop {
+            // The pool was resized to fewer threads than it has.
+            if shared.live.load(Ordering::SeqCst) > shared.max_threads.load(Ordering::SeqCst)
+                && self.retire(|| shared.max_threads.load(Ordering::SeqCst))
+            {
+                break;
+            }
+
+            let timeout = shared.idle_timeout.saturating_sub(idle_since.elapsed());
+            match shared.queue.pop(id, timeout) {
+                Pop::Job(job) => {
+                    let started = Instant::now();
+                    shared.wait_time.record(started - job.queued_at);
+                    shared.busy.fetch_add(1, Ordering::SeqCst);
+                    let result = panic::catch_unwind(AssertUnwindSafe(job.run));
+                    shared.busy.fetch_sub(1, Ordering::SeqCst);
+                    shared.run_time.record(started.elapsed());
+
+                    if result.is_ok() {
+                        shared.completed.fetch_add(1, Ordering::Relaxed);
+                    }
+                    if let Err(payload) = result {
+                        shared.panics.fetch_add(1, Ordering::Relaxed);
+                        if let Some(hook) = &shared.panic_hook {
+                            hook(&JobPanic::new(id, &*payload));
+                        }
+                    }
+                    idle_since = Instant::now();
+                }
+                Pop::Idle if idle_since.elapsed() >= shared.idle_timeout => {
+                    if self.retire(|| shared.min_threads.load(Ordering::SeqCst)) {
+                        break;
+                    }
+                    idle_since = Instant::now();
                 }
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down.");
+                Pop::Idle => {}
+                Pop::Closed => {
+                    debug!("Worker {id} disconnected; shutting down.");
                     break;
                 }
             }
-        });
+        }
+    }
 
-        Worker {
-            id,
-            thread: Some(thread),
+    /// Gives up this worker's slot if the pool has more than `limit` threads.
+    fn retire(&self, limit: impl Fn() -> usize) -> bool {
+        let mut workers = self.shared.workers();
+        if self.shared.live.load(Ordering::SeqCst) <= limit() {
+            return false;
+        }
+        workers[self.id].active = false;
+        self.shared.live.fetch_sub(1, Ordering::SeqCst);
+        debug!("Worker {} is no longer needed; shutting down.", self.id);
+        true
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // The thread is dying other than by the queue closing, such as from a panicking hook.
+        if thread::panicking() {
+            warn!("Worker {} died; respawning.", self.id);
+            match Worker::start(self.id, &self.shared) {
+                Ok(thread) => self.shared.workers()[self.id].thread = Some(thread),
+                Err(e) => error!("Could not respawn worker {}: {e}", self.id),
+            }
         }
     }
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
+/// A job waiting in the queue.
+pub(crate) struct Job {
+    run: Box<dyn FnOnce() + Send + 'static>,
+    queued_at: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn build_rejects_zero_threads() {
+        assert!(matches!(
+            ThreadPool::build(0),
+            Err(PoolCreationError::ZeroSize)
+        ));
+    }
+
+    #[test]
+    fn execute_runs_jobs() {
+        let pool = ThreadPool::build(2).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..4 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap()).unwrap();
+        }
+        let mut results: Vec<i32> = receiver.iter().take(4).collect();
+        results.sort();
+        assert_eq!(results, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn panicking_jobs_are_caught_and_counted() {
+        let (panic_sender, panics) = mpsc::channel();
+        let panic_sender = Mutex::new(panic_sender);
+        let pool = ThreadPool::builder(1)
+            .on_panic(move |panic| panic_sender.lock().unwrap().send(panic.clone()).unwrap())
+            .build()
+            .unwrap();
+
+        pool.execute(|| panic!("first")).unwrap();
+        pool.execute(|| std::panic::panic_any(42)).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send("still running").unwrap())
+            .unwrap();
+
+        assert_eq!(receiver.recv(), Ok("still running"));
+        assert_eq!(pool.panic_count(), 2);
+        let panics: Vec<JobPanic> = panics.iter().take(2).collect();
+        assert_eq!(panics[0].worker, 0);
+        assert_eq!(panics[0].message, "first");
+        assert_eq!(panics[1].message, "Box<dyn Any>");
+    }
+
+    #[test]
+    fn dead_workers_are_respawned() {
+        // A panic in the hook isn't caught, so it kills the worker thread.
+        let pool = ThreadPool::builder(1)
+            .on_panic(|_| panic!("the hook panicked too"))
+            .build()
+            .unwrap();
+        pool.execute(|| panic!("the job panicked")).unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap()).unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(()));
+        assert_eq!(pool.panic_count(), 1);
+    }
+
+    #[test]
+    fn execute_hands_back_the_job_after_shutdown() {
+        let pool = ThreadPool::build(1).unwrap();
+        pool.shared.queue.close();
+
+        let (sender, receiver) = mpsc::channel();
+        let error = pool.execute(move || sender.send(()).unwrap()).unwrap_err();
+        assert_eq!(error.kind(), ExecuteErrorKind::ShutDown);
+        error.into_job()();
+        assert_eq!(receiver.try_recv(), Ok(()));
+    }
+
+    #[test]
+    fn spawn_returns_results() {
+        let pool = ThreadPool::build(2).unwrap();
+        let handle = pool.spawn(|| (1..=10).sum::<u32>());
+        assert_eq!(handle.join().unwrap(), 55);
+
+        let handle = pool.spawn(|| -> u32 { panic!("no result") });
+        match handle.join() {
+            Err(e @ JoinError::Panicked(_)) => assert_eq!(e.to_string(), "job panicked: no result"),
+            other => panic!("unexpected {other:?}"),
+        }
+        // The pool only counts panics that nobody else is told about.
+        assert_eq!(pool.panic_count(), 0);
+    }
+
+    #[test]
+    fn try_join_and_join_timeout_give_the_handle_back() {
+        let pool = ThreadPool::build(1).unwrap();
+        let (sender, receiver) = mpsc::channel::<()>();
+        let handle = pool.spawn(move || receiver.recv().map(|_| "done"));
+
+        let Err(TryJoinError::Pending(handle)) = handle.try_join() else {
+            panic!("the job can't have finished yet");
+        };
+        let start = Instant::now();
+        let Err(TryJoinError::Pending(handle)) = handle.join_timeout(Duration::from_millis(50))
+        else {
+            panic!("the job can't have finished yet");
+        };
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        sender.send(()).unwrap();
+        let result = handle.join_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(result, Ok("done"));
+    }
+
+    #[test]
+    fn spawn_after_shutdown_is_cancelled() {
+        let pool = ThreadPool::build(1).unwrap();
+        pool.shared.queue.close();
+        assert!(matches!(pool.spawn(|| 1).join(), Err(JoinError::Cancelled)));
+    }
+
+    const SCHEDULERS: [Scheduler; 2] = [Scheduler::SharedQueue, Scheduler::WorkStealing];
+
+    /// A pool with one thread that is kept busy until the returned sender is dropped.
+    fn busy_pool(
+        scheduler: Scheduler,
+        capacity: usize,
+        policy: OverflowPolicy,
+    ) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::builder(1)
+            .scheduler(scheduler)
+            .queue_capacity(capacity)
+            .overflow_policy(policy)
+            .build()
+            .unwrap();
+        let (started_sender, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            let _ = released.recv();
+        })
+        .unwrap();
+        started.recv().unwrap();
+        (pool, release)
+    }
+
+    #[test]
+    fn reject_hands_back_the_job_when_full() {
+        for scheduler in SCHEDULERS {
+            let (pool, release) = busy_pool(scheduler, 1, OverflowPolicy::Reject);
+            let (sender, receiver) = mpsc::channel();
+            let queued = sender.clone();
+            pool.execute(move || queued.send("queued").unwrap())
+                .unwrap();
+
+            let error = pool
+                .execute(move || sender.send("rejected").unwrap())
+                .unwrap_err();
+            assert_eq!(error.kind(), ExecuteErrorKind::Full);
+            drop(release);
+            assert_eq!(receiver.recv(), Ok("queued"));
+
+            error.into_job()();
+            assert_eq!(receiver.recv(), Ok("rejected"));
+        }
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        for scheduler in SCHEDULERS {
+            let (pool, release) = busy_pool(scheduler, 1, OverflowPolicy::Block);
+            pool.execute(|| {}).unwrap();
+
+            let releaser = thread::spawn(move || {
+                thread::sleep(Duration::from_millis(50));
+                drop(release);
+            });
+            let start = Instant::now();
+            pool.execute(|| {}).unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(50));
+            releaser.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn drop_oldest_evicts_the_first_queued_job() {
+        for scheduler in SCHEDULERS {
+            let (pool, release) = busy_pool(scheduler, 2, OverflowPolicy::DropOldest);
+            let handles: Vec<_> = (0..3).map(|i| pool.spawn(move || i)).collect();
+            drop(release);
+
+            let results: Vec<_> = handles.into_iter().map(|h| h.join().ok()).collect();
+            assert_eq!(results, [None, Some(1), Some(2)]);
+        }
+    }
+
+    #[test]
+    fn caller_runs_when_full() {
+        for scheduler in SCHEDULERS {
+            let (pool, _release) = busy_pool(scheduler, 1, OverflowPolicy::CallerRuns);
+            pool.execute(|| {}).unwrap();
+
+            let caller = thread::current().id();
+            let handle = pool.spawn(move || thread::current().id() == caller);
+            assert_eq!(handle.try_join().ok(), Some(true));
+        }
+    }
+
+    #[test]
+    fn idle_threads_take_jobs_queued_behind_a_busy_one() {
+        for scheduler in SCHEDULERS {
+            let pool = ThreadPool::builder(2).scheduler(scheduler).build().unwrap();
+            let (release, released) = mpsc::channel::<()>();
+            let busy = pool.spawn(move || released.recv());
+
+            // Some of these are dealt to the busy thread, so the other one has to steal them.
+            let handles: Vec<_> = (0..10).map(|i| pool.spawn(move || i)).collect();
+            for (i, handle) in handles.into_iter().enumerate() {
+                let result = handle.join_timeout(Duration::from_secs(5));
+                assert_eq!(result.ok(), Some(i), "{scheduler:?}");
+            }
+            drop(release);
+            busy.join().unwrap().unwrap_err();
+        }
+    }
+
+    /// Waits for up to five seconds for `condition` to hold.
+    fn eventually(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+
+    #[test]
+    fn grows_while_busy_and_shrinks_when_idle() {
+        for scheduler in SCHEDULERS {
+            let pool = ThreadPool::builder(1)
+                .max_threads(3)
+                .idle_timeout(Duration::from_millis(100))
+                .scheduler(scheduler)
+                .build()
+                .unwrap();
+
+            // Three jobs that only finish once all three are running at the same time.
+            let barrier = Arc::new(std::sync::Barrier::new(3));
+            let handles: Vec<_> = (0..3)
+                .map(|_| {
+                    let barrier = Arc::clone(&barrier);
+                    pool.spawn(move || barrier.wait())
+                })
+                .collect();
+            for handle in handles {
+                handle.join_timeout(Duration::from_secs(5)).unwrap();
+            }
+            assert_eq!(pool.size(), 3);
+
+            assert!(eventually(|| pool.size() == 1), "{scheduler:?}");
+            assert_eq!(pool.spawn(|| "still works").join().unwrap(), "still works");
+        }
+    }
+
+    #[test]
+    fn resize() {
+        for scheduler in SCHEDULERS {
+            let pool = ThreadPool::builder(2).scheduler(scheduler).build().unwrap();
+            pool.resize(4).unwrap();
+            assert_eq!(pool.size(), 4);
+
+            pool.resize(1).unwrap();
+            assert!(eventually(|| pool.size() == 1), "{scheduler:?}");
+            let results: Vec<_> = (0..4).map(|i| pool.spawn(move || i)).collect();
+            let results: Vec<_> = results.into_iter().map(|h| h.join().unwrap()).collect();
+            assert_eq!(results, [0, 1, 2, 3]);
+
+            assert!(matches!(pool.resize(0), Err(PoolCreationError::ZeroSize)));
+        }
+    }
+
+    #[test]
+    fn build_rejects_max_below_size() {
+        assert!(matches!(
+            ThreadPool::builder(2).max_threads(1).build(),
+            Err(PoolCreationError::MaxBelowSize)
+        ));
+    }
+
+    #[test]
+    fn build_rejects_zero_capacity() {
+        assert!(matches!(
+            ThreadPool::builder(1).queue_capacity(0).build(),
+            Err(PoolCreationError::ZeroCapacity)
+        ));
+    }
+
+    #[test]
+    fn stats() {
+        let pool = ThreadPool::builder(2).thread_name("stats").build().unwrap();
+        let (release, released) = mpsc::channel::<()>();
+        let blocked = pool.spawn(move || released.recv());
+        assert!(eventually(|| pool.stats().busy_workers == 1));
+
+        let stats = pool.stats();
+        assert_eq!((stats.workers, stats.idle_workers), (2, 1));
+
+        pool.execute(|| panic!("counted")).unwrap();
+        let name = pool.spawn(|| thread::current().name().map(String::from));
+        assert!(name.join().unwrap().unwrap().starts_with("stats-"));
+        drop(release);
+        blocked.join().unwrap().unwrap_err();
+
+        assert!(eventually(|| pool.stats().busy_workers == 0));
+        let stats = pool.stats_handle().stats();
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.completed, 2);
+        assert_eq!(stats.panicked, 1);
+        assert_eq!(stats.wait_time.count(), 3);
+        assert_eq!(stats.run_time.count(), 3);
+    }
+}
@@ -0,0 +1,583 @@
+//! A small leveled logger shared by the pool, the server and the binary.
+//!
+//! Messages are logged with the [`error!`](crate::error), [`warn!`](crate::warn),
+//! [`info!`](crate::info), [`debug!`](crate::debug) and [`trace!`](crate::trace) macros, which go
+//! to the logger installed with [`init`], or else to standard output at [`LogLevel::Info`]. Each
+//! line is stamped with the time and the name of the thread that logged it, which for the pool's
+//! threads includes the worker's id:
+//!
+//! ```text
+//! 2026-10-18T01:32:13.123Z INFO  [worker-3] Worker 3 is no longer needed; shutting down.
+//! ```
+//!
+//! The server also logs every request it answers, in the Common or Combined Log Format.
+
+use std::{
+    fmt::{self, Write as _},
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Mutex, OnceLock, PoisonError},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    date::{format_common_log_date, format_rfc3339},
+    request::{Method, Version},
+    response::StatusCode,
+};
+
+/// Logs a message at [`LogLevel::Error`], formatted as with `format!`.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => {
+        $crate::log::logger().log($crate::log::LogLevel::Error, format_args!($($arg)+))
+    };
+}
+
+/// Logs a message at [`LogLevel::Warn`], formatted as with `format!`.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => {
+        $crate::log::logger().log($crate::log::LogLevel::Warn, format_args!($($arg)+))
+    };
+}
+
+/// Logs a message at [`LogLevel::Info`], formatted as with `format!`.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => {
+        $crate::log::logger().log($crate::log::LogLevel::Info, format_args!($($arg)+))
+    };
+}
+
+/// Logs a message at [`LogLevel::Debug`], formatted as with `format!`.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => {
+        $crate::log::logger().log($crate::log::LogLevel::Debug, format_args!($($arg)+))
+    };
+}
+
+/// Logs a message at [`LogLevel::Trace`], formatted as with `format!`.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => {
+        $crate::log::logger().log($crate::log::LogLevel::Trace, format_args!($($arg)+))
+    };
+}
+
+/// How much the server should say about what it is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogLevel, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(String::from(
+                "expected one of off, error, warn, info, debug or trace",
+            )),
+        }
+    }
+}
+
+/// How each line of the log is written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Plain text for people to read; requests are logged in the [`AccessLogFormat`].
+    Text,
+    /// One JSON object per line, for log collectors to ingest.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LogFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(String::from("expected text or json")),
+        }
+    }
+}
+
+/// Whether and how answered requests are logged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    Off,
+    /// `host ident user [date] "request" status bytes`, as written by most web servers.
+    Common,
+    /// The Common Log Format followed by the `Referer` and `User-Agent` headers, and then how
+    /// long the request took in seconds.
+    Combined,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AccessLogFormat, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(AccessLogFormat::Off),
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            _ => Err(String::from("expected off, common or combined")),
+        }
+    }
+}
+
+/// Where log lines are written.
+enum Output {
+    Stdout,
+    File(RotatingFile),
+}
+
+/// Writes messages at or above a level, and requests, to standard output or a file.
+///
+/// ```
+/// use web_server::log::{LogFormat, LogLevel, Logger};
+///
+/// let logger = Logger::new(LogLevel::Warn).with_format(LogFormat::Json);
+/// assert!(logger.enabled(LogLevel::Error));
+/// assert!(!logger.enabled(LogLevel::Info));
+/// ```
+pub struct Logger {
+    level: LogLevel,
+    format: LogFormat,
+    access_log: AccessLogFormat,
+    output: Mutex<Output>,
+}
+
+impl Logger {
+    /// Creates a logger that writes text to standard output, without logging requests.
+    pub fn new(level: LogLevel) -> Logger {
+        Logger {
+            level,
+            format: LogFormat::Text,
+            access_log: AccessLogFormat::Off,
+            output: Mutex::new(Output::Stdout),
+        }
+    }
+
+    pub fn with_format(mut self, format: LogFormat) -> Logger {
+        self.format = format;
+        self
+    }
+
+    /// Logs each answered request in `format`, at [`LogLevel::Info`].
+    pub fn with_access_log(mut self, format: AccessLogFormat) -> Logger {
+        self.access_log = format;
+        self
+    }
+
+    /// Writes to the file at `path` instead of standard output, appending to it if it exists.
+    ///
+    /// Once the file would grow beyond `max_size` bytes it is renamed to `path.1`, and any older
+    /// files are renamed in turn up to `path.<max_files>`, beyond which they are deleted.
+    pub fn with_file(
+        mut self,
+        path: impl Into<PathBuf>,
+        max_size: u64,
+        max_files: usize,
+    ) -> io::Result<Logger> {
+        let file = RotatingFile::open(path.into(), max_size, max_files)?;
+        self.output = Mutex::new(Output::File(file));
+        Ok(self)
+    }
+
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        level != LogLevel::Off && level <= self.level
+    }
+
+    /// Logs a message, if `level` is enabled. This is what the logging macros call.
+    pub fn log(&self, level: LogLevel, message: fmt::Arguments<'_>) {
+        if !self.enabled(level) {
+            return;
+        }
+        let line = format_message(self.format, SystemTime::now(), level, message);
+        self.write_line(&line);
+    }
+
+    /// Logs an answered request, if the access log is on.
+    pub fn access(&self, record: &AccessRecord<'_>) {
+        if self.access_log == AccessLogFormat::Off || !self.enabled(LogLevel::Info) {
+            return;
+        }
+        let line = match self.format {
+            LogFormat::Text => record.to_log_format(self.access_log),
+            LogFormat::Json => record.to_json(),
+        };
+        self.write_line(&line);
+    }
+
+    fn write_line(&self, line: &str) {
+        let mut output = self.output.lock().unwrap_or_else(PoisonError::into_inner);
+        // There is nowhere to report a failure to write the log, so it's ignored.
+        let _ = match &mut *output {
+            Output::Stdout => writeln!(io::stdout().lock(), "{line}"),
+            Output::File(file) => file.write_line(line),
+        };
+    }
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Installs the logger that the logging macros write to. This can only be done once, and before
+/// anything is logged; otherwise the logger is handed back.
+pub fn init(logger: Logger) -> Result<(), Logger> {
+    LOGGER.set(logger)
+}
+
+/// The logger installed with [`init`], or else one that writes text to standard output at
+/// [`LogLevel::Info`].
+pub fn logger() -> &'static Logger {
+    LOGGER.get_or_init(|| Logger::new(LogLevel::Info))
+}
+
+/// One answered request, as logged by [`Logger::access`].
+#[derive(Clone, Debug)]
+pub struct AccessRecord<'a> {
+    /// The client's address, if it's known.
+    pub peer: Option<SocketAddr>,
+    /// When the request arrived.
+    pub time: SystemTime,
+    /// The method, target and version, unless the request couldn't be parsed.
+    pub request: Option<(Method, &'a str, Version)>,
+    pub status: StatusCode,
+    /// The length of the response body, if it was known in advance.
+    pub bytes: Option<u64>,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    /// How long it took from reading the request to writing the response.
+    pub duration: Duration,
+}
+
+impl AccessRecord<'_> {
+    /// Formats the record as a line of the Common or Combined Log Format.
+    pub fn to_log_format(&self, format: AccessLogFormat) -> String {
+        let host = self
+            .peer
+            .map_or_else(|| String::from("-"), |peer| peer.ip().to_string());
+        let request = match self.request {
+            Some((method, target, version)) => format!("{method} {target} {version}"),
+            None => String::from("-"),
+        };
+        let bytes = self
+            .bytes
+            .map_or_else(|| String::from("-"), |bytes| bytes.to_string());
+        let mut line = format!(
+            "{host} - - [{}] \"{}\" {} {bytes}",
+            format_common_log_date(self.time),
+            escape_quoted(&request),
+            self.status.as_u16()
+        );
+        if format == AccessLogFormat::Combined {
+            let _ = write!(
+                line,
+                " \"{}\" \"{}\" {:.3}",
+                escape_quoted(self.referer.unwrap_or("-")),
+                escape_quoted(self.user_agent.unwrap_or("-")),
+                self.duration.as_secs_f64()
+            );
+        }
+        line
+    }
+
+    /// Formats the record as a JSON object, with every field of the Combined Log Format.
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"time\":{},\"level\":\"info\",\"thread\":{}",
+            json_string(&format_rfc3339(self.time)),
+            json_string(thread::current().name().unwrap_or("-"))
+        );
+        if let Some(peer) = self.peer {
+            let _ = write!(json, ",\"peer\":{}", json_string(&peer.to_string()));
+        }
+        if let Some((method, target, version)) = self.request {
+            let _ = write!(
+                json,
+                ",\"method\":\"{method}\",\"target\":{},\"version\":\"{version}\"",
+                json_string(target)
+            );
+        }
+        let _ = write!(json, ",\"status\":{}", self.status.as_u16());
+        if let Some(bytes) = self.bytes {
+            let _ = write!(json, ",\"bytes\":{bytes}");
+        }
+        if let Some(referer) = self.referer {
+            let _ = write!(json, ",\"referer\":{}", json_string(referer));
+        }
+        if let Some(user_agent) = self.user_agent {
+            let _ = write!(json, ",\"user_agent\":{}", json_string(user_agent));
+        }
+        let _ = write!(
+            json,
+            ",\"duration_ms\":{:.3}}}",
+            self.duration.as_secs_f64() * 1000.0
+        );
+        json
+    }
+}
+
+fn format_message(
+    format: LogFormat,
+    time: SystemTime,
+    level: LogLevel,
+    message: fmt::Arguments<'_>,
+) -> String {
+    let thread = thread::current();
+    let thread = thread.name().unwrap_or("-");
+    match format {
+        LogFormat::Text => format!(
+            "{} {:<5} [{thread}] {message}",
+            format_rfc3339(time),
+            level.as_str().to_ascii_uppercase()
+        ),
+        LogFormat::Json => format!(
+            "{{\"time\":{},\"level\":\"{}\",\"thread\":{},\"message\":{}}}",
+            json_string(&format_rfc3339(time)),
+            level.as_str(),
+            json_string(thread),
+            json_string(&message.to_string())
+        ),
+    }
+}
+
+/// Quotes and escapes `s` as a JSON string.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Escapes quotes, backslashes and control characters in a field of the Common Log Format, so a
+/// client can't forge a line with a crafted `User-Agent`.
+fn escape_quoted(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A log file that is renamed out of the way once it grows too big.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    /// How big the file is.
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let length = line.len() as u64 + 1;
+        // A line longer than the limit still gets written, to a file of its own.
+        if self.size > 0 && self.size + length > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += length;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            // The oldest file is overwritten by the one before it, and so on down to the current one.
+            for n in (1..self.max_files).rev() {
+                let _ = fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{n}"));
+        PathBuf::from(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, time::UNIX_EPOCH};
+
+    use super::*;
+
+    fn record<'a>() -> AccessRecord<'a> {
+        AccessRecord {
+            peer: Some("127.0.0.1:51234".parse().unwrap()),
+            time: UNIX_EPOCH + Duration::from_secs(784_111_777),
+            request: Some((Method::Get, "/index.html", Version::Http11)),
+            status: StatusCode::Ok,
+            bytes: Some(2326),
+            referer: None,
+            user_agent: Some("curl/8.0 \"quoted\""),
+            duration: Duration::from_micros(1_500),
+        }
+    }
+
+    #[test]
+    fn messages() {
+        let time = UNIX_EPOCH + Duration::from_millis(784_111_777_000);
+        let thread = thread::current().name().unwrap().to_string();
+
+        let text = format_message(
+            LogFormat::Text,
+            time,
+            LogLevel::Warn,
+            format_args!("a {}", 1),
+        );
+        assert_eq!(
+            text,
+            format!("1994-11-06T08:49:37.000Z WARN  [{thread}] a 1")
+        );
+
+        let json = format_message(
+            LogFormat::Json,
+            time,
+            LogLevel::Info,
+            format_args!("\"a\"\n"),
+        );
+        assert_eq!(
+            json,
+            format!(
+                "{{\"time\":\"1994-11-06T08:49:37.000Z\",\"level\":\"info\",\"thread\":\"{thread}\",\
+                 \"message\":\"\\\"a\\\"\\n\"}}"
+            )
+        );
+    }
+
+    #[test]
+    fn access_log_formats() {
+        let record = record();
+        assert_eq!(
+            record.to_log_format(AccessLogFormat::Common),
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /index.html HTTP/1.1\" 200 2326"
+        );
+        assert_eq!(
+            record.to_log_format(AccessLogFormat::Combined),
+            "127.0.0.1 - - [06/Nov/1994:08:49:37 +0000] \"GET /index.html HTTP/1.1\" 200 2326 \
+             \"-\" \"curl/8.0 \\\"quoted\\\"\" 0.002"
+        );
+
+        let unparsed = AccessRecord {
+            request: None,
+            status: StatusCode::BadRequest,
+            bytes: None,
+            ..record.clone()
+        };
+        assert!(unparsed
+            .to_log_format(AccessLogFormat::Common)
+            .ends_with("] \"-\" 400 -"));
+
+        let json = record.to_json();
+        assert!(json.contains(",\"peer\":\"127.0.0.1:51234\",\"method\":\"GET\""));
+        assert!(json.contains(",\"user_agent\":\"curl/8.0 \\\"quoted\\\"\","));
+        assert!(json.ends_with(",\"duration_ms\":1.500}"));
+        assert!(!json.contains("referer"));
+    }
+
+    #[test]
+    fn levels() {
+        let logger = Logger::new(LogLevel::Info);
+        assert!(logger.enabled(LogLevel::Warn));
+        assert!(logger.enabled(LogLevel::Info));
+        assert!(!logger.enabled(LogLevel::Debug));
+        assert!(!Logger::new(LogLevel::Off).enabled(LogLevel::Off));
+    }
+
+    #[test]
+    fn files_are_rotated() {
+        let dir = env::temp_dir().join(format!("web-server-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.log");
+
+        // Each line is 10 bytes with its newline, so each file holds two.
+        let logger = Logger::new(LogLevel::Info).with_file(&path, 20, 2).unwrap();
+        for n in 0..7 {
+            logger.write_line(&format!("line {n:04}"));
+        }
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("server.log"), "line 0006\n");
+        assert_eq!(read("server.log.1"), "line 0004\nline 0005\n");
+        assert_eq!(read("server.log.2"), "line 0002\nline 0003\n");
+        assert!(!dir.join("server.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
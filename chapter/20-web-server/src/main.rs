@@ -1,83 +1,230 @@
 use std::{
-    fs,
-    io::{prelude::*, BufReader},
-    net::{TcpListener, TcpStream},
+    env,
+    io::Write,
+    net::{SocketAddr, TcpListener},
     process::exit,
+    sync::Arc,
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use lib::ThreadPool;
+use web_server::{
+    config::ServerConfig,
+    files::StaticFiles,
+    info,
+    log::{self, Logger},
+    middleware::{CatchPanic, Compress, RequestId},
+    request::Limits,
+    response::{Body, Response, StatusCode},
+    router::Router,
+    server::{ConnectionOptions, Listener, Server},
+    signal,
+    sse::{self, Event, EventStream},
+    websocket::{self, Message},
+    OverflowPolicy, StatsHandle, ThreadPool,
+};
 
-mod lib;
+#[cfg(feature = "tls")]
+use web_server::tls::TlsAcceptor;
 
 fn main() {
-    let listener = TcpListener::bind("127.0.0.1:7878").unwrap_or_else(|e| {
-        eprintln!("Could not listen: {:?}", e);
-        exit(1);
+    if env::args()
+        .skip(1)
+        .any(|arg| arg == "--help" || arg == "-h")
+    {
+        print!("{}", ServerConfig::usage());
+        return;
+    }
+    let config = ServerConfig::load(env::args().skip(1), env::vars()).unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {e}");
+        exit(2);
     });
-    let pool = ThreadPool::new(4);
 
-    for stream in listener.incoming().take(2) {
-        let stream = stream.unwrap();
-        pool.execute(|| {
-            handle(stream);
-        });
+    let logger = Logger::new(config.log_level)
+        .with_format(config.log_format)
+        .with_access_log(config.access_log);
+    let logger = match &config.log_file {
+        Some(path) => logger
+            .with_file(path, config.log_max_size, config.log_max_files)
+            .unwrap_or_else(|e| {
+                eprintln!("Could not open log file {}: {e}", path.display());
+                exit(1);
+            }),
+        None => logger,
+    };
+    if log::init(logger).is_err() {
+        unreachable!("nothing is logged before this");
     }
 
-    println!("Shutting down.");
-}
-
-struct Response {
-    status: String,
-    contents: String,
-}
+    let files = StaticFiles::new(&config.document_root).unwrap_or_else(|e| {
+        eprintln!("Could not serve {}: {e}", config.document_root.display());
+        exit(1);
+    });
 
-impl Response {
-    fn create_ok(contents: String) -> Response {
-        Response {
-            status: String::from("HTTP/1.1 200 OK"),
-            contents,
+    let bind = |address: &SocketAddr| {
+        TcpListener::bind(address).unwrap_or_else(|e| {
+            eprintln!("Could not listen on {address}: {e}");
+            exit(1);
+        })
+    };
+    let mut listeners = Vec::new();
+    for address in &config.bind {
+        if config.https_redirect {
+            let port = config.tls_bind[0].port();
+            listeners.push(Listener::redirect_to_https(bind(address), port));
+            info!("Redirecting http://{address} to HTTPS");
+        } else {
+            listeners.push(Listener::http(bind(address)));
+            info!("Listening on http://{address}");
         }
     }
-
-    fn create_not_found(contents: String) -> Response {
-        Response {
-            status: String::from("HTTP/1.1 400 NOT FOUND"),
-            contents,
+    #[cfg(feature = "tls")]
+    if !config.tls_bind.is_empty() {
+        // Validation made sure that both are set.
+        let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) else {
+            unreachable!("tls_bind needs tls_cert and tls_key");
+        };
+        let acceptor = TlsAcceptor::from_pem_files(cert, key).unwrap_or_else(|e| {
+            eprintln!("Could not load the certificate and key: {e}");
+            exit(1);
+        });
+        for address in &config.tls_bind {
+            listeners.push(Listener::https(bind(address), acceptor.clone()));
+            info!("Listening on https://{address}");
         }
     }
+
+    let options = ConnectionOptions {
+        idle_timeout: config.keep_alive_timeout,
+        request_timeout: config.request_timeout,
+        write_timeout: config.write_timeout,
+        max_requests: config.max_requests_per_connection,
+        limits: Limits {
+            max_request_line_bytes: config.max_request_line_size,
+            max_header_bytes: config.max_header_size,
+            max_headers: config.max_headers,
+            max_body_bytes: config.max_body_size,
+        },
+        retry_after: config.retry_after,
+    };
+    // When every worker is busy and the queue is full, the server answers 503 straight away.
+    let pool = ThreadPool::builder(config.workers)
+        .max_threads(config.max_workers.unwrap_or(config.workers))
+        .idle_timeout(config.worker_idle_timeout)
+        .queue_capacity(config.queue_capacity)
+        .overflow_policy(OverflowPolicy::Reject)
+        .build()
+        .unwrap_or_else(|e| {
+            eprintln!("Could not start workers: {e}");
+            exit(1);
+        });
+    let stats = config.metrics.then(|| pool.stats_handle());
+    let mut router = routes(files.with_not_found_page("404.html"), stats);
+    if config.compression {
+        router = router.wrap(Compress::new().min_size(config.compression_min_size));
+    }
+    let server = Server::new(listeners, pool, router)
+        .unwrap_or_else(|e| {
+            eprintln!("Could not start server: {e}");
+            exit(1);
+        })
+        .with_options(options)
+        .with_io_mode(config.io_mode)
+        .with_grace_period(config.shutdown_timeout);
+
+    let handle = server.shutdown_handle();
+    if let Err(e) = signal::on_termination(move || {
+        info!("Shutting down.");
+        handle.shutdown();
+    }) {
+        eprintln!("Could not handle signals, so Ctrl+C will not shut down gracefully: {e}");
+    }
+
+    server.run();
 }
 
-fn handle(mut stream: TcpStream) {
-    let request: Vec<_> = BufReader::new(&mut stream)
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
+fn routes(files: StaticFiles, stats: Option<StatsHandle>) -> Router {
+    let files = Arc::new(files);
+    let sleep_files = Arc::clone(&files);
+    let not_found_files = Arc::clone(&files);
 
-    let response = match &request.first().unwrap()[..] {
-        "GET / HTTP/1.1" => {
-            let contents = fs::read_to_string("chapter/20-web-server/index.html").unwrap();
-            Response::create_ok(contents)
-        }
-        "GET /sleep HTTP/1.1" => {
+    let mut router = Router::new()
+        .get("/sleep", move |request| {
             // Simulate a slower computation.
             thread::sleep(Duration::from_secs(2));
-            let contents = fs::read_to_string("chapter/20-web-server/index.html").unwrap();
-            Response::create_ok(contents)
-        }
-        _ => {
-            let contents = fs::read_to_string("chapter/20-web-server/404.html").unwrap();
-            Response::create_not_found(contents)
-        }
-    };
-
-    let response = format!(
-        "{}\r\nContent-length: {}\r\n\r\n{}",
-        response.status,
-        response.contents.len(),
-        response.contents
-    );
-    stream.write_all(response.as_bytes()).unwrap();
+            sleep_files.serve(request, "index.html")
+        })
+        .get("/sleep/progress", |_| {
+            // The same computation, reporting on its progress as it goes.
+            let body = Body::from_writer(|writer| {
+                let started = Instant::now();
+                for step in 1..=4 {
+                    thread::sleep(Duration::from_millis(500));
+                    writeln!(writer, "step {step} of 4 done")?;
+                    writer.flush()?;
+                }
+                let elapsed = started.elapsed().as_millis();
+                writer.trailer("Server-Timing", format!("total;dur={elapsed}"));
+                Ok(())
+            });
+            Response::builder()
+                .content_type("text/plain; charset=utf-8")
+                .header("Trailer", "Server-Timing")
+                .body(body)
+                .build()
+        })
+        .get("/sleep/events", |request| {
+            // The same again as server-sent events, which a browser picks up where it left off if
+            // it reconnects.
+            let done: u32 = sse::last_event_id(request)
+                .and_then(|id| id.parse().ok())
+                .unwrap_or(0);
+            if done >= 4 {
+                // Nothing left to send, which 204 tells the browser so that it stops reconnecting.
+                return Response::new(StatusCode::NoContent);
+            }
+            let (events, stream) = EventStream::channel();
+            thread::spawn(move || {
+                for step in done + 1..=4 {
+                    thread::sleep(Duration::from_millis(500));
+                    let event = Event::new(format!("step {step} of 4 done"))
+                        .event("progress")
+                        .id(step.to_string());
+                    if events.send(event).is_err() {
+                        return;
+                    }
+                }
+            });
+            stream.into_response()
+        })
+        .get("/echo", |request| {
+            websocket::upgrade(request, |mut socket| {
+                while let Ok(message) = socket.recv() {
+                    match message {
+                        Message::Text(_) | Message::Binary(_) => {
+                            if socket.send(message).is_err() {
+                                break;
+                            }
+                        }
+                        Message::Close(_) => break,
+                        Message::Ping(_) | Message::Pong(_) => {}
+                    }
+                }
+            })
+        });
+    if let Some(stats) = stats {
+        router = router.get("/metrics", move |_| {
+            Response::builder()
+                .content_type("text/plain; version=0.0.4")
+                .body(stats.stats().to_prometheus("web_server_pool"))
+                .build()
+        });
+    }
+    router
+        .get("/*path", move |request| {
+            files.serve(request, request.param("path").unwrap())
+        })
+        .fallback(move |_| not_found_files.not_found())
+        .wrap(CatchPanic)
+        .wrap(RequestId)
 }
@@ -0,0 +1,286 @@
+//! Waiting for any of many sockets to become readable, for the server's event loop.
+//!
+//! Like [`signal`](crate::signal), this calls the C library directly, here for Linux's `epoll`; on
+//! other platforms [`Poller::new`] fails with [`io::ErrorKind::Unsupported`].
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    sync::Arc,
+    time::Duration,
+};
+
+/// A set of file descriptors to be told about once they have something to read.
+///
+/// It is level-triggered: a file descriptor keeps being reported for as long as there is something
+/// left to read, so an event doesn't have to be handled in one go.
+pub(crate) struct Poller {
+    imp: imp::Poller,
+}
+
+impl Poller {
+    pub fn new() -> io::Result<Poller> {
+        Ok(Poller {
+            imp: imp::Poller::new()?,
+        })
+    }
+
+    /// Starts watching `source`, such as a socket, reporting it with `token`.
+    pub fn add(&self, source: &impl imp::Source, token: u64) -> io::Result<()> {
+        self.imp.add(source, token)
+    }
+
+    /// Stops watching `source`, which must have been added.
+    pub fn remove(&self, source: &impl imp::Source) -> io::Result<()> {
+        self.imp.remove(source)
+    }
+
+    /// Creates a [`Waker`] for this poller, which reports it with `token`.
+    pub fn waker(&self, token: u64) -> io::Result<Waker> {
+        let file = self.imp.waker()?;
+        self.add(&file, token)?;
+        Ok(Waker {
+            file: Arc::new(file),
+        })
+    }
+
+    /// Waits until at least one file descriptor is ready, or for `timeout` if there is one, and
+    /// replaces the contents of `tokens` with those of the ones that are ready.
+    ///
+    /// A file descriptor is also ready when its peer hangs up or it fails, which reading tells.
+    pub fn wait(&self, tokens: &mut Vec<u64>, timeout: Option<Duration>) -> io::Result<()> {
+        tokens.clear();
+        // Round up, so that a deadline that is a fraction of a millisecond away isn't spun on.
+        let timeout = timeout.map_or(-1, |t| {
+            t.as_nanos().div_ceil(1_000_000).min(i32::MAX as u128) as i32
+        });
+        match self.imp.wait(tokens, timeout) {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Wakes a [`Poller`] that is waiting, from any thread.
+///
+/// Wakes that happen before the poller gets around to [`reset`](Waker::reset) are merged into one.
+#[derive(Clone)]
+pub(crate) struct Waker {
+    file: Arc<File>,
+}
+
+impl Waker {
+    pub fn wake(&self) -> io::Result<()> {
+        match (&*self.file).write(&1u64.to_ne_bytes()) {
+            // The counter is full, so a wake is pending anyway.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result.map(drop),
+        }
+    }
+
+    /// Stops the poller reporting wakes that it has already been woken by.
+    pub fn reset(&self) -> io::Result<()> {
+        match (&*self.file).read(&mut [0; 8]) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result.map(drop),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{
+        ffi::{c_int, c_uint},
+        fs::File,
+        io,
+        os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    };
+
+    pub use std::os::fd::AsRawFd as Source;
+
+    const EPOLL_CLOEXEC: c_int = 0o2000000;
+    const EPOLL_CTL_ADD: c_int = 1;
+    const EPOLL_CTL_DEL: c_int = 2;
+    const EPOLLIN: u32 = 0x001;
+    const EPOLLRDHUP: u32 = 0x2000;
+    const EFD_CLOEXEC: c_int = 0o2000000;
+    const EFD_NONBLOCK: c_int = 0o4000;
+
+    /// The kernel packs this structure on x86-64, and only there.
+    #[cfg_attr(target_arch = "x86_64", repr(C, packed))]
+    #[cfg_attr(not(target_arch = "x86_64"), repr(C))]
+    #[derive(Clone, Copy)]
+    struct EpollEvent {
+        events: u32,
+        data: u64,
+    }
+
+    extern "C" {
+        fn epoll_create1(flags: c_int) -> c_int;
+        fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut EpollEvent) -> c_int;
+        fn epoll_wait(
+            epfd: c_int,
+            events: *mut EpollEvent,
+            maxevents: c_int,
+            timeout: c_int,
+        ) -> c_int;
+        fn eventfd(initval: c_uint, flags: c_int) -> c_int;
+    }
+
+    /// How many events one call to `epoll_wait` can return; any more are returned by the next.
+    const MAX_EVENTS: usize = 256;
+
+    pub struct Poller {
+        epoll: OwnedFd,
+    }
+
+    fn check(result: c_int) -> io::Result<c_int> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(result)
+        }
+    }
+
+    impl Poller {
+        pub fn new() -> io::Result<Poller> {
+            // SAFETY: `epoll_create1` has no preconditions, and the descriptor it returns is ours.
+            let fd = check(unsafe { epoll_create1(EPOLL_CLOEXEC) })?;
+            Ok(Poller {
+                // SAFETY: `fd` is open and nothing else owns it.
+                epoll: unsafe { OwnedFd::from_raw_fd(fd) },
+            })
+        }
+
+        fn ctl(&self, op: c_int, fd: RawFd, event: &mut EpollEvent) -> io::Result<()> {
+            // SAFETY: the epoll descriptor is open, and `event` is valid for the call.
+            check(unsafe { epoll_ctl(self.epoll.as_raw_fd(), op, fd, event) })?;
+            Ok(())
+        }
+
+        pub fn add(&self, source: &impl Source, token: u64) -> io::Result<()> {
+            let mut event = EpollEvent {
+                events: EPOLLIN | EPOLLRDHUP,
+                data: token,
+            };
+            self.ctl(EPOLL_CTL_ADD, source.as_raw_fd(), &mut event)
+        }
+
+        pub fn remove(&self, source: &impl Source) -> io::Result<()> {
+            // Kernels before 2.6.9 insisted on an event, even though it is ignored.
+            let mut event = EpollEvent { events: 0, data: 0 };
+            self.ctl(EPOLL_CTL_DEL, source.as_raw_fd(), &mut event)
+        }
+
+        /// An eventfd, a counter that is readable while it isn't zero.
+        pub fn waker(&self) -> io::Result<File> {
+            // SAFETY: `eventfd` has no preconditions, and the descriptor it returns is ours.
+            let fd = check(unsafe { eventfd(0, EFD_CLOEXEC | EFD_NONBLOCK) })?;
+            // SAFETY: `fd` is open and nothing else owns it.
+            Ok(File::from(unsafe { OwnedFd::from_raw_fd(fd) }))
+        }
+
+        pub fn wait(&self, tokens: &mut Vec<u64>, timeout: c_int) -> io::Result<()> {
+            let mut ready = [EpollEvent { events: 0, data: 0 }; MAX_EVENTS];
+            // SAFETY: `ready` has room for `MAX_EVENTS` events.
+            let count = check(unsafe {
+                epoll_wait(
+                    self.epoll.as_raw_fd(),
+                    ready.as_mut_ptr(),
+                    MAX_EVENTS as c_int,
+                    timeout,
+                )
+            })?;
+            tokens.extend(ready[..count as usize].iter().map(|event| event.data));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::{ffi::c_int, fs::File, io};
+
+    /// Anything could be watched, since nothing ever is.
+    pub trait Source {}
+
+    impl<T> Source for T {}
+
+    pub struct Poller;
+
+    impl Poller {
+        pub fn new() -> io::Result<Poller> {
+            Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the event loop needs epoll, which is only on Linux",
+            ))
+        }
+
+        pub fn add(&self, _: &impl Source, _: u64) -> io::Result<()> {
+            unreachable!("a poller can't be created")
+        }
+
+        pub fn remove(&self, _: &impl Source) -> io::Result<()> {
+            unreachable!("a poller can't be created")
+        }
+
+        pub fn waker(&self) -> io::Result<File> {
+            unreachable!("a poller can't be created")
+        }
+
+        pub fn wait(&self, _: &mut Vec<u64>, _: c_int) -> io::Result<()> {
+            unreachable!("a poller can't be created")
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::{io::Write, os::unix::net::UnixStream, thread};
+
+    use super::*;
+
+    #[test]
+    fn reports_ready_descriptors() {
+        let poller = Poller::new().unwrap();
+        let (mut a, b) = UnixStream::pair().unwrap();
+        let (c, d) = UnixStream::pair().unwrap();
+        poller.add(&b, 1).unwrap();
+        poller.add(&d, 2).unwrap();
+
+        let mut tokens = Vec::new();
+        poller
+            .wait(&mut tokens, Some(Duration::from_millis(10)))
+            .unwrap();
+        assert!(tokens.is_empty());
+
+        a.write_all(b"x").unwrap();
+        poller.wait(&mut tokens, None).unwrap();
+        assert_eq!(tokens, [1]);
+        // Nothing was read, so it's still ready.
+        poller.wait(&mut tokens, None).unwrap();
+        assert_eq!(tokens, [1]);
+        poller.remove(&b).unwrap();
+
+        let waker = poller.waker(3).unwrap();
+        let other = waker.clone();
+        thread::spawn(move || {
+            other.wake().unwrap();
+            other.wake().unwrap();
+        })
+        .join()
+        .unwrap();
+        poller.wait(&mut tokens, None).unwrap();
+        assert_eq!(tokens, [3]);
+        waker.reset().unwrap();
+        poller
+            .wait(&mut tokens, Some(Duration::from_millis(10)))
+            .unwrap();
+        assert!(tokens.is_empty(), "both wakes were reset at once");
+
+        // A hang-up counts as ready too.
+        drop(c);
+        poller.wait(&mut tokens, None).unwrap();
+        assert_eq!(tokens, [2]);
+    }
+}
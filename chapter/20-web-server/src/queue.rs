@@ -0,0 +1,199 @@
+use std::{
+    collections::VecDeque,
+    sync::{Condvar, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use crate::{stealing::StealingQueue, ExecuteErrorKind, Job, OverflowPolicy, Scheduler};
+
+/// What a worker got from [`JobQueue::pop`].
+pub(crate) enum Pop {
+    Job(Job),
+    /// There was no job before the timeout, or the worker was woken by [`JobQueue::wake_all`].
+    Idle,
+    /// The queue has been closed and emptied.
+    Closed,
+}
+
+/// The jobs waiting for a worker, organized according to the pool's [`Scheduler`].
+pub(crate) enum JobQueue {
+    Shared(SharedQueue),
+    Stealing(StealingQueue),
+}
+
+impl JobQueue {
+    pub(crate) fn new(scheduler: Scheduler, workers: usize, capacity: Option<usize>) -> JobQueue {
+        match scheduler {
+            Scheduler::SharedQueue => JobQueue::Shared(SharedQueue::new(capacity)),
+            Scheduler::WorkStealing => JobQueue::Stealing(StealingQueue::new(workers, capacity)),
+        }
+    }
+
+    /// Called on worker `id`'s thread before it starts taking jobs.
+    pub(crate) fn enter(&self, id: usize) {
+        if let JobQueue::Stealing(queue) = self {
+            queue.enter(id);
+        }
+    }
+
+    /// Adds a job, handling a full queue according to `policy`. A job that can't be added is handed
+    /// back, which for [`OverflowPolicy::CallerRuns`] is the caller's cue to run it.
+    pub(crate) fn push(
+        &self,
+        job: Job,
+        policy: OverflowPolicy,
+    ) -> Result<(), (Job, ExecuteErrorKind)> {
+        match self {
+            JobQueue::Shared(queue) => queue.push(job, policy),
+            JobQueue::Stealing(queue) => queue.push(job, policy),
+        }
+    }
+
+    /// Takes a job for worker `id`, waiting up to `timeout` for one if there are none.
+    pub(crate) fn pop(&self, id: usize, timeout: Duration) -> Pop {
+        match self {
+            JobQueue::Shared(queue) => queue.pop(timeout),
+            JobQueue::Stealing(queue) => queue.pop(id, timeout),
+        }
+    }
+
+    /// How many jobs are waiting for a worker.
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            JobQueue::Shared(queue) => queue.len(),
+            JobQueue::Stealing(queue) => queue.len(),
+        }
+    }
+
+    /// Makes every worker that is waiting for a job return [`Pop::Idle`].
+    pub(crate) fn wake_all(&self) {
+        match self {
+            JobQueue::Shared(queue) => queue.wake_all(),
+            JobQueue::Stealing(queue) => queue.wake_all(),
+        }
+    }
+
+    /// Stops accepting jobs. Those already queued are still handed out by [`JobQueue::pop`].
+    pub(crate) fn close(&self) {
+        match self {
+            JobQueue::Shared(queue) => queue.close(),
+            JobQueue::Stealing(queue) => queue.close(),
+        }
+    }
+}
+
+/// A single queue that every worker takes jobs from, which [`ThreadPool`](crate::ThreadPool) used
+/// to keep in an `mpsc` channel. Unlike a channel, this can be bounded and can evict its oldest
+/// job.
+pub(crate) struct SharedQueue {
+    state: Mutex<State>,
+    /// Signalled when a job is pushed or the queue is closed.
+    not_empty: Condvar,
+    /// Signalled when a job is popped or the queue is closed.
+    not_full: Condvar,
+    capacity: Option<usize>,
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    closed: bool,
+}
+
+impl SharedQueue {
+    pub(crate) fn new(capacity: Option<usize>) -> SharedQueue {
+        SharedQueue {
+            state: Mutex::new(State {
+                jobs: VecDeque::new(),
+                closed: false,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+        }
+    }
+
+    pub(crate) fn push(
+        &self,
+        job: Job,
+        policy: OverflowPolicy,
+    ) -> Result<(), (Job, ExecuteErrorKind)> {
+        let mut state = self.state();
+        let mut evicted = None;
+        loop {
+            if state.closed {
+                return Err((job, ExecuteErrorKind::ShutDown));
+            }
+            if self
+                .capacity
+                .is_none_or(|capacity| state.jobs.len() < capacity)
+            {
+                break;
+            }
+            match policy {
+                OverflowPolicy::Block => {
+                    state = self
+                        .not_full
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                OverflowPolicy::Reject | OverflowPolicy::CallerRuns => {
+                    return Err((job, ExecuteErrorKind::Full));
+                }
+                OverflowPolicy::DropOldest => {
+                    evicted = state.jobs.pop_front();
+                    break;
+                }
+            }
+        }
+
+        state.jobs.push_back(job);
+        drop(state);
+        self.not_empty.notify_one();
+        // Dropping a job can run arbitrary code, such as closing a connection, so it's done
+        // without holding the lock.
+        drop(evicted);
+        Ok(())
+    }
+
+    pub(crate) fn pop(&self, timeout: Duration) -> Pop {
+        let mut state = self.state();
+        if state.jobs.is_empty() && !state.closed {
+            state = self
+                .not_empty
+                .wait_timeout(state, timeout)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+
+        if let Some(job) = state.jobs.pop_front() {
+            drop(state);
+            self.not_full.notify_one();
+            Pop::Job(job)
+        } else if state.closed {
+            Pop::Closed
+        } else {
+            Pop::Idle
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.state().jobs.len()
+    }
+
+    pub(crate) fn wake_all(&self) {
+        let _state = self.state();
+        self.not_empty.notify_all();
+    }
+
+    pub(crate) fn close(&self) {
+        self.state().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    /// Nothing panics while holding the lock, but if something ever did the queue would still be
+    /// consistent, so poisoning is ignored.
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
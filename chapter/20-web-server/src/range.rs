@@ -0,0 +1,359 @@
+//! Byte range requests (RFC 9110, 14), for resuming downloads and seeking within large files.
+
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::{BuildHasher, Hasher},
+    io::{self, Cursor, Read, Seek, SeekFrom},
+};
+
+use crate::response::{Body, Response, ResponseBuilder, StatusCode};
+
+/// More ranges than this in one request are ignored, and the whole representation is sent, rather
+/// than answering with lots of tiny parts.
+const MAX_RANGES: usize = 64;
+
+/// The bytes from `start` to `end`, both inclusive, as in a `Content-Range` header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    /// Never true, since a range holds at least one byte.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// The `Content-Range` value for this range of a representation `length` bytes long.
+    pub fn content_range(&self, length: u64) -> String {
+        format!("bytes {}-{}/{length}", self.start, self.end)
+    }
+}
+
+/// What a `Range` header asks of a representation of a given length.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// The header is malformed, uses a unit other than bytes or asks for too many ranges, so the
+    /// whole representation is sent instead.
+    Ignored,
+    /// None of the ranges overlap the representation, which calls for a `416`.
+    Unsatisfiable,
+    /// The ranges to send, in ascending order, with overlapping and adjacent ones merged.
+    Satisfiable(Vec<ByteRange>),
+}
+
+impl Ranges {
+    /// Parses a `Range` header value, such as `bytes=0-499, -500`, against a representation that is
+    /// `length` bytes long.
+    pub fn parse(value: &str, length: u64) -> Ranges {
+        let Some((unit, specs)) = value.split_once('=') else {
+            return Ranges::Ignored;
+        };
+        if !unit.trim().eq_ignore_ascii_case("bytes") {
+            return Ranges::Ignored;
+        }
+
+        let mut ranges = Vec::new();
+        for spec in specs.split(',').map(str::trim) {
+            // Empty list elements are allowed, as elsewhere in HTTP.
+            if spec.is_empty() {
+                continue;
+            }
+            let Some((first, last)) = spec.split_once('-') else {
+                return Ranges::Ignored;
+            };
+            let range = match (parse_position(first), parse_position(last)) {
+                // `-500`: the last 500 bytes.
+                (None, Some(suffix)) if first.trim().is_empty() => {
+                    (suffix > 0).then(|| ByteRange {
+                        start: length.saturating_sub(suffix),
+                        end: length.wrapping_sub(1),
+                    })
+                }
+                // `500-`: everything from byte 500 on.
+                (Some(start), None) if last.trim().is_empty() => Some(ByteRange {
+                    start,
+                    end: length.wrapping_sub(1),
+                }),
+                (Some(start), Some(end)) if start <= end => Some(ByteRange {
+                    start,
+                    end: end.min(length.wrapping_sub(1)),
+                }),
+                _ => return Ranges::Ignored,
+            };
+            // Ranges that start beyond the end are dropped; `end` has wrapped if `length` is 0.
+            if let Some(range) = range.filter(|range| range.start < length) {
+                ranges.push(range);
+            }
+            if ranges.len() > MAX_RANGES {
+                return Ranges::Ignored;
+            }
+        }
+        if ranges.is_empty() {
+            // A header with no ranges at all is malformed rather than unsatisfiable.
+            return if specs.split(',').all(|spec| spec.trim().is_empty()) {
+                Ranges::Ignored
+            } else {
+                Ranges::Unsatisfiable
+            };
+        }
+
+        ranges.sort_by_key(|range| range.start);
+        let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end.saturating_add(1) => {
+                    last.end = last.end.max(range.end);
+                }
+                _ => merged.push(range),
+            }
+        }
+        Ranges::Satisfiable(merged)
+    }
+}
+
+/// Parses the digits of a range position, or `None` if there are none.
+fn parse_position(digits: &str) -> Option<u64> {
+    let digits = digits.trim();
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    // Positions beyond any representation are as good as the largest one.
+    Some(digits.parse().unwrap_or(u64::MAX))
+}
+
+/// Answers with `ranges` of `source`, a representation of `content_type` that is `length` bytes
+/// long: `206 Partial Content` with the one range as the body, or with a `multipart/byteranges`
+/// body holding several.
+///
+/// The body is streamed from `source`, so it can be a file of any size. `builder` can carry headers
+/// that go with the whole representation, such as `ETag`.
+///
+/// ```
+/// use std::io::Cursor;
+/// use web_server::{
+///     range::{self, Ranges},
+///     response::Response,
+/// };
+///
+/// let report = b"0123456789".to_vec();
+/// let Ranges::Satisfiable(ranges) = Ranges::parse("bytes=2-4", 10) else {
+///     unreachable!()
+/// };
+/// let response =
+///     range::partial_content(Response::builder(), Cursor::new(report), "text/plain", 10, &ranges)
+///         .unwrap();
+/// assert_eq!(response.headers().get("Content-Range"), Some("bytes 2-4/10"));
+/// assert_eq!(response.body().len(), Some(3));
+/// ```
+pub fn partial_content<R: Read + Seek + Send + 'static>(
+    builder: ResponseBuilder,
+    mut source: R,
+    content_type: &str,
+    length: u64,
+    ranges: &[ByteRange],
+) -> io::Result<Response> {
+    let builder = builder.status(StatusCode::PartialContent);
+    if let [range] = ranges {
+        source.seek(SeekFrom::Start(range.start))?;
+        return Ok(builder
+            .header("Content-Range", range.content_range(length))
+            .content_type(content_type)
+            .body(Body::from_reader(
+                source.take(range.len()),
+                Some(range.len()),
+            ))
+            .build());
+    }
+
+    let boundary = format!("{:016x}", RandomState::new().build_hasher().finish());
+    let mut parts = VecDeque::new();
+    for (i, range) in ranges.iter().enumerate() {
+        // The line break before each boundary belongs to the boundary, so the first has none.
+        let separator = if i == 0 { "" } else { "\r\n" };
+        let header = format!(
+            "{separator}--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+            range.content_range(length)
+        );
+        parts.push_back(Part::Bytes(Cursor::new(header.into_bytes())));
+        parts.push_back(Part::Range(*range));
+    }
+    parts.push_back(Part::Bytes(Cursor::new(
+        format!("\r\n--{boundary}--\r\n").into_bytes(),
+    )));
+
+    let body_length = parts.iter().map(Part::len).sum();
+    Ok(builder
+        .content_type(format!("multipart/byteranges; boundary={boundary}"))
+        .body(Body::from_reader(
+            Multipart { source, parts },
+            Some(body_length),
+        ))
+        .build())
+}
+
+/// A piece of a `multipart/byteranges` body: either part headers, or a range of the source.
+enum Part {
+    Bytes(Cursor<Vec<u8>>),
+    Range(ByteRange),
+}
+
+impl Part {
+    fn len(&self) -> u64 {
+        match self {
+            Part::Bytes(bytes) => bytes.get_ref().len() as u64,
+            Part::Range(range) => range.len(),
+        }
+    }
+}
+
+/// Reads the parts of a `multipart/byteranges` body one after another, seeking to each range.
+struct Multipart<R> {
+    source: R,
+    parts: VecDeque<Part>,
+}
+
+impl<R: Read + Seek> Read for Multipart<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(part) = self.parts.front_mut() {
+            let read = match part {
+                Part::Bytes(bytes) => bytes.read(buf)?,
+                Part::Range(range) => {
+                    self.source.seek(SeekFrom::Start(range.start))?;
+                    let wanted = buf
+                        .len()
+                        .min(usize::try_from(range.len()).unwrap_or(usize::MAX));
+                    let read = self.source.read(&mut buf[..wanted])?;
+                    if read == 0 && wanted > 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    if read as u64 == range.len() {
+                        // The range is done; reading on would underflow it.
+                        self.parts.pop_front();
+                    } else {
+                        range.start += read as u64;
+                    }
+                    return Ok(read);
+                }
+            };
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            self.parts.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn satisfiable(value: &str, length: u64) -> Vec<(u64, u64)> {
+        match Ranges::parse(value, length) {
+            Ranges::Satisfiable(ranges) => ranges.iter().map(|r| (r.start, r.end)).collect(),
+            other => panic!("{value}: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parsing() {
+        assert_eq!(satisfiable("bytes=0-499", 10_000), [(0, 499)]);
+        assert_eq!(satisfiable("bytes=9500-", 10_000), [(9500, 9999)]);
+        assert_eq!(satisfiable("bytes=-500", 10_000), [(9500, 9999)]);
+        assert_eq!(satisfiable("bytes=-50000", 10_000), [(0, 9999)]);
+        assert_eq!(satisfiable("bytes=0-99999999999999999999", 10), [(0, 9)]);
+        assert_eq!(satisfiable("Bytes = 0-0 , -1", 10), [(0, 0), (9, 9)]);
+        assert_eq!(satisfiable("bytes=5-9, 20-30", 10), [(5, 9)]);
+        // Overlapping and adjacent ranges are merged, and put in order.
+        assert_eq!(
+            satisfiable("bytes=50-99, 0-10, 5-20, 21-30", 100),
+            [(0, 30), (50, 99)]
+        );
+
+        assert_eq!(Ranges::parse("bytes=10-", 10), Ranges::Unsatisfiable);
+        assert_eq!(Ranges::parse("bytes=-0", 10), Ranges::Unsatisfiable);
+        assert_eq!(Ranges::parse("bytes=0-", 0), Ranges::Unsatisfiable);
+
+        for malformed in [
+            "bytes",
+            "bytes=",
+            "bytes=,",
+            "items=0-1",
+            "bytes=1",
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes=--1",
+            "bytes=1-2-3",
+            "bytes=-",
+            "bytes=+1-2",
+            "bytes=a-5",
+        ] {
+            assert_eq!(Ranges::parse(malformed, 10), Ranges::Ignored, "{malformed}");
+        }
+        let many = (0..100)
+            .map(|i| format!("{}-{}", i * 2, i * 2))
+            .collect::<Vec<_>>();
+        let many = format!("bytes={}", many.join(","));
+        assert_eq!(Ranges::parse(&many, 1000), Ranges::Ignored);
+    }
+
+    fn body(mut response: Response) -> String {
+        let mut text = String::new();
+        match response.take_body() {
+            Body::Reader { mut reader, .. } => reader.read_to_string(&mut text).unwrap(),
+            _ => panic!("not streamed"),
+        };
+        text
+    }
+
+    #[test]
+    fn single_range() {
+        let source = Cursor::new(b"hello, world".to_vec());
+        let range = ByteRange { start: 7, end: 11 };
+        let response =
+            partial_content(Response::builder(), source, "text/plain", 12, &[range]).unwrap();
+        assert_eq!(response.status(), StatusCode::PartialContent);
+        assert_eq!(
+            response.headers().get("Content-Range"),
+            Some("bytes 7-11/12")
+        );
+        assert_eq!(response.body().len(), Some(5));
+        assert_eq!(body(response), "world");
+    }
+
+    #[test]
+    fn multiple_ranges() {
+        let source = Cursor::new(b"hello, world".to_vec());
+        let ranges = [
+            ByteRange { start: 0, end: 4 },
+            ByteRange { start: 7, end: 11 },
+        ];
+        let response =
+            partial_content(Response::builder(), source, "text/plain", 12, &ranges).unwrap();
+        let content_type = response.headers().get("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let length = response.body().len().unwrap();
+
+        let body = body(response);
+        assert_eq!(body.len() as u64, length);
+        assert_eq!(
+            body,
+            format!(
+                "--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-4/12\r\n\r\n\
+                 hello\r\n\
+                 --{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 7-11/12\r\n\r\n\
+                 world\r\n\
+                 --{boundary}--\r\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,862 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    io::{self, BufRead, Read},
+    str::FromStr,
+};
+
+use crate::response::StatusCode;
+
+/// An HTTP request method.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Connect,
+    Options,
+    Trace,
+    Patch,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Connect => "CONNECT",
+            Method::Options => "OPTIONS",
+            Method::Trace => "TRACE",
+            Method::Patch => "PATCH",
+        }
+    }
+}
+
+impl FromStr for Method {
+    type Err = ParseError;
+
+    /// Parses a method name, which (unlike header names) is case-sensitive.
+    fn from_str(s: &str) -> Result<Method, ParseError> {
+        match s {
+            "GET" => Ok(Method::Get),
+            "HEAD" => Ok(Method::Head),
+            "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "DELETE" => Ok(Method::Delete),
+            "CONNECT" => Ok(Method::Connect),
+            "OPTIONS" => Ok(Method::Options),
+            "TRACE" => Ok(Method::Trace),
+            "PATCH" => Ok(Method::Patch),
+            _ => Err(ParseError::InvalidMethod),
+        }
+    }
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The HTTP version from the request line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Version::Http10 => "HTTP/1.0",
+            Version::Http11 => "HTTP/1.1",
+        }
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// An ordered list of header fields.
+///
+/// Names are compared case-insensitively, but keep the case they were sent with.
+#[derive(Clone, Debug, Default)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers::default()
+    }
+
+    /// Returns the first value for `name`, if any.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Returns every value for `name`, in the order they were added.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Returns whether a comma-separated header such as `Connection` lists `token`.
+    pub fn has_token(&self, name: &str, token: &str) -> bool {
+        self.get_all(name)
+            .flat_map(|v| v.split(','))
+            .any(|t| t.trim().eq_ignore_ascii_case(token))
+    }
+
+    /// Adds a value, keeping any existing values for the same name.
+    pub fn append(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.entries.push((name.into(), value.into()));
+    }
+
+    /// Sets a value, replacing any existing values for the same name.
+    pub fn insert(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        let name = name.into();
+        self.remove(&name);
+        self.entries.push((name, value.into()));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Why a request could not be parsed.
+#[derive(Debug)]
+pub enum ParseError {
+    /// Reading from the connection failed.
+    Io(io::Error),
+    /// The connection closed part-way through a request.
+    UnexpectedEof,
+    /// The request line was not `method SP request-target SP HTTP-version`.
+    InvalidRequestLine,
+    InvalidMethod,
+    InvalidTarget,
+    InvalidVersion,
+    InvalidHeader,
+    InvalidContentLength,
+    /// A chunk of a `Transfer-Encoding: chunked` body was malformed.
+    InvalidChunk,
+    /// The body used a transfer coding other than `chunked`.
+    UnsupportedTransferEncoding,
+    /// The request line was longer than [`Limits::max_request_line_bytes`].
+    RequestLineTooLong,
+    /// The request line and header fields were larger than [`Limits::max_header_bytes`].
+    HeaderTooLarge,
+    /// There were more header fields than [`Limits::max_headers`].
+    TooManyHeaders,
+    /// The body was larger than [`Limits::max_body_bytes`].
+    BodyTooLarge,
+}
+
+impl ParseError {
+    /// The status code to answer a request that failed to parse with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ParseError::Io(e) if is_timeout(e) => StatusCode::RequestTimeout,
+            ParseError::InvalidMethod | ParseError::UnsupportedTransferEncoding => {
+                StatusCode::NotImplemented
+            }
+            ParseError::InvalidVersion => StatusCode::HttpVersionNotSupported,
+            ParseError::RequestLineTooLong => StatusCode::UriTooLong,
+            ParseError::HeaderTooLarge | ParseError::TooManyHeaders => {
+                StatusCode::RequestHeaderFieldsTooLarge
+            }
+            ParseError::BodyTooLarge => StatusCode::PayloadTooLarge,
+            _ => StatusCode::BadRequest,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(e) if is_timeout(e) => f.write_str("timed out reading the request"),
+            ParseError::Io(e) => write!(f, "i/o error: {e}"),
+            ParseError::UnexpectedEof => f.write_str("connection closed mid-request"),
+            ParseError::InvalidRequestLine => f.write_str("malformed request line"),
+            ParseError::InvalidMethod => f.write_str("unrecognized method"),
+            ParseError::InvalidTarget => f.write_str("malformed request target"),
+            ParseError::InvalidVersion => f.write_str("unsupported HTTP version"),
+            ParseError::InvalidHeader => f.write_str("malformed header field"),
+            ParseError::InvalidContentLength => f.write_str("invalid Content-Length"),
+            ParseError::InvalidChunk => f.write_str("malformed chunked body"),
+            ParseError::UnsupportedTransferEncoding => f.write_str("unsupported Transfer-Encoding"),
+            ParseError::RequestLineTooLong => f.write_str("request line too long"),
+            ParseError::HeaderTooLarge => f.write_str("header fields too large"),
+            ParseError::TooManyHeaders => f.write_str("too many header fields"),
+            ParseError::BodyTooLarge => f.write_str("body too large"),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a read failed because the socket's read timeout expired, which is reported as
+/// [`io::ErrorKind::WouldBlock`] on some platforms and [`io::ErrorKind::TimedOut`] on others.
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    )
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> ParseError {
+        ParseError::Io(e)
+    }
+}
+
+/// Limits on the size of a request, beyond which it is refused rather than read into memory.
+#[derive(Clone, Debug)]
+pub struct Limits {
+    /// The most bytes that the request line may take up, which in practice limits the target.
+    pub max_request_line_bytes: usize,
+    /// The most bytes that the request line and header fields may take up together.
+    pub max_header_bytes: usize,
+    /// The most header fields a request may have, however small they are.
+    pub max_headers: usize,
+    pub max_body_bytes: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_request_line_bytes: 8 * 1024,
+            max_header_bytes: 16 * 1024,
+            max_headers: 100,
+            max_body_bytes: 1024 * 1024,
+        }
+    }
+}
+
+/// A parsed HTTP/1.x request.
+#[derive(Clone, Debug)]
+pub struct Request {
+    method: Method,
+    target: String,
+    path: String,
+    query: Option<String>,
+    version: Version,
+    headers: Headers,
+    body: Vec<u8>,
+    trailers: Headers,
+    query_params: Vec<(String, String)>,
+    params: HashMap<String, String>,
+}
+
+impl Request {
+    /// Reads the next request from `reader`, with the default [`Limits`].
+    ///
+    /// Returns `Ok(None)` if the connection was closed before a request line was sent.
+    pub fn read<R: BufRead>(reader: &mut R) -> Result<Option<Request>, ParseError> {
+        Request::read_with_limits(reader, &Limits::default())
+    }
+
+    /// Reads the next request from `reader`, failing as soon as it exceeds one of `limits`.
+    pub fn read_with_limits<R: BufRead>(
+        reader: &mut R,
+        limits: &Limits,
+    ) -> Result<Option<Request>, ParseError> {
+        let mut budget = limits.max_header_bytes;
+
+        // The request line has a limit of its own, but also counts towards the header's.
+        let line_limit = limits.max_request_line_bytes.min(budget);
+        let mut line_budget = line_limit;
+        // A server should ignore at least one empty line before the request line (RFC 9112, 2.2).
+        let line = loop {
+            match read_line(reader, &mut line_budget) {
+                Ok(None) => return Ok(None),
+                Ok(Some(line)) if line.is_empty() => continue,
+                Ok(Some(line)) => break line,
+                Err(ParseError::HeaderTooLarge) if line_limit < budget => {
+                    return Err(ParseError::RequestLineTooLong);
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        budget -= line_limit - line_budget;
+
+        let (method, target, version) = parse_request_line(&line)?;
+        let (path, query) = split_target(&target)?;
+
+        let mut headers = Headers::new();
+        loop {
+            let line = read_line(reader, &mut budget)?.ok_or(ParseError::UnexpectedEof)?;
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() == limits.max_headers {
+                return Err(ParseError::TooManyHeaders);
+            }
+            let (name, value) = parse_header(&line)?;
+            headers.append(name, value);
+        }
+
+        let (body, trailers) = read_body(reader, &headers, limits)?;
+        let query_params = query.as_deref().map(parse_query).unwrap_or_default();
+
+        Ok(Some(Request {
+            method,
+            target,
+            path,
+            query,
+            version,
+            headers,
+            body,
+            trailers,
+            query_params,
+            params: HashMap::new(),
+        }))
+    }
+
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// The request-target exactly as it appeared in the request line.
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    /// The path portion of the target, without the query string.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The query string (without the leading `?`), if there was one.
+    pub fn query(&self) -> Option<&str> {
+        self.query.as_deref()
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    /// Lets middleware add or change headers before passing the request on.
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    /// Shorthand for `self.headers().get(name)`.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The fields sent after a chunked body, such as a checksum of it, which are kept apart from
+    /// the headers since they weren't known when the request was routed.
+    pub fn trailers(&self) -> &Headers {
+        &self.trailers
+    }
+
+    /// Whether the client asked for the connection to stay open after this request.
+    ///
+    /// Connections are persistent by default in HTTP/1.1, but only on request in HTTP/1.0.
+    pub fn keep_alive(&self) -> bool {
+        match self.version {
+            Version::Http11 => !self.headers.has_token("Connection", "close"),
+            Version::Http10 => self.headers.has_token("Connection", "keep-alive"),
+        }
+    }
+
+    /// Returns a path parameter captured by the matching route, such as `id` for `/users/:id`.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    pub fn params(&self) -> &HashMap<String, String> {
+        &self.params
+    }
+
+    pub(crate) fn set_params(&mut self, params: HashMap<String, String>) {
+        self.params = params;
+    }
+
+    /// Returns the first decoded value of a query string parameter.
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query_params
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Every decoded query string parameter, in order; a bare `?flag` has an empty value.
+    pub fn query_params(&self) -> &[(String, String)] {
+        &self.query_params
+    }
+}
+
+/// Decodes `%XX` escapes, replacing any resulting invalid UTF-8 with `U+FFFD`.
+pub(crate) fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            // In a query string (but not a path) `+` stands for a space.
+            let decode = |s: &str| percent_decode(&s.replace('+', " "));
+            (decode(name), decode(value))
+        })
+        .collect()
+}
+
+/// Reads a line terminated by `\n`, stripping the terminator and an optional preceding `\r`.
+///
+/// The line is charged against `budget`, and fails with [`ParseError::HeaderTooLarge`] if it doesn't
+/// fit.
+fn read_line<R: BufRead>(reader: &mut R, budget: &mut usize) -> Result<Option<String>, ParseError> {
+    let mut buf = Vec::new();
+    let read = reader.take(*budget as u64).read_until(b'\n', &mut buf)?;
+    *budget -= read;
+    if buf.pop() != Some(b'\n') {
+        return match read {
+            0 if *budget > 0 => Ok(None),
+            _ if *budget == 0 => Err(ParseError::HeaderTooLarge),
+            _ => Err(ParseError::UnexpectedEof),
+        };
+    }
+    if buf.last() == Some(&b'\r') {
+        buf.pop();
+    }
+    // Anything outside of ASCII is either obsolete or an attack, so there's no need to be lenient here.
+    if !buf.is_ascii() {
+        return Err(ParseError::InvalidHeader);
+    }
+    Ok(Some(String::from_utf8(buf).expect("ASCII is valid UTF-8")))
+}
+
+fn parse_request_line(line: &str) -> Result<(Method, String, Version), ParseError> {
+    let mut parts = line.split(' ');
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(ParseError::InvalidRequestLine);
+    };
+
+    let method = method.parse()?;
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        _ => return Err(ParseError::InvalidVersion),
+    };
+    if target.is_empty() || target.bytes().any(|b| b.is_ascii_control()) {
+        return Err(ParseError::InvalidTarget);
+    }
+
+    Ok((method, target.to_string(), version))
+}
+
+/// Splits a request-target into its path and query, accepting origin-form (`/a?b`), absolute-form
+/// (`http://host/a?b`) and asterisk-form (`*`).
+fn split_target(target: &str) -> Result<(String, Option<String>), ParseError> {
+    if target == "*" {
+        return Ok((String::from("*"), None));
+    }
+
+    let origin = if target.starts_with('/') {
+        target
+    } else if let Some((_, rest)) = target.split_once("://") {
+        rest.find('/').map_or("/", |i| &rest[i..])
+    } else {
+        return Err(ParseError::InvalidTarget);
+    };
+
+    // A fragment is never sent by a well-behaved client, but is not part of the path either way.
+    let origin = origin.split('#').next().unwrap_or_default();
+    match origin.split_once('?') {
+        Some((path, query)) => Ok((path.to_string(), Some(query.to_string()))),
+        None => Ok((origin.to_string(), None)),
+    }
+}
+
+fn parse_header(line: &str) -> Result<(&str, &str), ParseError> {
+    // Obsolete line folding (a continuation starting with whitespace) must be rejected (RFC 9112, 5.2).
+    if line.starts_with([' ', '\t']) {
+        return Err(ParseError::InvalidHeader);
+    }
+    let (name, value) = line.split_once(':').ok_or(ParseError::InvalidHeader)?;
+    if name.is_empty() || !name.bytes().all(is_token_byte) {
+        return Err(ParseError::InvalidHeader);
+    }
+    Ok((name, value.trim_matches([' ', '\t'])))
+}
+
+fn is_token_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+/// Reads the body that `headers` announce, along with any trailer fields.
+fn read_body<R: BufRead>(
+    reader: &mut R,
+    headers: &Headers,
+    limits: &Limits,
+) -> Result<(Vec<u8>, Headers), ParseError> {
+    // Transfer-Encoding overrides Content-Length when both are present (RFC 9112, 6.3).
+    if headers.contains("Transfer-Encoding") {
+        // Only `chunked` on its own is understood: a body that is also compressed, say, would reach
+        // the handler still encoded, with nothing to tell it so.
+        let mut codings = headers
+            .get_all("Transfer-Encoding")
+            .flat_map(|v| v.split(','))
+            .map(str::trim);
+        let chunked = codings
+            .next()
+            .is_some_and(|coding| coding.eq_ignore_ascii_case("chunked"));
+        if !chunked || codings.next().is_some() {
+            return Err(ParseError::UnsupportedTransferEncoding);
+        }
+        return read_chunked(reader, limits);
+    }
+
+    let mut length = None;
+    for value in headers.get_all("Content-Length") {
+        for value in value.split(',').map(str::trim) {
+            if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+                return Err(ParseError::InvalidContentLength);
+            }
+            let value: u64 = value
+                .parse()
+                .map_err(|_| ParseError::InvalidContentLength)?;
+            if length.is_some_and(|l| l != value) {
+                return Err(ParseError::InvalidContentLength);
+            }
+            length = Some(value);
+        }
+    }
+
+    let body = match length {
+        None | Some(0) => Vec::new(),
+        Some(length) if length > limits.max_body_bytes => return Err(ParseError::BodyTooLarge),
+        Some(length) => read_exact(reader, length)?,
+    };
+    Ok((body, Headers::new()))
+}
+
+fn read_exact<R: BufRead>(reader: &mut R, length: u64) -> Result<Vec<u8>, ParseError> {
+    let mut body = Vec::new();
+    reader.take(length).read_to_end(&mut body)?;
+    if (body.len() as u64) < length {
+        return Err(ParseError::UnexpectedEof);
+    }
+    Ok(body)
+}
+
+/// The longest a line introducing a chunk may be, which is plenty for a size and some extensions.
+const MAX_CHUNK_LINE: usize = 1024;
+
+fn read_chunked<R: BufRead>(
+    reader: &mut R,
+    limits: &Limits,
+) -> Result<(Vec<u8>, Headers), ParseError> {
+    let mut body = Vec::new();
+    loop {
+        let mut budget = MAX_CHUNK_LINE;
+        let line = match read_line(reader, &mut budget) {
+            Ok(line) => line.ok_or(ParseError::UnexpectedEof)?,
+            Err(ParseError::HeaderTooLarge) => return Err(ParseError::InvalidChunk),
+            Err(e) => return Err(e),
+        };
+        // Chunk extensions are allowed after a `;`, but nothing defines any, so they are ignored.
+        let size = line.split(';').next().unwrap_or_default().trim();
+        // `from_str_radix` would also take a sign, which the grammar doesn't allow.
+        if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(ParseError::InvalidChunk);
+        }
+        let size = u64::from_str_radix(size, 16).map_err(|_| ParseError::InvalidChunk)?;
+        if size == 0 {
+            break;
+        }
+        if size > limits.max_body_bytes - body.len() as u64 {
+            return Err(ParseError::BodyTooLarge);
+        }
+
+        body.extend(read_exact(reader, size)?);
+        if read_line(reader, &mut budget)?.as_deref() != Some("") {
+            return Err(ParseError::InvalidChunk);
+        }
+    }
+
+    // Trailer fields are charged against fresh header limits, since they are just as capable of
+    // being abused as headers.
+    let mut budget = limits.max_header_bytes;
+    let mut trailers = Headers::new();
+    loop {
+        let line = read_line(reader, &mut budget)?.ok_or(ParseError::UnexpectedEof)?;
+        if line.is_empty() {
+            return Ok((body, trailers));
+        }
+        if trailers.len() == limits.max_headers {
+            return Err(ParseError::TooManyHeaders);
+        }
+        let (name, value) = parse_header(&line)?;
+        trailers.append(name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(raw: &str) -> Result<Option<Request>, ParseError> {
+        Request::read(&mut raw.as_bytes())
+    }
+
+    #[test]
+    fn simple_get() {
+        let request = parse("GET /users?id=1 HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method(), Method::Get);
+        assert_eq!(request.target(), "/users?id=1");
+        assert_eq!(request.path(), "/users");
+        assert_eq!(request.query(), Some("id=1"));
+        assert_eq!(request.version(), Version::Http11);
+        assert!(request.body().is_empty());
+    }
+
+    #[test]
+    fn query_params() {
+        let request = parse("GET /?q=a+b%21&flag&q=c HTTP/1.1\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.query_param("q"), Some("a b!"));
+        assert_eq!(request.query_param("flag"), Some(""));
+        assert_eq!(request.query_params().len(), 3);
+    }
+
+    #[test]
+    fn percent_decoding() {
+        assert_eq!(percent_decode("a%20b%2fc"), "a b/c");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%e2%98%83"), "%zz\u{2603}");
+    }
+
+    #[test]
+    fn headers_are_case_insensitive() {
+        let request = parse("GET / HTTP/1.1\r\nX-Thing:  a \r\nx-thing: b\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.header("X-THING"), Some("a"));
+        assert_eq!(
+            request.headers().get_all("x-Thing").collect::<Vec<_>>(),
+            vec!["a", "b"]
+        );
+    }
+
+    #[test]
+    fn absolute_form_target() {
+        let request = parse("GET http://example.com/a/b?c HTTP/1.1\r\n\r\n")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.path(), "/a/b");
+        assert_eq!(request.query(), Some("c"));
+    }
+
+    #[test]
+    fn content_length_body() {
+        let request = parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello, world")
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.body(), b"hello");
+    }
+
+    #[test]
+    fn chunked_body() {
+        let raw = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX: y\r\n\r\n";
+        let request = parse(raw).unwrap().unwrap();
+        assert_eq!(request.body(), b"hello, world");
+        assert_eq!(request.trailers().get("X"), Some("y"));
+        assert!(!request.headers().contains("X"));
+    }
+
+    #[test]
+    fn closed_before_request() {
+        assert!(parse("").unwrap().is_none());
+        assert!(parse("\r\n").unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_requests() {
+        assert!(matches!(
+            parse("GET /\r\n\r\n"),
+            Err(ParseError::InvalidRequestLine)
+        ));
+        assert!(matches!(
+            parse("get / HTTP/1.1\r\n\r\n"),
+            Err(ParseError::InvalidMethod)
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/2.0\r\n\r\n"),
+            Err(ParseError::InvalidVersion)
+        ));
+        assert!(matches!(
+            parse("GET foo HTTP/1.1\r\n\r\n"),
+            Err(ParseError::InvalidTarget)
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nBad Name: x\r\n\r\n"),
+            Err(ParseError::InvalidHeader)
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nA: b\r\n c\r\n\r\n"),
+            Err(ParseError::InvalidHeader)
+        ));
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nHost: x\r\n"),
+            Err(ParseError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn size_limits() {
+        let limits = Limits {
+            max_header_bytes: 32,
+            max_body_bytes: 4,
+            ..Limits::default()
+        };
+        let parse = |raw: &str| Request::read_with_limits(&mut raw.as_bytes(), &limits);
+
+        assert!(parse("GET / HTTP/1.1\r\nA: 123456789\r\n\r\n").is_ok());
+        let large = "GET / HTTP/1.1\r\nA: 1234567890\r\n\r\n";
+        assert!(matches!(parse(large), Err(ParseError::HeaderTooLarge)));
+        let long_line = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(40));
+        assert!(matches!(parse(&long_line), Err(ParseError::HeaderTooLarge)));
+
+        let limits = Limits {
+            max_request_line_bytes: 24,
+            max_headers: 2,
+            max_body_bytes: 4,
+            ..Limits::default()
+        };
+        let parse = |raw: &str| Request::read_with_limits(&mut raw.as_bytes(), &limits);
+        assert!(parse("GET /12345 HTTP/1.1\r\nA: 1\r\nB: 2\r\n\r\n").is_ok());
+        let long_line = "GET /1234567890 HTTP/1.1\r\n\r\n";
+        assert!(matches!(
+            parse(long_line),
+            Err(ParseError::RequestLineTooLong)
+        ));
+        let many = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert!(matches!(parse(many), Err(ParseError::TooManyHeaders)));
+        assert_eq!(
+            ParseError::RequestLineTooLong.status(),
+            StatusCode::UriTooLong
+        );
+        let timeout = ParseError::Io(io::ErrorKind::WouldBlock.into());
+        assert_eq!(timeout.status(), StatusCode::RequestTimeout);
+
+        let body = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        assert!(matches!(parse(body), Err(ParseError::BodyTooLarge)));
+        let chunked =
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        assert!(matches!(parse(chunked), Err(ParseError::BodyTooLarge)));
+    }
+
+    #[test]
+    fn malformed_bodies() {
+        let conflicting = "POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab";
+        assert!(matches!(
+            parse(conflicting),
+            Err(ParseError::InvalidContentLength)
+        ));
+        let negative = "POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n";
+        assert!(matches!(
+            parse(negative),
+            Err(ParseError::InvalidContentLength)
+        ));
+        let short = "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc";
+        assert!(matches!(parse(short), Err(ParseError::UnexpectedEof)));
+        let gzip = "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n";
+        assert!(matches!(
+            parse(gzip),
+            Err(ParseError::UnsupportedTransferEncoding)
+        ));
+        for codings in [
+            "gzip, chunked",
+            "chunked, chunked",
+            "chunked\r\nTransfer-Encoding: gzip",
+        ] {
+            let raw = format!("POST / HTTP/1.1\r\nTransfer-Encoding: {codings}\r\n\r\n0\r\n\r\n");
+            assert!(
+                matches!(parse(&raw), Err(ParseError::UnsupportedTransferEncoding)),
+                "{codings}"
+            );
+        }
+        let bad_chunk = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n";
+        assert!(matches!(parse(bad_chunk), Err(ParseError::InvalidChunk)));
+        let signed_chunk =
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+a\r\n0123456789\r\n0\r\n\r\n";
+        assert!(matches!(parse(signed_chunk), Err(ParseError::InvalidChunk)));
+    }
+}
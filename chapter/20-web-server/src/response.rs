@@ -0,0 +1,851 @@
+use std::{
+    fmt,
+    io::{self, BufWriter, Read, Write},
+    net::{Shutdown, SocketAddr},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    date::format_http_date,
+    request::{Headers, Method, Version},
+    server::Transport,
+};
+
+/// An HTTP response status code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum StatusCode {
+    SwitchingProtocols = 101,
+    Ok = 200,
+    Created = 201,
+    Accepted = 202,
+    NoContent = 204,
+    PartialContent = 206,
+    MovedPermanently = 301,
+    Found = 302,
+    SeeOther = 303,
+    NotModified = 304,
+    TemporaryRedirect = 307,
+    PermanentRedirect = 308,
+    BadRequest = 400,
+    Unauthorized = 401,
+    Forbidden = 403,
+    NotFound = 404,
+    MethodNotAllowed = 405,
+    NotAcceptable = 406,
+    RequestTimeout = 408,
+    Conflict = 409,
+    Gone = 410,
+    LengthRequired = 411,
+    PreconditionFailed = 412,
+    PayloadTooLarge = 413,
+    UriTooLong = 414,
+    UnsupportedMediaType = 415,
+    RangeNotSatisfiable = 416,
+    UnprocessableContent = 422,
+    UpgradeRequired = 426,
+    TooManyRequests = 429,
+    RequestHeaderFieldsTooLarge = 431,
+    InternalServerError = 500,
+    NotImplemented = 501,
+    BadGateway = 502,
+    ServiceUnavailable = 503,
+    GatewayTimeout = 504,
+    HttpVersionNotSupported = 505,
+}
+
+impl StatusCode {
+    pub fn as_u16(&self) -> u16 {
+        *self as u16
+    }
+
+    /// The reason phrase recommended by RFC 9110.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            StatusCode::SwitchingProtocols => "Switching Protocols",
+            StatusCode::Ok => "OK",
+            StatusCode::Created => "Created",
+            StatusCode::Accepted => "Accepted",
+            StatusCode::NoContent => "No Content",
+            StatusCode::PartialContent => "Partial Content",
+            StatusCode::MovedPermanently => "Moved Permanently",
+            StatusCode::Found => "Found",
+            StatusCode::SeeOther => "See Other",
+            StatusCode::NotModified => "Not Modified",
+            StatusCode::TemporaryRedirect => "Temporary Redirect",
+            StatusCode::PermanentRedirect => "Permanent Redirect",
+            StatusCode::BadRequest => "Bad Request",
+            StatusCode::Unauthorized => "Unauthorized",
+            StatusCode::Forbidden => "Forbidden",
+            StatusCode::NotFound => "Not Found",
+            StatusCode::MethodNotAllowed => "Method Not Allowed",
+            StatusCode::NotAcceptable => "Not Acceptable",
+            StatusCode::RequestTimeout => "Request Timeout",
+            StatusCode::Conflict => "Conflict",
+            StatusCode::Gone => "Gone",
+            StatusCode::LengthRequired => "Length Required",
+            StatusCode::PreconditionFailed => "Precondition Failed",
+            StatusCode::PayloadTooLarge => "Content Too Large",
+            StatusCode::UriTooLong => "URI Too Long",
+            StatusCode::UnsupportedMediaType => "Unsupported Media Type",
+            StatusCode::RangeNotSatisfiable => "Range Not Satisfiable",
+            StatusCode::UnprocessableContent => "Unprocessable Content",
+            StatusCode::UpgradeRequired => "Upgrade Required",
+            StatusCode::TooManyRequests => "Too Many Requests",
+            StatusCode::RequestHeaderFieldsTooLarge => "Request Header Fields Too Large",
+            StatusCode::InternalServerError => "Internal Server Error",
+            StatusCode::NotImplemented => "Not Implemented",
+            StatusCode::BadGateway => "Bad Gateway",
+            StatusCode::ServiceUnavailable => "Service Unavailable",
+            StatusCode::GatewayTimeout => "Gateway Timeout",
+            StatusCode::HttpVersionNotSupported => "HTTP Version Not Supported",
+        }
+    }
+
+    /// Whether a response with this status may have a body at all (RFC 9110, 6.4.1).
+    pub fn allows_body(&self) -> bool {
+        !matches!(
+            self,
+            StatusCode::SwitchingProtocols | StatusCode::NoContent | StatusCode::NotModified
+        )
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.as_u16(), self.reason())
+    }
+}
+
+/// The payload of a response.
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    /// A body that is streamed from `reader` as it is written, rather than held in memory.
+    ///
+    /// If `length` is known it is sent as the `Content-Length`; otherwise the body is chunked.
+    Reader {
+        reader: Box<dyn Read + Send>,
+        length: Option<u64>,
+    },
+    /// A body that is produced as it is sent, by a function writing to a [`ResponseWriter`].
+    Writer(WriteBody),
+}
+
+/// A function that writes a streamed body; see [`Body::from_writer`].
+pub type WriteBody = Box<dyn FnOnce(&mut ResponseWriter<'_>) -> io::Result<()> + Send>;
+
+impl Body {
+    pub fn from_reader<R: Read + Send + 'static>(reader: R, length: Option<u64>) -> Body {
+        Body::Reader {
+            reader: Box::new(reader),
+            length,
+        }
+    }
+
+    /// A body that `write` produces once the response is being sent, for output that takes a while
+    /// to generate, such as the progress of a long-running job.
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use web_server::response::{Body, Response};
+    ///
+    /// let response = Response::builder()
+    ///     .content_type("text/plain")
+    ///     .body(Body::from_writer(|writer| {
+    ///         for step in 1..=3 {
+    ///             writeln!(writer, "step {step} done")?;
+    ///             // Sends what has been written so far, rather than when the buffer fills up.
+    ///             writer.flush()?;
+    ///         }
+    ///         writer.trailer("Server-Timing", "total;dur=3000");
+    ///         Ok(())
+    ///     }))
+    ///     .build();
+    ///
+    /// let mut bytes = Vec::new();
+    /// response.write_to(&mut bytes).unwrap();
+    /// let raw = String::from_utf8(bytes).unwrap();
+    /// assert!(raw.contains("Transfer-Encoding: chunked\r\n"));
+    /// assert!(raw.ends_with("\r\n0\r\nServer-Timing: total;dur=3000\r\n\r\n"));
+    /// ```
+    pub fn from_writer<F>(write: F) -> Body
+    where
+        F: FnOnce(&mut ResponseWriter<'_>) -> io::Result<()> + Send + 'static,
+    {
+        Body::Writer(Box::new(write))
+    }
+
+    /// The length of the body in bytes, if it is known up front.
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Reader { length, .. } => *length,
+            Body::Writer(_) => None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Empty => f.write_str("Empty"),
+            Body::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+            Body::Reader { length, .. } => write!(f, "Reader({length:?})"),
+            Body::Writer(_) => f.write_str("Writer"),
+        }
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(bytes: Vec<u8>) -> Body {
+        Body::Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Body {
+    fn from(bytes: &[u8]) -> Body {
+        Body::Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Body {
+    fn from(string: String) -> Body {
+        Body::Bytes(string.into_bytes())
+    }
+}
+
+impl From<&str> for Body {
+    fn from(string: &str) -> Body {
+        Body::Bytes(string.as_bytes().to_vec())
+    }
+}
+
+/// An HTTP response, ready to be written to a connection.
+///
+/// ```
+/// use web_server::response::{Response, StatusCode};
+///
+/// let response = Response::builder()
+///     .status(StatusCode::Created)
+///     .header("Location", "/users/42")
+///     .content_type("application/json")
+///     .body(r#"{"id":42}"#)
+///     .build();
+///
+/// let mut bytes = Vec::new();
+/// response.write_to(&mut bytes).unwrap();
+/// assert!(bytes.starts_with(b"HTTP/1.1 201 Created\r\n"));
+/// ```
+#[derive(Debug)]
+pub struct Response {
+    status: StatusCode,
+    headers: Headers,
+    body: Body,
+    upgrade: Option<OnUpgrade>,
+}
+
+impl Response {
+    /// Creates a response with the given status and no body.
+    pub fn new(status: StatusCode) -> Response {
+        Response {
+            status,
+            headers: Headers::new(),
+            body: Body::Empty,
+            upgrade: None,
+        }
+    }
+
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder {
+            response: Response::new(StatusCode::Ok),
+        }
+    }
+
+    /// Creates a `text/html` response.
+    pub fn html(status: StatusCode, contents: impl Into<String>) -> Response {
+        Response::builder()
+            .status(status)
+            .content_type("text/html; charset=utf-8")
+            .body(contents.into())
+            .build()
+    }
+
+    /// Creates a `text/plain` response.
+    pub fn text(status: StatusCode, contents: impl Into<String>) -> Response {
+        Response::builder()
+            .status(status)
+            .content_type("text/plain; charset=utf-8")
+            .body(contents.into())
+            .build()
+    }
+
+    /// Creates an `application/json` response from already-serialized JSON.
+    pub fn json(status: StatusCode, contents: impl Into<String>) -> Response {
+        Response::builder()
+            .status(status)
+            .content_type("application/json")
+            .body(contents.into())
+            .build()
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn set_status(&mut self, status: StatusCode) {
+        self.status = status;
+    }
+
+    pub fn headers(&self) -> &Headers {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut Headers {
+        &mut self.headers
+    }
+
+    pub fn body(&self) -> &Body {
+        &self.body
+    }
+
+    pub fn set_body(&mut self, body: impl Into<Body>) {
+        self.body = body.into();
+    }
+
+    /// Takes the body out of the response, leaving it empty.
+    pub fn take_body(&mut self) -> Body {
+        std::mem::replace(&mut self.body, Body::Empty)
+    }
+
+    /// Takes out what is to take over the connection after a `101 Switching Protocols` response.
+    pub(crate) fn take_upgrade(&mut self) -> Option<OnUpgrade> {
+        self.upgrade
+            .take()
+            .filter(|_| self.status == StatusCode::SwitchingProtocols)
+    }
+
+    /// Serializes the response as HTTP/1.1, filling in `Date`, `Content-Length` (or
+    /// `Transfer-Encoding`) and `Content-Type` when they were not set explicitly. Returns how many
+    /// bytes of body were written.
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if a header would break the framing of the
+    /// response, or with [`io::ErrorKind::UnexpectedEof`] if a streamed body was shorter than its
+    /// declared length.
+    pub fn write_to<W: Write>(self, writer: &mut W) -> io::Result<u64> {
+        self.write_for(writer, Method::Get, Version::Http11)
+    }
+
+    /// Like [`write_to`](Response::write_to), but as the answer to a `method` request from a client
+    /// that speaks `version`.
+    ///
+    /// A HEAD request gets the head that a GET would, `Content-Length` and all, but none of the
+    /// body, and a streamed one is never started. An HTTP/1.0 client can't read a chunked body, so
+    /// one of unknown length is ended by closing the connection instead, and trailers are left out.
+    pub fn write_for<W: Write>(
+        mut self,
+        writer: &mut W,
+        method: Method,
+        version: Version,
+    ) -> io::Result<u64> {
+        let body = if self.status.allows_body() {
+            self.take_body()
+        } else {
+            Body::Empty
+        };
+        let chunked = version != Version::Http10;
+
+        if !self.headers.contains("Date") {
+            self.headers
+                .insert("Date", format_http_date(SystemTime::now()));
+        }
+        if self.status.allows_body() {
+            // Only one way of framing the body may be sent, so any that the handler set which
+            // disagrees with it is dropped (RFC 9112, 6.3).
+            match body.len() {
+                Some(length) => {
+                    self.headers.remove("Transfer-Encoding");
+                    self.headers.insert("Content-Length", length.to_string());
+                }
+                None if chunked => {
+                    self.headers.remove("Content-Length");
+                    self.headers.insert("Transfer-Encoding", "chunked");
+                }
+                None => {
+                    self.headers.remove("Content-Length");
+                    self.headers.remove("Transfer-Encoding");
+                    self.headers.insert("Connection", "close");
+                }
+            }
+        }
+        if !body.is_empty() && !self.headers.contains("Content-Type") {
+            self.headers
+                .insert("Content-Type", "application/octet-stream");
+        }
+
+        let mut head = format!("HTTP/1.1 {}\r\n", self.status);
+        for (name, value) in self.headers.iter() {
+            if name.is_empty()
+                || !is_header_safe(name)
+                || !is_header_safe(value)
+                || name.contains(':')
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid response header {name:?}"),
+                ));
+            }
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        // Buffered so that a small response goes out in one write, rather than the head and then the
+        // body, which a client could read separately and Nagle's algorithm would delay.
+        let mut writer = BufWriter::new(writer);
+        writer.write_all(head.as_bytes())?;
+
+        let written = match body {
+            _ if method == Method::Head => 0,
+            Body::Empty => 0,
+            Body::Bytes(bytes) => {
+                writer.write_all(&bytes)?;
+                bytes.len() as u64
+            }
+            Body::Reader {
+                reader,
+                length: Some(length),
+            } => {
+                if io::copy(&mut reader.take(length), &mut writer)? < length {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "response body was shorter than its Content-Length",
+                    ));
+                }
+                length
+            }
+            Body::Reader {
+                mut reader,
+                length: None,
+            } => {
+                let mut body = ResponseWriter::new(&mut writer, chunked);
+                io::copy(&mut reader, &mut body)?;
+                body.finish()?
+            }
+            Body::Writer(write) => {
+                let mut body = ResponseWriter::new(&mut writer, chunked);
+                write(&mut body)?;
+                body.finish()?
+            }
+        };
+        writer.flush()?;
+        Ok(written)
+    }
+}
+
+/// How much of a streamed body is buffered before it is sent as a chunk.
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// Where a streamed body is written as it is produced; see [`Body::from_writer`].
+///
+/// What is written is buffered, and sent as a chunk of a `Transfer-Encoding: chunked` body once
+/// there is enough of it; call [`flush`](Write::flush) to send what has been written so far. An
+/// HTTP/1.0 client gets the body as it is instead, and reads until the connection is closed.
+pub struct ResponseWriter<'a> {
+    writer: &'a mut dyn Write,
+    chunked: bool,
+    buffer: Vec<u8>,
+    trailers: Headers,
+    written: u64,
+}
+
+impl<'a> ResponseWriter<'a> {
+    fn new(writer: &'a mut dyn Write, chunked: bool) -> ResponseWriter<'a> {
+        ResponseWriter {
+            writer,
+            chunked,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            trailers: Headers::new(),
+            written: 0,
+        }
+    }
+
+    /// Adds a field to send after the body, for metadata that is only known once the body is done,
+    /// such as a checksum or `Server-Timing`.
+    ///
+    /// Trailers can't be sent to HTTP/1.0 clients, and others are free to ignore them, so they
+    /// shouldn't carry anything that the response can't do without.
+    pub fn trailer(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.trailers.append(name, value);
+    }
+
+    /// How many bytes of body have been written so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Sends the buffered bytes as a chunk, unless there are none: an empty chunk ends the body.
+    fn send_chunk(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        if self.chunked {
+            write!(self.writer, "{:x}\r\n", self.buffer.len())?;
+            self.writer.write_all(&self.buffer)?;
+            self.writer.write_all(b"\r\n")?;
+        } else {
+            self.writer.write_all(&self.buffer)?;
+        }
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Ends the body with the last chunk and the trailers, returning its length.
+    fn finish(mut self) -> io::Result<u64> {
+        self.send_chunk()?;
+        if self.chunked {
+            let mut end = String::from("0\r\n");
+            for (name, value) in self.trailers.iter() {
+                if name.is_empty() || !is_header_safe(name) || !is_header_safe(value) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid trailer {name:?}"),
+                    ));
+                }
+                end.push_str(&format!("{name}: {value}\r\n"));
+            }
+            end.push_str("\r\n");
+            self.writer.write_all(end.as_bytes())?;
+        }
+        Ok(self.written)
+    }
+}
+
+impl Write for ResponseWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..taken]);
+        self.written += taken as u64;
+        if self.buffer.len() == CHUNK_SIZE {
+            self.send_chunk()?;
+        }
+        Ok(taken)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_chunk()?;
+        self.writer.flush()
+    }
+}
+
+/// Whether a header name or value can be written without injecting a line break.
+fn is_header_safe(s: &str) -> bool {
+    !s.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0)
+}
+
+/// Takes over a connection once a `101 Switching Protocols` response has been written; see
+/// [`ResponseBuilder::upgrade`].
+pub(crate) struct OnUpgrade(Box<dyn FnOnce(Upgraded) + Send>);
+
+impl OnUpgrade {
+    pub(crate) fn run(self, upgraded: Upgraded) {
+        (self.0)(upgraded)
+    }
+}
+
+impl fmt::Debug for OnUpgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("OnUpgrade")
+    }
+}
+
+/// A connection that has switched from HTTP to another protocol, which is read from and written
+/// to directly. It is closed when dropped.
+pub struct Upgraded {
+    /// What the client sent after its request, before it was handed over.
+    leftover: io::Cursor<Vec<u8>>,
+    stream: Box<dyn Transport + Send>,
+}
+
+impl Upgraded {
+    pub(crate) fn new(
+        stream: Box<dyn Transport + Send>,
+        leftover: Vec<u8>,
+    ) -> io::Result<Upgraded> {
+        // The timeouts for reading a request don't apply to whatever comes next.
+        stream.tcp().set_read_timeout(None)?;
+        Ok(Upgraded {
+            leftover: io::Cursor::new(leftover),
+            stream,
+        })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.tcp().peer_addr()
+    }
+
+    /// Sets how long a read may wait for the client before failing with
+    /// [`io::ErrorKind::WouldBlock`] (or [`TimedOut`](io::ErrorKind::TimedOut), on some
+    /// platforms); by default it waits for as long as it takes.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.tcp().set_read_timeout(timeout)
+    }
+}
+
+impl Read for Upgraded {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if (self.leftover.position() as usize) < self.leftover.get_ref().len() {
+            return self.leftover.read(buf);
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Upgraded {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Drop for Upgraded {
+    fn drop(&mut self) {
+        let _ = self.stream.close();
+        let _ = self.stream.tcp().shutdown(Shutdown::Both);
+    }
+}
+
+/// Builds a [`Response`] one part at a time; see [`Response::builder`].
+#[derive(Debug)]
+pub struct ResponseBuilder {
+    response: Response,
+}
+
+impl ResponseBuilder {
+    pub fn status(mut self, status: StatusCode) -> ResponseBuilder {
+        self.response.status = status;
+        self
+    }
+
+    /// Adds a header, keeping any earlier values for the same name.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> ResponseBuilder {
+        self.response.headers.append(name, value);
+        self
+    }
+
+    pub fn content_type(mut self, content_type: impl Into<String>) -> ResponseBuilder {
+        self.response.headers.insert("Content-Type", content_type);
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Body>) -> ResponseBuilder {
+        self.response.body = body.into();
+        self
+    }
+
+    /// Has `f` take over the connection once the response has been sent, speaking whatever
+    /// protocol the response switched to, as [`websocket::upgrade`](crate::websocket::upgrade)
+    /// does. It only happens if the status is `101 Switching Protocols`.
+    ///
+    /// `f` runs on the worker that answered the request, which it keeps until it returns.
+    pub fn upgrade<F>(mut self, f: F) -> ResponseBuilder
+    where
+        F: FnOnce(Upgraded) + Send + 'static,
+    {
+        self.response.upgrade = Some(OnUpgrade(Box::new(f)));
+        self
+    }
+
+    pub fn build(self) -> Response {
+        self.response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn serialize(response: Response) -> String {
+        let mut bytes = Vec::new();
+        response.write_to(&mut bytes).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn automatic_headers() {
+        let raw = serialize(Response::html(StatusCode::NotFound, "<h1>Oops</h1>"));
+        assert!(raw.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(raw.contains("\r\nContent-Type: text/html; charset=utf-8\r\n"));
+        assert!(raw.contains("\r\nContent-Length: 13\r\n"));
+        assert!(raw.contains("\r\nDate: "));
+        assert!(raw.ends_with("\r\n\r\n<h1>Oops</h1>"));
+    }
+
+    #[test]
+    fn binary_body() {
+        let response = Response::builder().body(vec![0u8, 159, 146, 150]).build();
+        let mut bytes = Vec::new();
+        response.write_to(&mut bytes).unwrap();
+        assert!(bytes.ends_with(b"\r\n\r\n\x00\x9f\x92\x96"));
+        let head = String::from_utf8_lossy(&bytes);
+        assert!(head.contains("Content-Type: application/octet-stream\r\n"));
+    }
+
+    #[test]
+    fn streamed_body() {
+        let body = Body::from_reader(&b"hello, world"[..], Some(5));
+        let raw = serialize(Response::builder().body(body).build());
+        assert!(raw.contains("Content-Length: 5\r\n"));
+        assert!(raw.ends_with("\r\n\r\nhello"));
+
+        let body = Body::from_reader(&b"hello"[..], None);
+        let raw = serialize(Response::builder().body(body).build());
+        assert!(raw.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!raw.contains("Content-Length"));
+        assert!(raw.ends_with("\r\n\r\n5\r\nhello\r\n0\r\n\r\n"));
+
+        // An HTTP/1.0 client gets the body as it is, ended by closing the connection.
+        let body = Body::from_reader(&b"hello"[..], None);
+        let mut bytes = Vec::new();
+        let written = Response::builder()
+            .body(body)
+            .build()
+            .write_for(&mut bytes, Method::Get, Version::Http10)
+            .unwrap();
+        assert_eq!(written, 5);
+        let raw = String::from_utf8(bytes).unwrap();
+        assert!(raw.contains("Connection: close\r\n"));
+        assert!(!raw.contains("Transfer-Encoding"));
+        assert!(raw.ends_with("\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn head_responses() {
+        let mut bytes = Vec::new();
+        let written = Response::text(StatusCode::Ok, "hello")
+            .write_for(&mut bytes, Method::Head, Version::Http11)
+            .unwrap();
+        assert_eq!(written, 0);
+        let raw = String::from_utf8(bytes).unwrap();
+        assert!(raw.contains("Content-Length: 5\r\n"));
+        assert!(raw.ends_with("\r\n\r\n"));
+
+        let body = Body::from_writer(|_| panic!("the body of a HEAD response was written"));
+        let mut bytes = Vec::new();
+        Response::builder()
+            .body(body)
+            .build()
+            .write_for(&mut bytes, Method::Head, Version::Http11)
+            .unwrap();
+        let raw = String::from_utf8(bytes).unwrap();
+        assert!(raw.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(raw.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn one_way_of_framing() {
+        let streamed = || {
+            Response::builder()
+                .header("Content-Length", "3")
+                .body(Body::from_reader(io::Cursor::new("abc"), None))
+                .build()
+        };
+        let raw = serialize(streamed());
+        assert!(raw.contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!raw.contains("Content-Length"), "{raw}");
+
+        let mut bytes = Vec::new();
+        streamed()
+            .write_for(&mut bytes, Method::Get, Version::Http10)
+            .unwrap();
+        let raw = String::from_utf8(bytes).unwrap();
+        assert!(raw.contains("Connection: close\r\n"));
+        assert!(!raw.contains("Content-Length"), "{raw}");
+        assert!(!raw.contains("Transfer-Encoding"), "{raw}");
+
+        let raw = serialize(
+            Response::builder()
+                .header("Transfer-Encoding", "chunked")
+                .body("abc")
+                .build(),
+        );
+        assert!(raw.contains("Content-Length: 3\r\n"));
+        assert!(!raw.contains("Transfer-Encoding"), "{raw}");
+    }
+
+    #[test]
+    fn written_body() {
+        let body = Body::from_writer(|writer| {
+            writer.write_all(b"hello")?;
+            writer.flush()?;
+            writer.write_all(b", ")?;
+            writer.write_all(b"world")?;
+            writer.trailer("X-Checksum", "abc");
+            Ok(())
+        });
+        let raw = serialize(Response::builder().body(body).build());
+        assert!(raw.ends_with("\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\nX-Checksum: abc\r\n\r\n"));
+
+        // Without flushing, the body goes out in chunks of a fixed size.
+        let body = Body::from_writer(|writer| writer.write_all(&[b'x'; 20_000]));
+        let raw = serialize(Response::builder().body(body).build());
+        let chunks: Vec<&str> = raw.split("\r\n").filter(|line| line.len() < 10).collect();
+        assert_eq!(
+            chunks[chunks.len() - 6..],
+            ["2000", "2000", "e20", "0", "", ""]
+        );
+
+        let body = Body::from_writer(|writer| {
+            writer.trailer("X-Evil", "a\r\nb");
+            Ok(())
+        });
+        let error = Response::builder()
+            .body(body)
+            .build()
+            .write_to(&mut Vec::new());
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
+        // A failure part way through leaves the body unfinished, rather than ending it cleanly.
+        let body = Body::from_writer(|writer| {
+            writer.write_all(b"partial")?;
+            Err(io::Error::other("job failed"))
+        });
+        let mut bytes = Vec::new();
+        let result = Response::builder().body(body).build().write_to(&mut bytes);
+        assert!(result.is_err());
+        assert!(!String::from_utf8(bytes).unwrap().ends_with("0\r\n\r\n"));
+    }
+
+    #[test]
+    fn short_streamed_body() {
+        let body = Body::from_reader(&b"abc"[..], Some(10));
+        let error = Response::builder()
+            .body(body)
+            .build()
+            .write_to(&mut Vec::new());
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn no_body_statuses() {
+        let raw = serialize(Response::text(StatusCode::NotModified, "ignored"));
+        assert!(raw.starts_with("HTTP/1.1 304 Not Modified\r\n"));
+        assert!(!raw.contains("Content-Length"));
+        assert!(raw.ends_with("\r\n\r\n"));
+    }
+
+    #[test]
+    fn rejects_header_injection() {
+        let response = Response::builder()
+            .header("X-Evil", "a\r\nSet-Cookie: b")
+            .build();
+        let error = response.write_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+
+use crate::{
+    middleware::{Middleware, Next},
+    request::{percent_decode, Method, Request},
+    response::{Response, StatusCode},
+};
+
+/// A function that turns a request into a response.
+pub type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync + 'static>;
+
+/// Dispatches requests to handlers registered by method and path pattern.
+///
+/// Patterns are made of `/`-separated segments, each of which is one of:
+///
+/// - a literal, such as `users`, which must match exactly;
+/// - a parameter, such as `:id`, which matches any single segment;
+/// - a wildcard, such as `*rest`, which matches the remainder of the path (and must be last).
+///
+/// Captured values are percent-decoded and available through [`Request::param`]. Routes are tried
+/// in the order they were added, and the first match wins.
+///
+/// ```
+/// use web_server::{
+///     response::{Response, StatusCode},
+///     router::Router,
+/// };
+///
+/// let router = Router::new()
+///     .get("/users/:id", |request| {
+///         let id = request.param("id").unwrap();
+///         Response::text(StatusCode::Ok, format!("user {id}"))
+///     })
+///     .get("/static/*path", |request| {
+///         let path = request.param("path").unwrap();
+///         Response::text(StatusCode::Ok, format!("file {path}"))
+///     });
+/// ```
+pub struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Handler>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+struct Route {
+    method: Method,
+    pattern: Vec<Segment>,
+    handler: Handler,
+}
+
+#[derive(Debug, PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router {
+            routes: Vec::new(),
+            fallback: None,
+            middleware: Vec::new(),
+        }
+    }
+
+    /// Registers `handler` for requests with the given method and path pattern.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pattern does not start with `/`, has a wildcard that is not the last segment,
+    /// or captures the same name twice.
+    pub fn route<F>(mut self, method: Method, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method,
+            pattern: parse_pattern(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    pub fn get<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn post<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<F>(self, pattern: &str, handler: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Sets the handler for requests that match no route, instead of an empty `404`.
+    pub fn fallback<F>(mut self, handler: F) -> Router
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// Runs `middleware` around every request, including those that match no route.
+    ///
+    /// Middleware added first runs first, so it sees the request before (and the response after)
+    /// any added later.
+    pub fn wrap(mut self, middleware: impl Middleware) -> Router {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Runs the middleware and then the first route matching `request`.
+    ///
+    /// A HEAD request with no route of its own runs the GET route for the path. If the path matches
+    /// a route for a different method, answers `405` with an `Allow` header listing the methods that
+    /// would have matched, or `204` with the same header for an OPTIONS request; if it matches
+    /// nothing, runs the fallback.
+    pub fn handle(&self, request: Request) -> Response {
+        Next::new(&self.middleware, &|request| self.dispatch(request)).run(request)
+    }
+
+    fn dispatch(&self, mut request: Request) -> Response {
+        let method = request.method();
+        // `OPTIONS *` asks about the server as a whole, rather than any one resource.
+        if method == Method::Options && request.path() == "*" {
+            let mut allowed = vec![Method::Options];
+            for route in &self.routes {
+                add_allowed(&mut allowed, route.method);
+            }
+            return allow(StatusCode::NoContent, &allowed);
+        }
+        let segments: Vec<&str> = request.path().trim_start_matches('/').split('/').collect();
+
+        let mut allowed: Vec<Method> = Vec::new();
+        // A HEAD request is answered by the GET route for the path, unless it has a HEAD route of
+        // its own.
+        let mut get_route = None;
+        for route in &self.routes {
+            let Some(params) = match_pattern(&route.pattern, &segments) else {
+                continue;
+            };
+            if route.method != method {
+                if method == Method::Head && route.method == Method::Get && get_route.is_none() {
+                    get_route = Some((route, params));
+                }
+                add_allowed(&mut allowed, route.method);
+                continue;
+            }
+
+            request.set_params(params);
+            return (route.handler)(&request);
+        }
+        if let Some((route, params)) = get_route {
+            request.set_params(params);
+            return (route.handler)(&request);
+        }
+
+        if !allowed.is_empty() {
+            if method == Method::Options {
+                add_allowed(&mut allowed, Method::Options);
+                return allow(StatusCode::NoContent, &allowed);
+            }
+            return allow(StatusCode::MethodNotAllowed, &allowed);
+        }
+
+        match &self.fallback {
+            Some(fallback) => fallback(&request),
+            None => Response::new(StatusCode::NotFound),
+        }
+    }
+}
+
+/// Adds `method` to the methods in an `Allow` header, along with HEAD for GET, since a HEAD request
+/// is answered by the GET route.
+fn add_allowed(allowed: &mut Vec<Method>, method: Method) {
+    let methods: &[Method] = if method == Method::Get {
+        &[Method::Get, Method::Head]
+    } else {
+        &[method]
+    };
+    for method in methods {
+        if !allowed.contains(method) {
+            allowed.push(*method);
+        }
+    }
+}
+
+fn allow(status: StatusCode, allowed: &[Method]) -> Response {
+    let allow: Vec<&str> = allowed.iter().map(Method::as_str).collect();
+    Response::builder()
+        .status(status)
+        .header("Allow", allow.join(", "))
+        .build()
+}
+
+impl Default for Router {
+    fn default() -> Router {
+        Router::new()
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let Some(rest) = pattern.strip_prefix('/') else {
+        panic!("route pattern {pattern:?} must start with '/'");
+    };
+
+    let mut names = Vec::new();
+    let segments: Vec<Segment> = rest
+        .split('/')
+        .map(|segment| {
+            let (segment, name) = if let Some(name) = segment.strip_prefix(':') {
+                (Segment::Param(name.to_string()), name)
+            } else if let Some(name) = segment.strip_prefix('*') {
+                (Segment::Wildcard(name.to_string()), name)
+            } else {
+                return Segment::Literal(segment.to_string());
+            };
+            assert!(
+                !names.contains(&name),
+                "route pattern {pattern:?} captures {name:?} twice"
+            );
+            names.push(name);
+            segment
+        })
+        .collect();
+
+    if let Some(i) = segments
+        .iter()
+        .position(|s| matches!(s, Segment::Wildcard(_)))
+    {
+        assert!(
+            i == segments.len() - 1,
+            "wildcard in route pattern {pattern:?} must be last"
+        );
+    }
+    segments
+}
+
+fn match_pattern(pattern: &[Segment], segments: &[&str]) -> Option<HashMap<String, String>> {
+    let mut params = HashMap::new();
+    for (i, expected) in pattern.iter().enumerate() {
+        match expected {
+            Segment::Wildcard(name) => {
+                params.insert(name.clone(), percent_decode(&segments.get(i..)?.join("/")));
+                return Some(params);
+            }
+            Segment::Param(name) => {
+                params.insert(name.clone(), percent_decode(segments.get(i)?));
+            }
+            Segment::Literal(literal) => {
+                if segments.get(i)? != literal {
+                    return None;
+                }
+            }
+        }
+    }
+    (pattern.len() == segments.len()).then_some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::Body;
+
+    fn request(method: &str, target: &str) -> Request {
+        let raw = format!("{method} {target} HTTP/1.1\r\n\r\n");
+        Request::read(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn router() -> Router {
+        Router::new()
+            .get("/", |_| Response::text(StatusCode::Ok, "index"))
+            .get("/users/:id", |r| {
+                Response::text(StatusCode::Ok, format!("get {}", r.param("id").unwrap()))
+            })
+            .delete("/users/:id", |r| {
+                Response::text(StatusCode::Ok, format!("delete {}", r.param("id").unwrap()))
+            })
+            .get("/static/*rest", |r| {
+                Response::text(
+                    StatusCode::Ok,
+                    format!("static {}", r.param("rest").unwrap()),
+                )
+            })
+    }
+
+    fn body(response: Response) -> String {
+        match response.body() {
+            Body::Bytes(bytes) => String::from_utf8(bytes.clone()).unwrap(),
+            body => panic!("unexpected body {body:?}"),
+        }
+    }
+
+    #[test]
+    fn literal_route() {
+        assert_eq!(body(router().handle(request("GET", "/"))), "index");
+    }
+
+    #[test]
+    fn param_route() {
+        let router = router();
+        assert_eq!(
+            body(router.handle(request("GET", "/users/a%20b"))),
+            "get a b"
+        );
+        assert_eq!(
+            body(router.handle(request("DELETE", "/users/42?x=1"))),
+            "delete 42"
+        );
+    }
+
+    #[test]
+    fn wildcard_route() {
+        let router = router();
+        let response = router.handle(request("GET", "/static/css/site.css"));
+        assert_eq!(body(response), "static css/site.css");
+        assert_eq!(body(router.handle(request("GET", "/static/"))), "static ");
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let response = router().handle(request("POST", "/users/42"));
+        assert_eq!(response.status(), StatusCode::MethodNotAllowed);
+        assert_eq!(response.headers().get("Allow"), Some("GET, HEAD, DELETE"));
+    }
+
+    #[test]
+    fn head_runs_the_get_route() {
+        let response = router().handle(request("HEAD", "/users/42"));
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(body(response), "get 42");
+
+        // Unless there is a HEAD route of its own, even one added after the GET route.
+        let router = router().route(Method::Head, "/", |_| Response::new(StatusCode::NoContent));
+        assert_eq!(
+            router.handle(request("HEAD", "/")).status(),
+            StatusCode::NoContent
+        );
+    }
+
+    #[test]
+    fn options() {
+        let response = router().handle(request("OPTIONS", "/users/42"));
+        assert_eq!(response.status(), StatusCode::NoContent);
+        assert_eq!(
+            response.headers().get("Allow"),
+            Some("GET, HEAD, DELETE, OPTIONS")
+        );
+
+        let response = router().handle(request("OPTIONS", "*"));
+        assert_eq!(response.status(), StatusCode::NoContent);
+        assert_eq!(
+            response.headers().get("Allow"),
+            Some("OPTIONS, GET, HEAD, DELETE")
+        );
+    }
+
+    #[test]
+    fn not_found() {
+        let router = router();
+        assert_eq!(
+            router.handle(request("GET", "/users")).status(),
+            StatusCode::NotFound
+        );
+        assert_eq!(
+            router.handle(request("GET", "/users/1/2")).status(),
+            StatusCode::NotFound
+        );
+
+        let router = router.fallback(|_| Response::text(StatusCode::NotFound, "custom"));
+        assert_eq!(body(router.handle(request("GET", "/nope"))), "custom");
+    }
+
+    #[test]
+    #[should_panic(expected = "must be last")]
+    fn wildcard_must_be_last() {
+        Router::new().get("/*rest/more", |_| Response::new(StatusCode::Ok));
+    }
+}
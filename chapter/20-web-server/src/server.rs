@@ -0,0 +1,1621 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsAcceptor;
+use crate::{
+    log::{self, AccessRecord},
+    poll::{Poller, Waker},
+    request::{is_timeout, Limits, ParseError, Request, Version},
+    response::{OnUpgrade, Response, StatusCode, Upgraded},
+    router::Router,
+    ExecuteErrorKind, ThreadPool,
+};
+
+/// Limits on how long a connection is kept open and how long a client may take.
+#[derive(Clone, Debug)]
+pub struct ConnectionOptions {
+    /// How long to wait for the next request before closing the connection.
+    pub idle_timeout: Duration,
+    /// How long a client has to send the whole of a request once it has started, however steadily
+    /// it trickles in, before being answered with `408 Request Timeout`.
+    pub request_timeout: Duration,
+    /// How long writing a response may stall before the connection is given up on.
+    pub write_timeout: Duration,
+    /// How many requests to serve before closing the connection, so no client can hold it forever.
+    pub max_requests: usize,
+    pub limits: Limits,
+    /// How long a client that was turned away because the pool was saturated is asked to wait
+    /// before trying again.
+    pub retry_after: Duration,
+}
+
+impl Default for ConnectionOptions {
+    fn default() -> ConnectionOptions {
+        ConnectionOptions {
+            idle_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_requests: 100,
+            limits: Limits::default(),
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+/// How a [`Server`] waits for requests on its connections.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IoMode {
+    /// Each connection has a worker to itself for as long as it is open, blocked reading from it
+    /// while the client is quiet, so as many idle connections as there are workers stall the server.
+    #[default]
+    Blocking,
+    /// One thread waits on every connection at once with `epoll`, reading requests as their bytes
+    /// arrive, and hands only complete requests to the workers. It is only supported on Linux.
+    ///
+    /// HTTPS connections are still served as with [`Blocking`](IoMode::Blocking).
+    EventLoop,
+}
+
+impl FromStr for IoMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IoMode, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "blocking" => Ok(IoMode::Blocking),
+            "event-loop" => Ok(IoMode::EventLoop),
+            _ => Err(String::from("expected blocking or event-loop")),
+        }
+    }
+}
+
+/// A socket that a [`Server`] accepts connections on, and how they are served.
+///
+/// A plain [`TcpListener`] converts into one that serves HTTP.
+#[derive(Debug)]
+pub struct Listener {
+    tcp: TcpListener,
+    kind: ListenerKind,
+}
+
+#[derive(Clone, Debug)]
+enum ListenerKind {
+    Http,
+    #[cfg(feature = "tls")]
+    Https(TlsAcceptor),
+    RedirectToHttps(u16),
+}
+
+impl Listener {
+    pub fn http(listener: TcpListener) -> Listener {
+        Listener {
+            tcp: listener,
+            kind: ListenerKind::Http,
+        }
+    }
+
+    /// Serves HTTPS, starting TLS on each connection with `acceptor`.
+    #[cfg(feature = "tls")]
+    pub fn https(listener: TcpListener, acceptor: TlsAcceptor) -> Listener {
+        Listener {
+            tcp: listener,
+            kind: ListenerKind::Https(acceptor),
+        }
+    }
+
+    /// Serves plain HTTP by redirecting every request to the same URL over HTTPS on `port`, with
+    /// `308 Permanent Redirect`, rather than routing it.
+    pub fn redirect_to_https(listener: TcpListener, port: u16) -> Listener {
+        Listener {
+            tcp: listener,
+            kind: ListenerKind::RedirectToHttps(port),
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.local_addr()
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Listener {
+        Listener::http(listener)
+    }
+}
+
+impl ListenerKind {
+    fn is_tls(&self) -> bool {
+        match self {
+            #[cfg(feature = "tls")]
+            ListenerKind::Https(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Serves a connection accepted by this listener, starting TLS on it first if need be.
+    fn serve(
+        &self,
+        stream: TcpStream,
+        router: &Router,
+        options: &ConnectionOptions,
+        tracked: Option<&TrackedConnection>,
+    ) -> io::Result<()> {
+        match self {
+            #[cfg(feature = "tls")]
+            ListenerKind::Https(acceptor) => {
+                serve(acceptor.accept(stream)?, router, options, tracked)
+            }
+            _ => serve(stream, router, options, tracked),
+        }
+    }
+}
+
+/// A router that sends every request to the same host and target over HTTPS on `port`.
+fn https_redirect(port: u16) -> Router {
+    Router::new().fallback(move |request| {
+        let Some(host) = request.header("Host") else {
+            return Response::text(StatusCode::BadRequest, "A Host header is required.");
+        };
+        // Leave out the port the request came in on, minding IPv6 literals such as `[::1]:80`.
+        let host = match host.rsplit_once(':') {
+            Some((name, port)) if !port.contains(']') => name,
+            _ => host,
+        };
+        let port = if port == 443 {
+            String::new()
+        } else {
+            format!(":{port}")
+        };
+        Response::builder()
+            .status(StatusCode::PermanentRedirect)
+            .header(
+                "Location",
+                format!("https://{host}{port}{}", request.target()),
+            )
+            .build()
+    })
+}
+
+/// Accepts connections and serves them on a [`ThreadPool`] until it is shut down.
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use web_server::{router::Router, server::Server, ThreadPool};
+///
+/// let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
+/// let server = Server::new([listener], ThreadPool::new(4), Router::new()).unwrap();
+///
+/// let handle = server.shutdown_handle();
+/// std::thread::spawn(move || {
+///     std::thread::sleep(std::time::Duration::from_secs(60));
+///     handle.shutdown();
+/// });
+///
+/// server.run();
+/// ```
+///
+/// With the `tls` feature, a server can also listen for HTTPS; see
+/// [`TlsAcceptor`](crate::tls::TlsAcceptor).
+pub struct Server {
+    listeners: Vec<Listener>,
+    pool: ThreadPool,
+    router: Arc<Router>,
+    options: Arc<ConnectionOptions>,
+    grace_period: Duration,
+    io_mode: IoMode,
+    shared: Arc<Shared>,
+}
+
+/// State shared between the server, its connections and its shutdown handles.
+struct Shared {
+    shutting_down: AtomicBool,
+    /// The addresses to connect to so that each blocked `accept` notices the shutdown.
+    wake_addresses: Vec<SocketAddr>,
+    next_id: AtomicU64,
+    connections: Mutex<HashMap<u64, Tracked>>,
+    /// Notified whenever a connection closes.
+    closed: Condvar,
+}
+
+struct Tracked {
+    stream: TcpStream,
+    /// Whether the connection is waiting for its next request, and so can be closed at any time.
+    idle: bool,
+}
+
+impl Shared {
+    fn connections(&self) -> MutexGuard<'_, HashMap<u64, Tracked>> {
+        // The map is never left half-updated, so a panic elsewhere doesn't make it unusable.
+        self.connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+impl Server {
+    /// Creates a server that accepts connections from every one of `listeners`, which can be
+    /// [`Listener`]s or plain `TcpListener`s for HTTP.
+    pub fn new(
+        listeners: impl IntoIterator<Item = impl Into<Listener>>,
+        pool: ThreadPool,
+        router: Router,
+    ) -> io::Result<Server> {
+        let listeners: Vec<Listener> = listeners.into_iter().map(Into::into).collect();
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a server needs at least one listener",
+            ));
+        }
+
+        let mut wake_addresses = Vec::with_capacity(listeners.len());
+        for listener in &listeners {
+            let mut address = listener.local_addr()?;
+            // Connecting to an unspecified address isn't portable, but every interface has loopback.
+            if address.ip().is_unspecified() {
+                address.set_ip(match address {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            wake_addresses.push(address);
+        }
+
+        Ok(Server {
+            listeners,
+            pool,
+            router: Arc::new(router),
+            options: Arc::new(ConnectionOptions::default()),
+            grace_period: Duration::from_secs(10),
+            io_mode: IoMode::default(),
+            shared: Arc::new(Shared {
+                shutting_down: AtomicBool::new(false),
+                wake_addresses,
+                next_id: AtomicU64::new(0),
+                connections: Mutex::new(HashMap::new()),
+                closed: Condvar::new(),
+            }),
+        })
+    }
+
+    pub fn with_options(mut self, options: ConnectionOptions) -> Server {
+        self.options = Arc::new(options);
+        self
+    }
+
+    /// Sets how long requests that are in flight at shutdown are given to finish.
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Server {
+        self.grace_period = grace_period;
+        self
+    }
+
+    pub fn with_io_mode(mut self, io_mode: IoMode) -> Server {
+        self.io_mode = io_mode;
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Serves connections until a [`ShutdownHandle`] is triggered, and then shuts down gracefully.
+    ///
+    /// Once shutdown begins no more connections are accepted and idle connections are closed, while
+    /// requests that are already being handled get up to the grace period to finish. Connections
+    /// still open after that are closed forcibly, and finally the pool's workers are joined.
+    pub fn run(self) {
+        match self.io_mode {
+            IoMode::Blocking => thread::scope(|scope| {
+                for (listener, address) in self.listeners.iter().zip(&self.shared.wake_addresses) {
+                    // Named so that what they log can be told apart.
+                    thread::Builder::new()
+                        .name(format!("accept-{address}"))
+                        .spawn_scoped(scope, || self.accept(listener))
+                        .expect("failed to spawn an accept thread");
+                }
+            }),
+            IoMode::EventLoop => {
+                if let Err(e) = EventLoop::new(&self).and_then(EventLoop::run) {
+                    error!("The event loop failed: {e}");
+                }
+            }
+        }
+        drop(self.listeners);
+
+        let deadline = Instant::now().checked_add(self.grace_period);
+        let mut connections = self.shared.connections();
+        for connection in connections.values().filter(|c| c.idle) {
+            let _ = connection.stream.shutdown(Shutdown::Read);
+        }
+        while !connections.is_empty() {
+            // A grace period too long for the clock to count to is waited out for as long as it takes.
+            let Some(deadline) = deadline else {
+                connections = self
+                    .shared
+                    .closed
+                    .wait(connections)
+                    .unwrap_or_else(PoisonError::into_inner);
+                continue;
+            };
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                warn!(
+                    "Closing {} connection(s) that outlived the grace period.",
+                    connections.len()
+                );
+                for connection in connections.values() {
+                    let _ = connection.stream.shutdown(Shutdown::Both);
+                }
+                break;
+            }
+            connections = self
+                .shared
+                .closed
+                .wait_timeout(connections, timeout)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+        drop(connections);
+
+        // Dropping the pool waits for every worker to finish its current job.
+        drop(self.pool);
+    }
+
+    /// The router for requests that come in on `listener`.
+    fn router_for(&self, listener: &Listener) -> Arc<Router> {
+        match listener.kind {
+            ListenerKind::RedirectToHttps(port) => Arc::new(https_redirect(port)),
+            _ => Arc::clone(&self.router),
+        }
+    }
+
+    fn accept(&self, listener: &Listener) {
+        let router = self.router_for(listener);
+        for stream in listener.tcp.incoming() {
+            if self.shared.is_shutting_down() {
+                break;
+            }
+            match stream {
+                Ok(stream) => self.hand_off(listener, &router, stream),
+                Err(e) => error!("Could not accept connection: {e}"),
+            }
+        }
+    }
+
+    /// Gives a worker a connection to serve until it closes.
+    fn hand_off(&self, listener: &Listener, router: &Arc<Router>, stream: TcpStream) {
+        let Ok(tracked) = TrackedConnection::register(&self.shared, &stream) else {
+            return;
+        };
+        // The job takes the stream, so keep a way to answer if the pool won't take the job.
+        let Ok(overflow) = stream.try_clone() else {
+            return;
+        };
+
+        let router = Arc::clone(router);
+        let options = Arc::clone(&self.options);
+        let kind = listener.kind.clone();
+        let job = move || {
+            if let Err(e) = kind.serve(stream, &router, &options, Some(&tracked)) {
+                debug!("Connection failed: {e}");
+            }
+        };
+        match self.pool.execute(job) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ExecuteErrorKind::Full => {
+                warn!("Every worker is busy; turning a connection away.");
+                // Dropping the job that's handed back closes the connection after this. There's
+                // no answering a TLS client without a handshake, so it just sees the close.
+                if !listener.kind.is_tls() {
+                    let _ = overflow
+                        .set_write_timeout(Some(Duration::from_secs(1)))
+                        .and_then(|()| service_unavailable(&overflow, self.options.retry_after));
+                }
+            }
+            Err(e) => error!("Could not serve connection: {e}"),
+        }
+    }
+}
+
+/// Waits on every connection of a [`Server`] from one thread, for [`IoMode::EventLoop`].
+///
+/// Connections are read without blocking until a whole request has arrived, and only then handed
+/// to a worker, which writes the response and hands the connection back to wait for the next one.
+/// Listeners are polled with the tokens `0..n`, the waker with `n` and connections after that.
+struct EventLoop<'a> {
+    server: &'a Server,
+    poller: Poller,
+    routers: Vec<Arc<Router>>,
+    /// The connections that are waiting for (the rest of) a request, by token.
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+    /// The earliest any of `connections` might time out.
+    next_deadline: Option<Instant>,
+    handback: Arc<Handback>,
+    returned: mpsc::Receiver<Connection>,
+}
+
+impl<'a> EventLoop<'a> {
+    fn new(server: &'a Server) -> io::Result<EventLoop<'a>> {
+        let poller = Poller::new()?;
+        for (token, listener) in server.listeners.iter().enumerate() {
+            listener.tcp.set_nonblocking(true)?;
+            poller.add(&listener.tcp, token as u64)?;
+        }
+        let wake_token = server.listeners.len() as u64;
+        let (sender, returned) = mpsc::channel();
+        let handback = Handback {
+            connections: sender,
+            waker: poller.waker(wake_token)?,
+        };
+        Ok(EventLoop {
+            server,
+            routers: server
+                .listeners
+                .iter()
+                .map(|l| server.router_for(l))
+                .collect(),
+            poller,
+            connections: HashMap::new(),
+            next_token: wake_token + 1,
+            next_deadline: None,
+            handback: Arc::new(handback),
+            returned,
+        })
+    }
+
+    /// Serves connections until the server shuts down, when the connections that are waiting for a
+    /// request are closed.
+    fn run(mut self) -> io::Result<()> {
+        let wake_token = self.server.listeners.len() as u64;
+        let mut tokens = Vec::new();
+        while !self.server.shared.is_shutting_down() {
+            let timeout = self
+                .next_deadline
+                .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            self.poller.wait(&mut tokens, timeout)?;
+            for &token in &tokens {
+                if token < wake_token {
+                    self.accept(token as usize);
+                } else if token == wake_token {
+                    self.handback.waker.reset()?;
+                    while let Ok(connection) = self.returned.try_recv() {
+                        self.resume(connection);
+                    }
+                } else {
+                    self.read(token);
+                }
+            }
+            if self
+                .next_deadline
+                .is_some_and(|deadline| deadline <= Instant::now())
+            {
+                self.expire();
+            }
+        }
+        Ok(())
+    }
+
+    fn accept(&mut self, index: usize) {
+        let listener = &self.server.listeners[index];
+        loop {
+            let stream = match listener.tcp.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Could not accept connection: {e}");
+                    return;
+                }
+            };
+            if self.server.shared.is_shutting_down() {
+                return;
+            }
+            // Reading TLS records without blocking isn't worth the trouble here.
+            if listener.kind.is_tls() {
+                if stream.set_nonblocking(false).is_ok() {
+                    self.server.hand_off(listener, &self.routers[index], stream);
+                }
+                continue;
+            }
+            let Ok(tracked) = TrackedConnection::register(&self.server.shared, &stream) else {
+                continue;
+            };
+            let connection = Connection {
+                token: self.next_token,
+                listener: index,
+                peer: stream.peer_addr().ok(),
+                stream,
+                buffer: Vec::new(),
+                served: 0,
+                arrival: None,
+                idle_since: Instant::now(),
+                tracked,
+            };
+            self.next_token += 1;
+            self.watch(connection);
+        }
+    }
+
+    /// Reads what has arrived on a connection, and hands it to a worker once a request is complete.
+    fn read(&mut self, token: u64) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let started = connection.arrival.is_some();
+        let open = connection.fill().unwrap_or_else(|e| {
+            debug!("Connection failed: {e}");
+            false
+        });
+        match connection.parse(&self.server.options.limits) {
+            Some(parsed) => {
+                if let Some(connection) = self.unwatch(token) {
+                    self.dispatch(connection, parsed);
+                }
+            }
+            None if !open => drop(self.unwatch(token)),
+            None if !started => {
+                let deadline = connection.deadline(&self.server.options);
+                self.extend_deadline(deadline);
+            }
+            None => {}
+        }
+    }
+
+    /// Waits for the next request on a connection that a worker has answered a request on, unless
+    /// it has already arrived.
+    fn resume(&mut self, mut connection: Connection) {
+        if !connection.buffer.is_empty() {
+            connection.arrival = Some(Arrival::now(connection.peer));
+        }
+        match connection.parse(&self.server.options.limits) {
+            Some(parsed) => self.dispatch(connection, parsed),
+            None => self.watch(connection),
+        }
+    }
+
+    fn watch(&mut self, connection: Connection) {
+        let watched = connection
+            .stream
+            .set_nonblocking(true)
+            .and_then(|()| self.poller.add(&connection.stream, connection.token));
+        if let Err(e) = watched {
+            debug!("Connection failed: {e}");
+            return;
+        }
+        self.extend_deadline(connection.deadline(&self.server.options));
+        self.connections.insert(connection.token, connection);
+    }
+
+    fn unwatch(&mut self, token: u64) -> Option<Connection> {
+        let connection = self.connections.remove(&token)?;
+        let _ = self.poller.remove(&connection.stream);
+        Some(connection)
+    }
+
+    fn extend_deadline(&mut self, deadline: Option<Instant>) {
+        if let Some(deadline) = deadline {
+            self.next_deadline = Some(self.next_deadline.map_or(deadline, |d| d.min(deadline)));
+        }
+    }
+
+    /// Closes connections that have been idle for too long, and answers those that have taken too
+    /// long to send a request with `408 Request Timeout`.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let options = &self.server.options;
+        let mut expired = Vec::new();
+        self.next_deadline = None;
+        for (&token, connection) in &self.connections {
+            match connection.deadline(options) {
+                Some(deadline) if deadline <= now => expired.push(token),
+                Some(deadline) => {
+                    self.next_deadline =
+                        Some(self.next_deadline.map_or(deadline, |d| d.min(deadline)));
+                }
+                None => {}
+            }
+        }
+        for token in expired {
+            let Some(connection) = self.unwatch(token) else {
+                continue;
+            };
+            if connection.arrival.is_some() {
+                let timed_out = ParseError::Io(io::ErrorKind::TimedOut.into());
+                self.dispatch(connection, Err(timed_out));
+            }
+        }
+    }
+
+    /// Has a worker answer a request, or refuse one that couldn't be parsed.
+    fn dispatch(&self, connection: Connection, parsed: Result<Request, ParseError>) {
+        // The job takes the stream, so keep a way to answer if the pool won't take the job.
+        let Ok(overflow) = connection.stream.try_clone() else {
+            return;
+        };
+        let router = Arc::clone(&self.routers[connection.listener]);
+        let options = Arc::clone(&self.server.options);
+        let handback = Arc::clone(&self.handback);
+        let job = move || connection.serve(parsed, &router, &options, &handback);
+        match self.server.pool.execute(job) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ExecuteErrorKind::Full => {
+                warn!("Every worker is busy; turning a request away.");
+                // The stream is left non-blocking, so that a client that isn't reading can't hold
+                // up every other connection; one whose send buffer is full is just closed.
+                let _ = service_unavailable(&overflow, self.server.options.retry_after);
+            }
+            Err(e) => error!("Could not serve request: {e}"),
+        }
+    }
+}
+
+/// A connection that is served by an [`EventLoop`].
+struct Connection {
+    token: u64,
+    /// The index of the listener that accepted it, which decides how its requests are routed.
+    listener: usize,
+    stream: TcpStream,
+    peer: Option<SocketAddr>,
+    /// What has been read but not parsed yet, which may include the start of the next request.
+    buffer: Vec<u8>,
+    served: usize,
+    /// When the request that is being read started to arrive, if one has.
+    arrival: Option<Arrival>,
+    /// When the connection was accepted or its last response was written.
+    idle_since: Instant,
+    tracked: TrackedConnection,
+}
+
+impl Connection {
+    /// When the connection times out, unless the timeout is too far off for the clock to count to,
+    /// in which case it never does.
+    fn deadline(&self, options: &ConnectionOptions) -> Option<Instant> {
+        match &self.arrival {
+            Some(arrival) => arrival.started.checked_add(options.request_timeout),
+            None => self.idle_since.checked_add(options.idle_timeout),
+        }
+    }
+
+    /// Reads what has arrived without blocking, returning whether the client may send more.
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 8192];
+        // The poller is level-triggered, so it comes back for anything that is left, and a client
+        // that sends a lot at once doesn't keep the others waiting meanwhile.
+        for _ in 0..8 {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    if self.arrival.is_none() {
+                        self.arrival = Some(Arrival::now(self.peer));
+                    }
+                    self.buffer.extend_from_slice(&chunk[..n]);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Takes the next request out of the buffer once all of it has arrived, or the error that
+    /// parsing it ran into.
+    ///
+    /// The parser simply starts over each time more of a request arrives, which is wasteful for
+    /// large bodies, but they're limited in size anyway.
+    fn parse(&mut self, limits: &Limits) -> Option<Result<Request, ParseError>> {
+        let mut unparsed = &self.buffer[..];
+        let parsed = match Request::read_with_limits(&mut unparsed, limits) {
+            Ok(Some(request)) => Ok(request),
+            Ok(None) | Err(ParseError::UnexpectedEof) => return None,
+            Err(e) => Err(e),
+        };
+        let consumed = self.buffer.len() - unparsed.len();
+        self.buffer.drain(..consumed);
+        Some(parsed)
+    }
+
+    /// Answers a request on a worker, and then hands the connection back to the event loop unless
+    /// it is to be closed.
+    fn serve(
+        mut self,
+        parsed: Result<Request, ParseError>,
+        router: &Router,
+        options: &ConnectionOptions,
+        handback: &Handback,
+    ) {
+        let arrival = self
+            .arrival
+            .take()
+            .unwrap_or_else(|| Arrival::now(self.peer));
+        self.served += 1;
+        let result = self
+            .stream
+            .set_nonblocking(false)
+            .and_then(|()| self.stream.set_write_timeout(Some(options.write_timeout)))
+            .and_then(|()| match parsed {
+                Ok(request) => respond(
+                    request,
+                    router,
+                    options,
+                    self.served,
+                    Some(&self.tracked),
+                    &mut self.stream,
+                    &arrival,
+                ),
+                Err(e) => refuse(&e, &mut self.stream, &arrival).map(|()| Outcome::Close),
+            });
+        match result {
+            Ok(Outcome::KeepAlive) => {
+                self.idle_since = Instant::now();
+                handback.send(self);
+            }
+            Ok(Outcome::Close) => {
+                if let Err(e) = linger_close(self.stream) {
+                    debug!("Connection failed: {e}");
+                }
+            }
+            // The connection stays with this worker from now on, out of the event loop's hands.
+            Ok(Outcome::Upgrade(upgrade)) => {
+                self.tracked.set_idle(true);
+                match Upgraded::new(Box::new(self.stream), self.buffer) {
+                    Ok(upgraded) => upgrade.run(upgraded),
+                    Err(e) => debug!("Connection failed: {e}"),
+                }
+            }
+            Err(e) => debug!("Connection failed: {e}"),
+        }
+    }
+}
+
+/// How workers hand connections back to the [`EventLoop`] once they have answered a request.
+struct Handback {
+    connections: mpsc::Sender<Connection>,
+    waker: Waker,
+}
+
+impl Handback {
+    fn send(&self, connection: Connection) {
+        // If the event loop has stopped, the connection is dropped, and so closed, instead.
+        if self.connections.send(connection).is_ok() {
+            if let Err(e) = self.waker.wake() {
+                error!("Could not wake the event loop: {e}");
+            }
+        }
+    }
+}
+
+/// Asks a [`Server`] to shut down; it can be cloned and sent to other threads.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+}
+
+impl ShutdownHandle {
+    /// Begins a graceful shutdown; calling this more than once has no further effect.
+    pub fn shutdown(&self) {
+        if self.shared.shutting_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        // Each accept loop is blocked until a connection arrives, so give it one.
+        for address in &self.shared.wake_addresses {
+            let _ = TcpStream::connect_timeout(address, Duration::from_secs(1));
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shared.is_shutting_down()
+    }
+}
+
+/// A connection's entry in the server's list of open connections, removed when this is dropped.
+struct TrackedConnection {
+    id: u64,
+    shared: Arc<Shared>,
+}
+
+impl TrackedConnection {
+    fn register(shared: &Arc<Shared>, stream: &TcpStream) -> io::Result<TrackedConnection> {
+        let id = shared.next_id.fetch_add(1, Ordering::Relaxed);
+        let tracked = Tracked {
+            stream: stream.try_clone()?,
+            // A connection that was accepted before shutdown began still gets its first request
+            // answered, so it doesn't start out idle.
+            idle: false,
+        };
+        shared.connections().insert(id, tracked);
+        Ok(TrackedConnection {
+            id,
+            shared: Arc::clone(shared),
+        })
+    }
+
+    /// Marks the connection as waiting for its next request, unless the server is shutting down.
+    fn set_idle(&self, idle: bool) -> bool {
+        let mut connections = self.shared.connections();
+        if idle && self.shared.is_shutting_down() {
+            return false;
+        }
+        if let Some(connection) = connections.get_mut(&self.id) {
+            connection.idle = idle;
+        }
+        true
+    }
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        self.shared.connections().remove(&self.id);
+        self.shared.closed.notify_all();
+    }
+}
+
+/// Serves requests from `stream` until the client or the options ask for the connection to close.
+///
+/// Requests are answered strictly in the order they arrive, so pipelined requests (sent before the
+/// previous response was read) work without any special handling: the bytes of the next request
+/// simply wait in the reader's buffer.
+pub fn serve_connection(
+    stream: TcpStream,
+    router: &Router,
+    options: &ConnectionOptions,
+) -> io::Result<()> {
+    serve(stream, router, options, None)
+}
+
+/// A connection's byte stream: TCP itself, or TLS on top of it.
+pub(crate) trait Transport: Read + Write {
+    fn tcp(&self) -> &TcpStream;
+
+    /// Finishes with the stream before the TCP connection is shut down.
+    fn close(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for TcpStream {
+    fn tcp(&self) -> &TcpStream {
+        self
+    }
+}
+
+fn serve<S: Transport + Send + 'static>(
+    stream: S,
+    router: &Router,
+    options: &ConnectionOptions,
+    tracked: Option<&TrackedConnection>,
+) -> io::Result<()> {
+    let tcp = stream.tcp();
+    tcp.set_write_timeout(Some(options.write_timeout))?;
+    let peer = tcp.peer_addr().ok();
+    // Responses are written through the reader, which leaves what it has buffered alone.
+    let mut reader = BufReader::new(Deadline {
+        stream,
+        deadline: None,
+    });
+
+    for served in 1.. {
+        if served > 1 && tracked.is_some_and(|t| !t.set_idle(true)) {
+            break;
+        }
+        // Wait for the first byte of the next request, which may already be buffered.
+        reader.get_mut().deadline = None;
+        reader
+            .get_ref()
+            .stream
+            .tcp()
+            .set_read_timeout(Some(options.idle_timeout))?;
+        let waited = reader.fill_buf().map(|buffered| buffered.is_empty());
+        if let Some(tracked) = tracked {
+            tracked.set_idle(false);
+        }
+        match waited {
+            Ok(false) => {}
+            // The client hung up or went idle between requests, which is how most connections end.
+            Ok(true) => return Ok(()),
+            Err(e) if is_timeout(&e) => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let arrival = Arrival::now(peer);
+        reader.get_mut().deadline = arrival.started.checked_add(options.request_timeout);
+        let request = match Request::read_with_limits(&mut reader, &options.limits) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                refuse(&e, reader.get_mut(), &arrival)?;
+                return linger_close(reader.into_inner().stream);
+            }
+        };
+
+        let outcome = respond(
+            request,
+            router,
+            options,
+            served,
+            tracked,
+            reader.get_mut(),
+            &arrival,
+        )?;
+        match outcome {
+            Outcome::KeepAlive => {}
+            Outcome::Close => break,
+            Outcome::Upgrade(upgrade) => {
+                // Whatever the client sent after the request belongs to the new protocol.
+                let leftover = reader.buffer().to_vec();
+                let stream = reader.into_inner().stream;
+                // Shutting down closes the connection for reading straight away, like an idle one.
+                if let Some(tracked) = tracked {
+                    tracked.set_idle(true);
+                }
+                upgrade.run(Upgraded::new(Box::new(stream), leftover)?);
+                return Ok(());
+            }
+        }
+    }
+    linger_close(reader.into_inner().stream)
+}
+
+/// Where a request came from and when it started to arrive, for the access log.
+struct Arrival {
+    peer: Option<SocketAddr>,
+    time: SystemTime,
+    started: Instant,
+}
+
+impl Arrival {
+    fn now(peer: Option<SocketAddr>) -> Arrival {
+        Arrival {
+            peer,
+            time: SystemTime::now(),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// Answers a request that could not be parsed; the connection should be closed afterwards.
+fn refuse(error: &ParseError, writer: &mut impl Write, arrival: &Arrival) -> io::Result<()> {
+    let mut response = Response::text(error.status(), error.to_string());
+    response.headers_mut().insert("Connection", "close");
+    let bytes = response.body().len();
+    response.write_to(writer)?;
+    log::logger().access(&AccessRecord {
+        peer: arrival.peer,
+        time: arrival.time,
+        request: None,
+        status: error.status(),
+        bytes,
+        referer: None,
+        user_agent: None,
+        duration: arrival.started.elapsed(),
+    });
+    Ok(())
+}
+
+/// What becomes of a connection once a response has been written to it.
+enum Outcome {
+    KeepAlive,
+    Close,
+    /// The response switched protocols, and this takes over the connection.
+    Upgrade(OnUpgrade),
+}
+
+/// Routes the `served`th request on a connection and writes the response, returning whether the
+/// connection can be kept open for another request.
+fn respond(
+    request: Request,
+    router: &Router,
+    options: &ConnectionOptions,
+    served: usize,
+    tracked: Option<&TrackedConnection>,
+    writer: &mut impl Write,
+    arrival: &Arrival,
+) -> io::Result<Outcome> {
+    let keep_alive = request.keep_alive() && served < options.max_requests;
+    let (method, version) = (request.method(), request.version());
+    let target = request.target().to_string();
+    let referer = request.header("Referer").map(String::from);
+    let user_agent = request.header("User-Agent").map(String::from);
+    let mut response = router.handle(request);
+    let upgrade = response.take_upgrade();
+
+    // An HTTP/1.0 client can only tell where a body of unknown length ends by the connection
+    // closing, and there's no point in keeping a connection open if the server is about to stop.
+    let keep_alive = keep_alive
+        && (response.body().len().is_some() || version != Version::Http10)
+        && !tracked.is_some_and(|t| t.shared.is_shutting_down())
+        && !response.headers().has_token("Connection", "close");
+    // A response that switches protocols says for itself what becomes of the connection.
+    if upgrade.is_none() {
+        if !keep_alive {
+            response.headers_mut().insert("Connection", "close");
+        } else if version == Version::Http10 {
+            response.headers_mut().insert("Connection", "keep-alive");
+        }
+        if keep_alive {
+            // Rounded up, since `timeout=0` would tell the client not to reuse the connection at all.
+            let timeout = options.idle_timeout.as_secs_f64().ceil().max(1.0) as u64;
+            let max = options.max_requests - served;
+            response
+                .headers_mut()
+                .insert("Keep-Alive", format!("timeout={timeout}, max={max}"));
+        }
+    }
+
+    let status = response.status();
+    let bytes = response.write_for(writer, method, version)?;
+    log::logger().access(&AccessRecord {
+        peer: arrival.peer,
+        time: arrival.time,
+        request: Some((method, &target, version)),
+        status,
+        bytes: Some(bytes),
+        referer: referer.as_deref(),
+        user_agent: user_agent.as_deref(),
+        duration: arrival.started.elapsed(),
+    });
+    Ok(match upgrade {
+        Some(upgrade) => Outcome::Upgrade(upgrade),
+        None if keep_alive => Outcome::KeepAlive,
+        None => Outcome::Close,
+    })
+}
+
+/// Turns a client away with `503 Service Unavailable`, on the accept loop's or event loop's thread.
+///
+/// Unlike [`linger_close`], this doesn't wait for the client to finish sending its request, which
+/// would hold up the loop at exactly the wrong time. The response is small enough to fit in the
+/// socket's send buffer, though a client that is still sending may see a reset instead. How long a
+/// write may take is up to the stream: a non-blocking one fails with
+/// [`io::ErrorKind::WouldBlock`] rather than wait for a client that isn't reading.
+fn service_unavailable(stream: &TcpStream, retry_after: Duration) -> io::Result<()> {
+    let mut response = Response::text(
+        StatusCode::ServiceUnavailable,
+        "The server is too busy to answer right now.",
+    );
+    let headers = response.headers_mut();
+    // Retry-After is in whole seconds, and zero would invite the client to try again immediately.
+    headers.insert("Retry-After", retry_after.as_secs().max(1).to_string());
+    headers.insert("Connection", "close");
+    response.write_to(&mut &*stream)?;
+    stream.shutdown(Shutdown::Write)
+}
+
+/// Closes a connection without discarding a response the client hasn't read yet.
+///
+/// Closing a socket that still has unread input makes the kernel send a reset, which can destroy
+/// the response in flight, so anything else the client (for instance, a pipelined request) sent is
+/// drained for a short while first.
+fn linger_close<S: Transport>(mut stream: S) -> io::Result<()> {
+    stream.close()?;
+    let tcp = stream.tcp();
+    tcp.shutdown(Shutdown::Write)?;
+    tcp.set_read_timeout(Some(Duration::from_millis(500)))?;
+    // Errors here only mean the client went away first, which is exactly what we're waiting for.
+    let _ = io::copy(&mut tcp.take(1 << 16), &mut io::sink());
+    Ok(())
+}
+
+/// Reads from a connection, timing out once the deadline for the current request has passed, so
+/// that a client can't hold a worker by sending a byte every so often.
+struct Deadline<S> {
+    stream: S,
+    deadline: Option<Instant>,
+}
+
+impl<S: Transport> Read for Deadline<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            self.stream.tcp().set_read_timeout(Some(remaining))?;
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Transport> Write for Deadline<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread::{self, JoinHandle},
+    };
+
+    use super::*;
+    use crate::response::{Body, StatusCode};
+
+    /// Serves a single connection with `options`, sends `raw` to it, and returns everything the
+    /// server wrote back before closing.
+    fn exchange(raw: &str, options: ConnectionOptions) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let router = Router::new()
+                .get("/:name", |request| {
+                    Response::text(StatusCode::Ok, request.param("name").unwrap())
+                })
+                .get("/stream/:name", |request| {
+                    let name = request.param("name").unwrap().to_string();
+                    let body = Body::from_writer(move |writer| {
+                        writer.write_all(name.as_bytes())?;
+                        writer.flush()?;
+                        writer.write_all(b"!")
+                    });
+                    Response::builder().body(body).build()
+                })
+                .post("/echo", |request| {
+                    let checksum = request.trailers().get("X-Checksum").unwrap_or_default();
+                    let body = String::from_utf8_lossy(request.body());
+                    Response::text(StatusCode::Ok, format!("{body} {checksum}"))
+                });
+            let (stream, _) = listener.accept().unwrap();
+            serve_connection(stream, &router, &options).unwrap();
+        });
+
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(raw.as_bytes()).unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        drop(client);
+        server.join().unwrap();
+        output
+    }
+
+    /// Splits the output of [`exchange`] into one string per response.
+    fn responses(output: &str) -> Vec<&str> {
+        output.split("HTTP/1.1 ").skip(1).collect()
+    }
+
+    fn short_idle() -> ConnectionOptions {
+        ConnectionOptions {
+            idle_timeout: Duration::from_millis(100),
+            ..ConnectionOptions::default()
+        }
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let output = exchange(
+            "GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\nConnection: close\r\n\r\n",
+            short_idle(),
+        );
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].ends_with("\r\n\r\na"));
+        assert!(responses[1].ends_with("\r\n\r\nb"));
+        assert!(responses[2].ends_with("\r\n\r\nc"));
+        assert!(responses[2].contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn head_requests_get_no_body() {
+        let output = exchange(
+            "HEAD /a HTTP/1.1\r\n\r\nHEAD /stream/b HTTP/1.1\r\n\r\n\
+             GET /c HTTP/1.1\r\nConnection: close\r\n\r\n",
+            short_idle(),
+        );
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 3);
+        assert!(responses[0].contains("Content-Length: 1\r\n"));
+        assert!(responses[0].ends_with("\r\n\r\n"));
+        assert!(responses[1].contains("Transfer-Encoding: chunked\r\n"));
+        assert!(responses[1].ends_with("\r\n\r\n"));
+        assert!(responses[2].ends_with("\r\n\r\nc"));
+    }
+
+    #[test]
+    fn idle_connection_is_closed() {
+        let output = exchange("GET /a HTTP/1.1\r\n\r\n", short_idle());
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 1);
+        assert!(responses[0].contains("Keep-Alive: timeout=1, max=99\r\n"));
+        assert!(!responses[0].contains("Connection: close"));
+    }
+
+    #[test]
+    fn http10_closes_by_default() {
+        let output = exchange(
+            "GET /a HTTP/1.0\r\n\r\nGET /b HTTP/1.0\r\n\r\n",
+            short_idle(),
+        );
+        assert_eq!(responses(&output).len(), 1);
+        assert!(output.contains("Connection: close\r\n"));
+
+        let output = exchange(
+            "GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\n",
+            short_idle(),
+        );
+        assert!(output.contains("Connection: keep-alive\r\n"));
+    }
+
+    #[test]
+    fn streamed_responses() {
+        let output = exchange(
+            "GET /stream/a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\nConnection: close\r\n\r\n",
+            short_idle(),
+        );
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].contains("Transfer-Encoding: chunked\r\n"));
+        assert!(!responses[0].contains("Connection: close"));
+        assert!(responses[0].ends_with("\r\n\r\n1\r\na\r\n1\r\n!\r\n0\r\n\r\n"));
+
+        // An HTTP/1.0 client gets the body up to the connection closing instead.
+        let output = exchange("GET /stream/a HTTP/1.0\r\n\r\n", short_idle());
+        assert!(output.contains("Connection: close\r\n"));
+        assert!(output.ends_with("\r\n\r\na!"));
+    }
+
+    #[test]
+    fn chunked_requests() {
+        let output = exchange(
+            "POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+             6\r\nhello,\r\n6\r\n world\r\n0\r\nX-Checksum: 1234\r\n\r\n",
+            short_idle(),
+        );
+        assert!(output.ends_with("\r\n\r\nhello, world 1234"));
+    }
+
+    #[test]
+    fn max_requests_per_connection() {
+        let options = ConnectionOptions {
+            max_requests: 2,
+            ..short_idle()
+        };
+        let output = exchange(
+            "GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /c HTTP/1.1\r\n\r\n",
+            options,
+        );
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].contains("Keep-Alive: timeout=1, max=1\r\n"));
+        assert!(responses[1].contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn malformed_request_closes() {
+        let output = exchange("NOPE\r\n\r\nGET /a HTTP/1.1\r\n\r\n", short_idle());
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert!(!output.contains("200 OK"));
+    }
+
+    #[test]
+    fn slow_requests_time_out() {
+        let options = ConnectionOptions {
+            request_timeout: Duration::from_millis(200),
+            ..short_idle()
+        };
+        // The request never finishes, but keeps the connection from going idle.
+        let output = exchange("GET /a HTTP/1.1\r\nHost: x\r\n", options);
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(output.contains("Connection: close\r\n"));
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        let options = ConnectionOptions {
+            limits: Limits {
+                max_request_line_bytes: 32,
+                ..Limits::default()
+            },
+            ..short_idle()
+        };
+        let long_target = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(32));
+        let output = exchange(&long_target, options);
+        assert!(output.starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+    }
+
+    /// Runs a [`Server`] whose `/sleep/:ms` route takes that long to answer.
+    fn start(grace_period: Duration) -> (SocketAddr, ShutdownHandle, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = Router::new().get("/sleep/:ms", |request| {
+            let ms = request.param("ms").unwrap().parse().unwrap();
+            thread::sleep(Duration::from_millis(ms));
+            Response::text(StatusCode::Ok, "done")
+        });
+        let server = Server::new([listener], ThreadPool::new(2), router)
+            .unwrap()
+            .with_grace_period(grace_period);
+        let handle = server.shutdown_handle();
+        (address, handle, thread::spawn(move || server.run()))
+    }
+
+    #[test]
+    fn redirects_to_https() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let listeners = [Listener::redirect_to_https(listener, 8443)];
+        let server = Server::new(listeners, ThreadPool::new(1), Router::new()).unwrap();
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        let get = |raw: &str| {
+            let mut client = TcpStream::connect(address).unwrap();
+            client.write_all(raw.as_bytes()).unwrap();
+            let mut output = String::new();
+            client.read_to_string(&mut output).unwrap();
+            output
+        };
+        let output =
+            get("POST /a?b=c HTTP/1.1\r\nHost: example.com:8080\r\nConnection: close\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 308 Permanent Redirect\r\n"));
+        assert!(output.contains("Location: https://example.com:8443/a?b=c\r\n"));
+        let output = get("GET / HTTP/1.1\r\nHost: [::1]\r\nConnection: close\r\n\r\n");
+        assert!(output.contains("Location: https://[::1]:8443/\r\n"));
+        let output = get("GET / HTTP/1.0\r\n\r\n");
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_closes_idle_connections() {
+        let (address, handle, server) = start(Duration::from_secs(10));
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"GET /sleep/0 HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = [0; 512];
+        let n = client.read(&mut response).unwrap();
+        assert!(String::from_utf8_lossy(&response[..n]).ends_with("done"));
+
+        let started = Instant::now();
+        handle.shutdown();
+        server.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert_eq!(client.read(&mut response).unwrap(), 0);
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn shutdown_finishes_in_flight_requests() {
+        let (address, handle, server) = start(Duration::from_secs(10));
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"GET /sleep/300 HTTP/1.1\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+        handle.shutdown();
+
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(output.contains("Connection: close\r\n"));
+        assert!(output.ends_with("done"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn shutdown_gives_up_after_grace_period() {
+        let (address, handle, server) = start(Duration::from_millis(100));
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"GET /sleep/1000 HTTP/1.1\r\n\r\n")
+            .unwrap();
+        thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        handle.shutdown();
+        let mut output = Vec::new();
+        let _ = client.read_to_end(&mut output);
+        assert!(output.is_empty());
+        assert!(started.elapsed() < Duration::from_millis(800));
+        server.join().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn event_loop() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = Router::new().post("/:name", |request| {
+            let body = String::from_utf8_lossy(request.body());
+            Response::text(
+                StatusCode::Ok,
+                format!("{}{body}", request.param("name").unwrap()),
+            )
+        });
+        let options = ConnectionOptions {
+            request_timeout: Duration::from_millis(300),
+            ..ConnectionOptions::default()
+        };
+        // A single worker, which idle connections would keep to themselves in the blocking mode.
+        let server = Server::new([listener], ThreadPool::new(1), router)
+            .unwrap()
+            .with_options(options)
+            .with_io_mode(IoMode::EventLoop);
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        let idle: Vec<TcpStream> = (0..8)
+            .map(|_| TcpStream::connect(address).unwrap())
+            .collect();
+        let mut partial = TcpStream::connect(address).unwrap();
+        partial.write_all(b"POST /slow HTTP/1.1\r\nHo").unwrap();
+
+        // Pipelined requests, arriving in pieces that split both a request line and a body.
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"POST /a HTTP/1.1\r\n\r\nPOST /b HT")
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client
+            .write_all(b"TP/1.1\r\nContent-Length: 2\r\nConnection: close\r\n\r\n!")
+            .unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.write_all(b"!").unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        let responses = responses(&output);
+        assert_eq!(responses.len(), 2);
+        assert!(responses[0].ends_with("\r\n\r\na"));
+        assert!(responses[1].ends_with("\r\n\r\nb!!"));
+
+        let mut malformed = TcpStream::connect(address).unwrap();
+        malformed.write_all(b"NOPE\r\n\r\n").unwrap();
+        let mut output = String::new();
+        malformed.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let mut output = String::new();
+        partial.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+
+        handle.shutdown();
+        server.join().unwrap();
+        for mut connection in idle {
+            assert_eq!(connection.read(&mut [0; 1]).unwrap(), 0);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn event_loop_timeouts_past_the_clock() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = Router::new().get("/", |_| Response::text(StatusCode::Ok, "ok"));
+        // Too long to add to an `Instant`, which is the same as no timeout at all.
+        let options = ConnectionOptions {
+            request_timeout: Duration::MAX,
+            idle_timeout: Duration::MAX,
+            ..ConnectionOptions::default()
+        };
+        let server = Server::new([listener], ThreadPool::new(1), router)
+            .unwrap()
+            .with_options(options)
+            .with_io_mode(IoMode::EventLoop);
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        let _idle = TcpStream::connect(address).unwrap();
+        let mut partial = TcpStream::connect(address).unwrap();
+        partial.write_all(b"GET / HT").unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut output = String::new();
+        client.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn saturated_pool_answers_503() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let pool = ThreadPool::builder(1)
+            .queue_capacity(1)
+            .overflow_policy(crate::OverflowPolicy::Reject)
+            .build()
+            .unwrap();
+        let router = Router::new().get("/", |_| Response::text(StatusCode::Ok, "ok"));
+        let server = Server::new([listener], pool, router).unwrap();
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        // The first connection keeps the only worker busy, and the second fills the queue.
+        let mut busy = TcpStream::connect(address).unwrap();
+        busy.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        busy.read_exact(&mut [0; 12]).unwrap();
+        let queued = TcpStream::connect(address).unwrap();
+
+        let mut rejected = TcpStream::connect(address).unwrap();
+        let mut output = String::new();
+        rejected.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(output.contains("Retry-After: 1\r\n"));
+
+        drop((busy, queued));
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn event_loop_saturated_pool_answers_503() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let pool = ThreadPool::builder(1)
+            .queue_capacity(1)
+            .overflow_policy(crate::OverflowPolicy::Reject)
+            .build()
+            .unwrap();
+        let router = Router::new().get("/", |_| {
+            thread::sleep(Duration::from_millis(300));
+            Response::text(StatusCode::Ok, "ok")
+        });
+        let server = Server::new([listener], pool, router)
+            .unwrap()
+            .with_io_mode(IoMode::EventLoop);
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        // The first request keeps the only worker busy, and the second fills the queue.
+        let clients: Vec<TcpStream> = (0..2)
+            .map(|_| {
+                let mut client = TcpStream::connect(address).unwrap();
+                client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+                thread::sleep(Duration::from_millis(50));
+                client
+            })
+            .collect();
+
+        let mut rejected = TcpStream::connect(address).unwrap();
+        rejected.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut output = String::new();
+        rejected.read_to_string(&mut output).unwrap();
+        assert!(output.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        drop(clients);
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn service_unavailable_does_not_wait_for_slow_readers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        // Fill the send buffer, which the client never reads from.
+        stream.set_nonblocking(true).unwrap();
+        let filler = [0; 65536];
+        while stream.write(&filler).is_ok() {}
+
+        let started = Instant::now();
+        let error = service_unavailable(&stream, Duration::from_secs(1)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+}
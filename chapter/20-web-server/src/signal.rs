@@ -0,0 +1,86 @@
+use std::{io, thread, time::Duration};
+
+/// Runs `f` on a background thread once the process receives `SIGINT` or `SIGTERM`.
+///
+/// Only the first signal is intercepted: after it the default disposition is restored, so pressing
+/// Ctrl+C a second time terminates the process immediately, as usual.
+///
+/// The standard library has no API for signals, so this uses the C library's `signal` directly, as
+/// in the "Using `extern` Functions to Call External Code" section of chapter 19. It isn't supported
+/// on platforms other than Unix.
+pub fn on_termination<F>(f: F) -> io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    imp::install()?;
+
+    thread::Builder::new()
+        .name(String::from("signal-watcher"))
+        .spawn(move || {
+            // A signal handler can't safely do much more than set a flag, so the flag is polled here.
+            while !imp::received() {
+                thread::sleep(Duration::from_millis(100));
+            }
+            f();
+        })?;
+    Ok(())
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::{
+        ffi::c_int,
+        io,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    const SIGINT: c_int = 2;
+    const SIGTERM: c_int = 15;
+    const SIG_DFL: usize = 0;
+    const SIG_ERR: usize = usize::MAX;
+
+    static RECEIVED: AtomicBool = AtomicBool::new(false);
+
+    extern "C" {
+        fn signal(signum: c_int, handler: usize) -> usize;
+    }
+
+    extern "C" fn handle(_: c_int) {
+        RECEIVED.store(true, Ordering::SeqCst);
+        // SAFETY: `signal` is async-signal-safe, and `SIG_DFL` is a valid disposition.
+        unsafe {
+            signal(SIGINT, SIG_DFL);
+            signal(SIGTERM, SIG_DFL);
+        }
+    }
+
+    pub fn install() -> io::Result<()> {
+        for signum in [SIGINT, SIGTERM] {
+            // SAFETY: `handle` has the signature of a signal handler, and only touches an atomic.
+            if unsafe { signal(signum, handle as extern "C" fn(c_int) as usize) } == SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn received() -> bool {
+        RECEIVED.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::io;
+
+    pub fn install() -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "signals are only supported on Unix",
+        ))
+    }
+
+    pub fn received() -> bool {
+        false
+    }
+}
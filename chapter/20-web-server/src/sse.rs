@@ -0,0 +1,252 @@
+//! Server-sent events: a `text/event-stream` response that a browser's `EventSource` reads events
+//! from as they happen, reconnecting by itself if the connection drops.
+//!
+//! Events are sent down a channel to an [`EventStream`], whose body is written by the worker that
+//! answered the request, so the work that produces them happens elsewhere, such as on a thread of
+//! its own:
+//!
+//! ```no_run
+//! use std::{thread, time::Duration};
+//! use web_server::{
+//!     router::Router,
+//!     sse::{self, Event, EventStream},
+//! };
+//!
+//! let router = Router::new().get("/progress", |request| {
+//!     // A browser that reconnects says which event it saw last, so it can carry on from there.
+//!     let seen: u32 = sse::last_event_id(request)
+//!         .and_then(|id| id.parse().ok())
+//!         .unwrap_or(0);
+//!     let (events, stream) = EventStream::channel();
+//!     thread::spawn(move || {
+//!         for step in seen + 1..=10 {
+//!             thread::sleep(Duration::from_secs(1));
+//!             let event = Event::new(format!("{step}0%")).id(step.to_string());
+//!             if events.send(event).is_err() {
+//!                 // The browser has gone.
+//!                 return;
+//!             }
+//!         }
+//!     });
+//!     stream.into_response()
+//! });
+//! ```
+
+use std::{
+    fmt,
+    io::Write,
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender},
+    time::Duration,
+};
+
+use crate::{
+    request::Request,
+    response::{Body, Response},
+};
+
+/// The `Last-Event-ID` a browser sends when it reconnects, which is the `id` of the last event it
+/// received.
+pub fn last_event_id(request: &Request) -> Option<&str> {
+    request.header("Last-Event-ID")
+}
+
+/// One event of an [`EventStream`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Event {
+    data: String,
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    /// An event carrying `data`, which may span several lines.
+    pub fn new(data: impl Into<String>) -> Event {
+        Event {
+            data: data.into(),
+            ..Event::default()
+        }
+    }
+
+    /// Names the type of event, which the browser dispatches to listeners for that name rather than
+    /// to `onmessage`.
+    pub fn event(mut self, name: impl Into<String>) -> Event {
+        self.event = Some(name.into());
+        self
+    }
+
+    /// Sets the id that the browser sends back as `Last-Event-ID` if it reconnects after this event.
+    pub fn id(mut self, id: impl Into<String>) -> Event {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Tells the browser how long to wait before reconnecting, from now on.
+    pub fn retry(mut self, delay: Duration) -> Event {
+        self.retry = Some(delay);
+        self
+    }
+}
+
+/// Writes the event as lines of `field: value`, ended by a blank line.
+///
+/// Line breaks can't be escaped, so the data is split into a `data` line per line, and line breaks
+/// in the name or id are left out.
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let single_line = |s: &str| s.replace(['\r', '\n'], "");
+        if let Some(event) = &self.event {
+            writeln!(f, "event: {}", single_line(event))?;
+        }
+        if let Some(id) = &self.id {
+            // An id with a NUL in it is ignored, and so is the field as a whole.
+            writeln!(f, "id: {}", single_line(id).replace('\0', ""))?;
+        }
+        if let Some(retry) = self.retry {
+            writeln!(f, "retry: {}", retry.as_millis())?;
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            writeln!(f, "data: {line}")?;
+        }
+        writeln!(f)
+    }
+}
+
+/// A `text/event-stream` response that sends the events it receives on a channel, and ends once
+/// every [`Sender`] has been dropped.
+///
+/// While nothing is happening a comment is sent every so often, as a heartbeat: it stops proxies
+/// from timing the connection out, and lets the server notice that the browser has gone, which it
+/// otherwise wouldn't until the next event.
+///
+/// A stream that has nothing left to send when the browser reconnects should be answered with
+/// `204 No Content` instead, which tells the browser to stop reconnecting.
+pub struct EventStream {
+    receiver: Receiver<Event>,
+    heartbeat: Duration,
+}
+
+impl EventStream {
+    /// A stream of the events sent to `receiver`, with a heartbeat every 15 seconds.
+    pub fn new(receiver: Receiver<Event>) -> EventStream {
+        EventStream {
+            receiver,
+            heartbeat: Duration::from_secs(15),
+        }
+    }
+
+    /// A stream together with the sender of its events.
+    pub fn channel() -> (Sender<Event>, EventStream) {
+        let (sender, receiver) = mpsc::channel();
+        (sender, EventStream::new(receiver))
+    }
+
+    /// Sets how long the stream can go without sending anything before a heartbeat is sent.
+    pub fn heartbeat(mut self, interval: Duration) -> EventStream {
+        self.heartbeat = interval;
+        self
+    }
+
+    /// The response that streams the events, which keeps the worker that writes it until the
+    /// stream ends or the browser goes away.
+    pub fn into_response(self) -> Response {
+        let EventStream {
+            receiver,
+            heartbeat,
+        } = self;
+        let body = Body::from_writer(move |writer| {
+            // Sends the head straight away, so that the browser knows the stream is open.
+            writer.flush()?;
+            loop {
+                match receiver.recv_timeout(heartbeat) {
+                    Ok(event) => {
+                        write!(writer, "{event}")?;
+                        // Send whatever else is ready along with it.
+                        for event in receiver.try_iter() {
+                            write!(writer, "{event}")?;
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => writer.write_all(b": heartbeat\n\n")?,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+                }
+                writer.flush()?;
+            }
+        });
+        Response::builder()
+            .content_type("text/event-stream")
+            .header("Cache-Control", "no-cache")
+            .body(body)
+            .build()
+    }
+}
+
+impl From<EventStream> for Response {
+    fn from(stream: EventStream) -> Response {
+        stream.into_response()
+    }
+}
+
+impl fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventStream")
+            .field("heartbeat", &self.heartbeat)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn formats_events() {
+        assert_eq!(Event::new("hello").to_string(), "data: hello\n\n");
+        assert_eq!(Event::new("").to_string(), "data: \n\n");
+        let event = Event::new("one\ntwo\r\nthree\n")
+            .event("progress")
+            .id("4\n2")
+            .retry(Duration::from_secs(3));
+        assert_eq!(
+            event.to_string(),
+            "event: progress\nid: 42\nretry: 3000\n\
+             data: one\ndata: two\ndata: three\ndata: \n\n"
+        );
+    }
+
+    #[test]
+    fn last_event_id_from_the_header() {
+        let request = Request::read(&mut &b"GET / HTTP/1.1\r\nLast-Event-ID: 7\r\n\r\n"[..])
+            .unwrap()
+            .unwrap();
+        assert_eq!(last_event_id(&request), Some("7"));
+    }
+
+    #[test]
+    fn streams_events_with_heartbeats() {
+        let (events, stream) = EventStream::channel();
+        let response = stream.heartbeat(Duration::from_millis(10)).into_response();
+        assert_eq!(
+            response.headers().get("Content-Type"),
+            Some("text/event-stream")
+        );
+        let producer = thread::spawn(move || {
+            events.send(Event::new("first").id("1")).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            events.send(Event::new("second").id("2")).unwrap();
+        });
+
+        let mut bytes = Vec::new();
+        response.write_to(&mut bytes).unwrap();
+        producer.join().unwrap();
+        let raw = String::from_utf8(bytes).unwrap();
+        assert!(raw.contains("Transfer-Encoding: chunked\r\n"));
+        let first = raw.find("id: 1\ndata: first\n\n").unwrap();
+        let heartbeat = raw.find(": heartbeat\n\n").unwrap();
+        let second = raw.find("id: 2\ndata: second\n\n").unwrap();
+        assert!(first < heartbeat && heartbeat < second, "{raw}");
+        // The stream ended when the sender was dropped.
+        assert!(raw.ends_with("\r\n0\r\n\r\n"));
+    }
+}
@@ -0,0 +1,211 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// A snapshot of what a [`ThreadPool`](crate::ThreadPool) is doing, from
+/// [`ThreadPool::stats`](crate::ThreadPool::stats).
+///
+/// The numbers are read one at a time while the pool carries on working, so they may be slightly
+/// inconsistent with each other; `busy_workers` may briefly exceed `workers`, say.
+#[derive(Clone, Debug)]
+pub struct PoolStats {
+    /// How many threads the pool has.
+    pub workers: usize,
+    /// How many threads are running a job.
+    pub busy_workers: usize,
+    /// How many threads are waiting for a job.
+    pub idle_workers: usize,
+    /// How many jobs are waiting for a thread.
+    pub queued: usize,
+    /// How many jobs have finished without panicking.
+    pub completed: u64,
+    /// How many jobs have panicked. Jobs queued with [`ThreadPool::spawn`](crate::ThreadPool::spawn)
+    /// hand their panics to their handle, so they count as completed instead.
+    pub panicked: u64,
+    /// How long jobs waited in the queue before a thread started them.
+    pub wait_time: Histogram,
+    /// How long jobs took to run.
+    pub run_time: Histogram,
+}
+
+impl PoolStats {
+    /// Formats the stats in the Prometheus text exposition format, with each metric's name
+    /// starting with `prefix`.
+    ///
+    /// ```
+    /// use web_server::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let text = pool.stats().to_prometheus("pool");
+    /// assert!(text.contains("\npool_workers 2\n"));
+    /// ```
+    pub fn to_prometheus(&self, prefix: &str) -> String {
+        let mut text = String::new();
+        let gauges = [
+            ("workers", "Threads in the pool.", self.workers),
+            ("busy_workers", "Threads running a job.", self.busy_workers),
+            (
+                "idle_workers",
+                "Threads waiting for a job.",
+                self.idle_workers,
+            ),
+            ("queued_jobs", "Jobs waiting for a thread.", self.queued),
+        ];
+        for (name, help, value) in gauges {
+            metric_header(&mut text, prefix, name, help, "gauge");
+            let _ = writeln!(text, "{prefix}_{name} {value}");
+        }
+
+        let counters = [
+            (
+                "jobs_completed_total",
+                "Jobs that finished without panicking.",
+                self.completed,
+            ),
+            ("jobs_panicked_total", "Jobs that panicked.", self.panicked),
+        ];
+        for (name, help, value) in counters {
+            metric_header(&mut text, prefix, name, help, "counter");
+            let _ = writeln!(text, "{prefix}_{name} {value}");
+        }
+
+        let histograms = [
+            (
+                "job_wait_seconds",
+                "Time jobs waited for a thread.",
+                &self.wait_time,
+            ),
+            ("job_run_seconds", "Time jobs took to run.", &self.run_time),
+        ];
+        for (name, help, histogram) in histograms {
+            metric_header(&mut text, prefix, name, help, "histogram");
+            for (bound, count) in histogram.buckets() {
+                let le = bound.as_secs_f64();
+                let _ = writeln!(text, "{prefix}_{name}_bucket{{le=\"{le}\"}} {count}");
+            }
+            let (count, sum) = (histogram.count(), histogram.sum().as_secs_f64());
+            let _ = writeln!(text, "{prefix}_{name}_bucket{{le=\"+Inf\"}} {count}");
+            let _ = writeln!(text, "{prefix}_{name}_sum {sum}");
+            let _ = writeln!(text, "{prefix}_{name}_count {count}");
+        }
+        text
+    }
+}
+
+fn metric_header(text: &mut String, prefix: &str, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(text, "# HELP {prefix}_{name} {help}");
+    let _ = writeln!(text, "# TYPE {prefix}_{name} {kind}");
+}
+
+/// The upper bounds of a histogram's buckets, in microseconds, from 100µs to 10s.
+const BOUNDS_MICROS: [u64; 16] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000, 10_000_000,
+];
+
+/// How a set of durations is distributed, in buckets from 100µs to 10s.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Histogram {
+    /// How many durations fell in each bucket or any before it, as in Prometheus.
+    cumulative: Vec<(Duration, u64)>,
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    /// Each bucket's upper bound, with how many durations were no longer than it. Durations longer
+    /// than the last bound are only included in [`Histogram::count`].
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.cumulative.iter().copied()
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// All the durations added together.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let count = u32::try_from(self.count).ok().filter(|count| *count > 0)?;
+        Some(self.sum / count)
+    }
+}
+
+/// Records durations into a [`Histogram`] from any number of threads at once.
+pub(crate) struct Recorder {
+    /// One count for each bucket, then one for durations beyond the last bound.
+    counts: [AtomicU64; BOUNDS_MICROS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Recorder {
+    pub(crate) fn new() -> Recorder {
+        Recorder {
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, duration: Duration) {
+        let micros = duration.as_micros();
+        let bucket = BOUNDS_MICROS
+            .iter()
+            .position(|bound| micros <= u128::from(*bound))
+            .unwrap_or(BOUNDS_MICROS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> Histogram {
+        let mut cumulative = Vec::with_capacity(BOUNDS_MICROS.len());
+        let mut count = 0;
+        for (bound, bucket) in BOUNDS_MICROS.iter().zip(&self.counts) {
+            count += bucket.load(Ordering::Relaxed);
+            cumulative.push((Duration::from_micros(*bound), count));
+        }
+        count += self.counts[BOUNDS_MICROS.len()].load(Ordering::Relaxed);
+
+        Histogram {
+            cumulative,
+            count,
+            sum: Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_are_cumulative() {
+        let recorder = Recorder::new();
+        for micros in [50, 100, 101, 3_000, 20_000_000] {
+            recorder.record(Duration::from_micros(micros));
+        }
+        let histogram = recorder.snapshot();
+
+        let buckets: Vec<_> = histogram.buckets().collect();
+        assert_eq!(buckets[0], (Duration::from_micros(100), 2));
+        assert_eq!(buckets[1], (Duration::from_micros(250), 3));
+        assert_eq!(buckets[5], (Duration::from_millis(5), 4));
+        assert_eq!(buckets.last(), Some(&(Duration::from_secs(10), 4)));
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.sum(), Duration::from_micros(20_003_251));
+        assert_eq!(histogram.mean(), Some(Duration::from_nanos(4_000_650_200)));
+    }
+
+    #[test]
+    fn empty_histogram() {
+        let histogram = Recorder::new().snapshot();
+        assert_eq!(histogram.count(), 0);
+        assert_eq!(histogram.mean(), None);
+        assert!(histogram.buckets().all(|(_, count)| count == 0));
+    }
+}
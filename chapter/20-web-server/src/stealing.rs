@@ -0,0 +1,247 @@
+use std::{
+    cell::Cell,
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    time::Duration,
+};
+
+use crate::{queue::Pop, ExecuteErrorKind, Job, OverflowPolicy};
+
+thread_local! {
+    /// The queue and worker that the current thread belongs to, if it's a worker of a pool with a
+    /// work-stealing queue. The queue is identified by its address, which is stable because it
+    /// lives in an `Arc` for as long as its workers do.
+    static CURRENT_WORKER: Cell<Option<(usize, usize)>> = const { Cell::new(None) };
+}
+
+/// A deque for each worker, which it takes jobs from while other workers steal from it whenever
+/// their own runs dry.
+///
+/// Jobs queued from outside the pool are dealt out to the deques in turn, while a job queued by a
+/// worker goes on its own deque. Each deque has its own lock, so workers only contend with each
+/// other when stealing, rather than on every job as with [`SharedQueue`](crate::queue::SharedQueue).
+///
+/// There is a deque for as many workers as the pool can grow to; if it is resized beyond that,
+/// workers share deques.
+pub(crate) struct StealingQueue {
+    /// Each job is numbered, so that [`OverflowPolicy::DropOldest`] can find the oldest.
+    deques: Vec<Mutex<VecDeque<(u64, Job)>>>,
+    /// The deque that the next job from outside the pool goes on.
+    next_deque: AtomicUsize,
+    next_sequence: AtomicU64,
+    /// How many jobs are in all the deques together, which is what the capacity limits.
+    queued: AtomicUsize,
+    capacity: Option<usize>,
+    closed: AtomicBool,
+    /// Guards sleeping and waking, rather than any data: workers wait on `not_empty` when there's
+    /// nothing to steal, and blocked callers of `push` wait on `not_full`.
+    sleep: Mutex<()>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    /// How many threads are waiting on each condition variable, so that the common case of nobody
+    /// waiting doesn't need the lock.
+    idle_workers: AtomicUsize,
+    blocked_callers: AtomicUsize,
+}
+
+impl StealingQueue {
+    pub(crate) fn new(workers: usize, capacity: Option<usize>) -> StealingQueue {
+        StealingQueue {
+            deques: (0..workers.max(1))
+                .map(|_| Mutex::new(VecDeque::new()))
+                .collect(),
+            next_deque: AtomicUsize::new(0),
+            next_sequence: AtomicU64::new(0),
+            queued: AtomicUsize::new(0),
+            capacity,
+            closed: AtomicBool::new(false),
+            sleep: Mutex::new(()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            idle_workers: AtomicUsize::new(0),
+            blocked_callers: AtomicUsize::new(0),
+        }
+    }
+
+    /// Marks the current thread as worker `id`, so the jobs it queues go on its own deque.
+    pub(crate) fn enter(&self, id: usize) {
+        CURRENT_WORKER.with(|current| current.set(Some((self.address(), id))));
+    }
+
+    pub(crate) fn push(
+        &self,
+        job: Job,
+        policy: OverflowPolicy,
+    ) -> Result<(), (Job, ExecuteErrorKind)> {
+        let mut evicted = None;
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err((job, ExecuteErrorKind::ShutDown));
+            }
+            if self.reserve() {
+                break;
+            }
+            match policy {
+                OverflowPolicy::Block => self.wait_for_room(),
+                OverflowPolicy::Reject | OverflowPolicy::CallerRuns => {
+                    return Err((job, ExecuteErrorKind::Full));
+                }
+                OverflowPolicy::DropOldest => {
+                    // The evicted job's slot goes to the new one; if another thread got to the
+                    // oldest job first, there may be room now anyway.
+                    evicted = self.take_oldest();
+                    if evicted.is_some() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        let deque = match CURRENT_WORKER.with(Cell::get) {
+            Some((address, id)) if address == self.address() => id % self.deques.len(),
+            _ => self.next_deque.fetch_add(1, Ordering::Relaxed) % self.deques.len(),
+        };
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        lock(&self.deques[deque]).push_back((sequence, job));
+
+        if self.idle_workers.load(Ordering::SeqCst) > 0 {
+            let _sleep = lock(&self.sleep);
+            self.not_empty.notify_one();
+        }
+        // Dropping a job can run arbitrary code, such as closing a connection, so it's done
+        // without holding any lock.
+        drop(evicted);
+        Ok(())
+    }
+
+    /// Takes a job from worker `id`'s own deque, or else steals one from another worker, waiting
+    /// up to `timeout` for one if there are none.
+    pub(crate) fn pop(&self, id: usize, timeout: Duration) -> Pop {
+        loop {
+            if let Some(job) = self.find_job(id) {
+                return Pop::Job(job);
+            }
+
+            let sleep = lock(&self.sleep);
+            self.idle_workers.fetch_add(1, Ordering::SeqCst);
+            // `push` adds the job before checking for idle workers, and this counts itself as idle
+            // before checking for jobs, so at least one of them sees the other.
+            let empty = self.queued.load(Ordering::SeqCst) == 0;
+            if empty && self.closed.load(Ordering::SeqCst) {
+                self.idle_workers.fetch_sub(1, Ordering::SeqCst);
+                return Pop::Closed;
+            }
+            if empty {
+                drop(
+                    self.not_empty
+                        .wait_timeout(sleep, timeout)
+                        .unwrap_or_else(PoisonError::into_inner),
+                );
+            }
+            self.idle_workers.fetch_sub(1, Ordering::SeqCst);
+            if empty {
+                return match self.find_job(id) {
+                    Some(job) => Pop::Job(job),
+                    None => Pop::Idle,
+                };
+            }
+            // Otherwise a job has a slot but isn't on a deque yet, and will be in a moment.
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn wake_all(&self) {
+        let _sleep = lock(&self.sleep);
+        self.not_empty.notify_all();
+    }
+
+    /// Stops accepting jobs. Those already queued are still handed out by [`StealingQueue::pop`].
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+        let _sleep = lock(&self.sleep);
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    fn find_job(&self, id: usize) -> Option<Job> {
+        let count = self.deques.len();
+        // Start with the worker's own deque, then try the others in turn.
+        let (_, job) = (0..count).find_map(|offset| {
+            let deque = &self.deques[(id + offset) % count];
+            lock(deque).pop_front()
+        })?;
+        self.release();
+        Some(job)
+    }
+
+    /// Removes the job that has been queued longest, without freeing up its slot.
+    fn take_oldest(&self) -> Option<Job> {
+        loop {
+            let (index, sequence) = self
+                .deques
+                .iter()
+                .enumerate()
+                .filter_map(|(i, deque)| lock(deque).front().map(|(sequence, _)| (i, *sequence)))
+                .min_by_key(|(_, sequence)| *sequence)?;
+            let mut deque = lock(&self.deques[index]);
+            // A worker may have taken it in the meantime, in which case look again.
+            if deque.front().is_some_and(|(s, _)| *s == sequence) {
+                return deque.pop_front().map(|(_, job)| job);
+            }
+        }
+    }
+
+    /// Claims a slot for a new job, if there's room.
+    fn reserve(&self) -> bool {
+        self.queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                match self.capacity {
+                    Some(capacity) if queued >= capacity => None,
+                    _ => Some(queued + 1),
+                }
+            })
+            .is_ok()
+    }
+
+    /// Frees the slot of a job that was taken off a deque.
+    fn release(&self) {
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        if self.blocked_callers.load(Ordering::SeqCst) > 0 {
+            let _sleep = lock(&self.sleep);
+            self.not_full.notify_one();
+        }
+    }
+
+    fn wait_for_room(&self) {
+        let sleep = lock(&self.sleep);
+        self.blocked_callers.fetch_add(1, Ordering::SeqCst);
+        // As in `pop`, either this sees the freed slot or `release` sees this caller waiting.
+        let full = self
+            .capacity
+            .is_some_and(|capacity| self.queued.load(Ordering::SeqCst) >= capacity);
+        if full && !self.closed.load(Ordering::SeqCst) {
+            drop(
+                self.not_full
+                    .wait(sleep)
+                    .unwrap_or_else(PoisonError::into_inner),
+            );
+        }
+        self.blocked_callers.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn address(&self) -> usize {
+        self as *const StealingQueue as usize
+    }
+}
+
+/// Nothing panics while holding these locks, but if something ever did the deques would still be
+/// consistent, so poisoning is ignored.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,231 @@
+//! HTTPS, with [rustls](https://docs.rs/rustls); only built with the `tls` feature.
+
+use std::{fmt, io, net::TcpStream, path::Path, sync::Arc};
+
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig, ServerConnection, StreamOwned,
+};
+
+use crate::server::Transport;
+
+/// Starts TLS on accepted connections, with a certificate chain and private key.
+///
+/// Cloning it is cheap, so one can be shared by several listeners.
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use web_server::{
+///     router::Router,
+///     server::{Listener, Server},
+///     tls::TlsAcceptor,
+///     ThreadPool,
+/// };
+///
+/// let acceptor = TlsAcceptor::from_pem_files("cert.pem", "key.pem").unwrap();
+/// let https = TcpListener::bind("0.0.0.0:443").unwrap();
+/// let http = TcpListener::bind("0.0.0.0:80").unwrap();
+/// let listeners = [
+///     Listener::https(https, acceptor),
+///     Listener::redirect_to_https(http, 443),
+/// ];
+/// let server = Server::new(listeners, ThreadPool::new(4), Router::new()).unwrap();
+/// server.run();
+/// ```
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    /// Uses a rustls configuration as it is, for settings that the other constructors don't cover,
+    /// such as client certificates.
+    pub fn new(config: Arc<ServerConfig>) -> TlsAcceptor {
+        TlsAcceptor { config }
+    }
+
+    /// Reads the certificate chain (the server's certificate first) and the private key from PEM
+    /// files, as issued by Let's Encrypt or generated by `openssl`.
+    pub fn from_pem_files(
+        cert_path: impl AsRef<Path>,
+        key_path: impl AsRef<Path>,
+    ) -> io::Result<TlsAcceptor> {
+        let (cert_path, key_path) = (cert_path.as_ref(), key_path.as_ref());
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| pem_error(cert_path, e))?;
+        let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| pem_error(key_path, e))?;
+        TlsAcceptor::from_der(certs, key)
+    }
+
+    /// Like [`from_pem_files`](TlsAcceptor::from_pem_files), but from PEM that's already in memory.
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> io::Result<TlsAcceptor> {
+        let certs = CertificateDer::pem_slice_iter(cert_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| pem_error("the certificate", e))?;
+        let key = PrivateKeyDer::from_pem_slice(key_pem).map_err(|e| pem_error("the key", e))?;
+        TlsAcceptor::from_der(certs, key)
+    }
+
+    fn from_der(
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> io::Result<TlsAcceptor> {
+        if certs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no certificates found",
+            ));
+        }
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // Only HTTP/1.1 is spoken, so say so to clients that would prefer HTTP/2.
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::new(Arc::new(config)))
+    }
+
+    /// Wraps an accepted connection; the handshake happens as it is first read from or written to.
+    pub(crate) fn accept(&self, stream: TcpStream) -> io::Result<TlsStream> {
+        let connection = ServerConnection::new(Arc::clone(&self.config))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
+
+impl fmt::Debug for TlsAcceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsAcceptor").finish_non_exhaustive()
+    }
+}
+
+fn pem_error(source: impl AsRef<Path>, error: rustls::pki_types::pem::Error) -> io::Error {
+    let kind = match error {
+        rustls::pki_types::pem::Error::Io(e) => return e,
+        rustls::pki_types::pem::Error::NoItemsFound => io::ErrorKind::NotFound,
+        _ => io::ErrorKind::InvalidData,
+    };
+    let source = source.as_ref().display();
+    io::Error::new(kind, format!("{source}: {error}"))
+}
+
+pub(crate) type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+impl Transport for TlsStream {
+    fn tcp(&self) -> &TcpStream {
+        &self.sock
+    }
+
+    /// Tells the client that the response is complete, so that it can tell a clean close from a
+    /// truncation attack.
+    fn close(&mut self) -> io::Result<()> {
+        self.conn.send_close_notify();
+        io::Write::flush(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore};
+
+    use super::*;
+    use crate::{
+        response::{Response, StatusCode},
+        router::Router,
+        server::{Listener, Server},
+        ThreadPool,
+    };
+
+    /// A certificate for `localhost` that signs itself, as PEM.
+    fn self_signed() -> (String, String) {
+        let certified = rcgen::generate_simple_self_signed(["localhost".to_string()]).unwrap();
+        (certified.cert.pem(), certified.signing_key.serialize_pem())
+    }
+
+    fn client(cert_pem: &str) -> Arc<ClientConfig> {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_slice_iter(cert_pem.as_bytes()) {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Arc::new(config)
+    }
+
+    #[test]
+    fn serves_https() {
+        let (cert, key) = self_signed();
+        let acceptor = TlsAcceptor::from_pem(cert.as_bytes(), key.as_bytes()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = Router::new().get("/", |_| Response::text(StatusCode::Ok, "secret"));
+        let server = Server::new(
+            [Listener::https(listener, acceptor)],
+            ThreadPool::new(1),
+            router,
+        )
+        .unwrap();
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        let name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(client(&cert), name).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nsecret"));
+        assert_eq!(
+            stream.conn.alpn_protocol(),
+            Some(&b"http/1.1"[..]),
+            "ALPN is negotiated"
+        );
+
+        // A client that doesn't trust the certificate can't get anything out of the server.
+        let (other, _) = self_signed();
+        let name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(client(&other), name).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+        assert!(stream.write_all(b"GET / HTTP/1.1\r\n\r\n").is_err());
+
+        // Neither can one that doesn't speak TLS at all.
+        let mut plain = TcpStream::connect(address).unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut output = Vec::new();
+        let _ = plain.read_to_end(&mut output);
+        assert!(!String::from_utf8_lossy(&output).contains("secret"));
+
+        handle.shutdown();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn bad_pem() {
+        let (cert, key) = self_signed();
+        let error = TlsAcceptor::from_pem(b"", key.as_bytes()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = TlsAcceptor::from_pem(cert.as_bytes(), b"").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        // A key that doesn't go with the certificate.
+        let (_, other_key) = self_signed();
+        assert!(TlsAcceptor::from_pem(cert.as_bytes(), other_key.as_bytes()).is_err());
+
+        let error = TlsAcceptor::from_pem_files("/nonexistent/cert.pem", "key.pem").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,870 @@
+//! WebSockets (RFC 6455), for pushing messages to a browser as things happen, and hearing back.
+//!
+//! A route answers the handshake with [`upgrade`], whose handler then has the connection to itself
+//! as a [`WebSocket`]:
+//!
+//! ```no_run
+//! use web_server::{
+//!     router::Router,
+//!     websocket::{self, Message},
+//! };
+//!
+//! let router = Router::new().get("/echo", |request| {
+//!     websocket::upgrade(request, |mut socket| {
+//!         while let Ok(message) = socket.recv() {
+//!             match message {
+//!                 Message::Text(_) | Message::Binary(_) => {
+//!                     if socket.send(message).is_err() {
+//!                         break;
+//!                     }
+//!                 }
+//!                 Message::Close(_) => break,
+//!                 Message::Ping(_) | Message::Pong(_) => {}
+//!             }
+//!         }
+//!     })
+//! });
+//! ```
+//!
+//! The handler runs on the worker that answered the handshake and keeps it until it returns, so
+//! the pool needs a worker for every WebSocket that is open at once, on top of those for requests.
+
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    net::SocketAddr,
+    str, thread,
+    time::Duration,
+};
+
+use crate::{
+    base64,
+    request::{is_timeout, Method, Request, Version},
+    response::{Response, StatusCode, Upgraded},
+    sha1::sha1,
+};
+
+/// Appended to the client's key before it is hashed, to show that the server speaks WebSocket.
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// The most payload a ping, pong or close frame can carry.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// Answers a WebSocket handshake, and once the response has been sent hands the connection to
+/// `handler`.
+///
+/// A request that isn't a valid handshake is refused with `400 Bad Request`, or with
+/// `426 Upgrade Required` if it isn't a WebSocket request at all or asks for a version other
+/// than 13.
+pub fn upgrade<F>(request: &Request, handler: F) -> Response
+where
+    F: FnOnce(WebSocket) + Send + 'static,
+{
+    let key = match handshake_key(request) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    Response::builder()
+        .status(StatusCode::SwitchingProtocols)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", accept_key(key))
+        .upgrade(|upgraded| handler(WebSocket::new(upgraded)))
+        .build()
+}
+
+/// The `Sec-WebSocket-Accept` that answers a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    base64::encode(&sha1(format!("{key}{GUID}").as_bytes()))
+}
+
+/// Checks that `request` is a WebSocket handshake, returning its key or the response that refuses
+/// it.
+fn handshake_key(request: &Request) -> Result<&str, Response> {
+    if !request.headers().has_token("Upgrade", "websocket")
+        || !request.headers().has_token("Connection", "upgrade")
+    {
+        let mut response =
+            Response::text(StatusCode::UpgradeRequired, "This is a WebSocket endpoint.");
+        response.headers_mut().insert("Upgrade", "websocket");
+        return Err(response);
+    }
+    if request.method() != Method::Get || request.version() != Version::Http11 {
+        return Err(Response::text(
+            StatusCode::BadRequest,
+            "A WebSocket handshake must be an HTTP/1.1 GET request.",
+        ));
+    }
+    if request.header("Sec-WebSocket-Version") != Some("13") {
+        let mut response = Response::text(
+            StatusCode::UpgradeRequired,
+            "Only version 13 of WebSocket is supported.",
+        );
+        response.headers_mut().insert("Sec-WebSocket-Version", "13");
+        return Err(response);
+    }
+    let key = request
+        .header("Sec-WebSocket-Key")
+        .unwrap_or_default()
+        .trim();
+    if base64::decode(key).is_none_or(|nonce| nonce.len() != 16) {
+        return Err(Response::text(
+            StatusCode::BadRequest,
+            "Sec-WebSocket-Key must be 16 bytes in base64.",
+        ));
+    }
+    Ok(key)
+}
+
+/// A message sent or received over a [`WebSocket`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+    /// A ping from the client, which has already been answered with a pong by the time it is
+    /// received.
+    Ping(Vec<u8>),
+    Pong(Vec<u8>),
+    /// The client is closing the connection, with a code if it gave one. The close has already
+    /// been answered by the time it is received, and nothing more can be.
+    Close(Option<CloseFrame>),
+}
+
+/// Why a WebSocket was closed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CloseFrame {
+    pub code: CloseCode,
+    /// An explanation for people, rather than programs, of at most 123 bytes.
+    pub reason: String,
+}
+
+/// The status code of a close frame (RFC 6455, 7.4).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseCode {
+    /// 1000: whatever the connection was for is done.
+    Normal,
+    /// 1001: the server is going down, or the browser is leaving the page.
+    GoingAway,
+    /// 1002
+    ProtocolError,
+    /// 1003: a kind of message that can't be handled, such as binary for a text-only endpoint.
+    Unsupported,
+    /// 1007: a message that doesn't match its type, such as text that isn't UTF-8.
+    InvalidPayload,
+    /// 1008
+    PolicyViolation,
+    /// 1009
+    MessageTooBig,
+    /// 1011: the server ran into something it couldn't cope with.
+    InternalError,
+    /// Any other code, such as one from 4000 to 4999, which are for applications to use as they
+    /// like.
+    Other(u16),
+}
+
+impl CloseCode {
+    pub fn as_u16(self) -> u16 {
+        match self {
+            CloseCode::Normal => 1000,
+            CloseCode::GoingAway => 1001,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::Unsupported => 1003,
+            CloseCode::InvalidPayload => 1007,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::MessageTooBig => 1009,
+            CloseCode::InternalError => 1011,
+            CloseCode::Other(code) => code,
+        }
+    }
+
+    /// Whether the code may be sent in a close frame; some are reserved, or only for reporting that
+    /// there wasn't a close frame.
+    fn is_valid(self) -> bool {
+        matches!(self.as_u16(), 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(code: u16) -> CloseCode {
+        match code {
+            1000 => CloseCode::Normal,
+            1001 => CloseCode::GoingAway,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::Unsupported,
+            1007 => CloseCode::InvalidPayload,
+            1008 => CloseCode::PolicyViolation,
+            1009 => CloseCode::MessageTooBig,
+            1011 => CloseCode::InternalError,
+            code => CloseCode::Other(code),
+        }
+    }
+}
+
+impl fmt::Display for CloseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_u16())
+    }
+}
+
+/// The server's end of a WebSocket connection.
+///
+/// Messages larger than the frame size are sent in fragments, and fragmented messages from the
+/// client are put back together before they are received. A client that breaks the protocol is
+/// sent a close frame saying how, and receiving fails with [`io::ErrorKind::InvalidData`].
+///
+/// If the handler returns without closing the WebSocket, it is closed with
+/// [`CloseCode::Normal`], or [`CloseCode::InternalError`] if the handler panicked.
+pub struct WebSocket {
+    stream: Upgraded,
+    /// What has been read but not yet parsed into frames.
+    incoming: Vec<u8>,
+    /// The type and payload so far of a message that is arriving in fragments.
+    partial: Option<(u8, Vec<u8>)>,
+    max_message_size: usize,
+    frame_size: usize,
+    sent_close: bool,
+    received_close: bool,
+}
+
+impl WebSocket {
+    fn new(stream: Upgraded) -> WebSocket {
+        WebSocket {
+            stream,
+            incoming: Vec::new(),
+            partial: None,
+            max_message_size: 1024 * 1024,
+            frame_size: 64 * 1024,
+            sent_close: false,
+            received_close: false,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    /// Sets how long [`recv`](WebSocket::recv) waits for a message before failing with
+    /// [`io::ErrorKind::WouldBlock`] (or [`TimedOut`](io::ErrorKind::TimedOut), on some platforms),
+    /// so that a handler can push updates in between. A message that was part-way through arriving
+    /// is picked up where it left off by the next call. By default it waits for as long as it takes.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// Sets the largest message that can be received, beyond which the connection is closed with
+    /// [`CloseCode::MessageTooBig`]; it is 1MiB by default.
+    pub fn set_max_message_size(&mut self, bytes: usize) {
+        self.max_message_size = bytes;
+    }
+
+    /// Sets the largest frame that is sent; longer messages are sent in fragments of this size.
+    /// It is 64KiB by default.
+    pub fn set_frame_size(&mut self, bytes: usize) {
+        self.frame_size = bytes.max(1);
+    }
+
+    /// Waits for the next message from the client, answering pings and closes along the way.
+    ///
+    /// Fails with [`io::ErrorKind::NotConnected`] once a close has been received, and with
+    /// [`io::ErrorKind::UnexpectedEof`] if the connection closes without one, such as when the
+    /// server shuts down.
+    pub fn recv(&mut self) -> io::Result<Message> {
+        if self.received_close {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the WebSocket is closed",
+            ));
+        }
+        loop {
+            let received = self.next_frame().and_then(|frame| self.receive(frame));
+            match received {
+                Ok(Some(message)) => return Ok(message),
+                Ok(None) => {}
+                Err(ReadError::Io(e)) if is_timeout(&e) => return Err(e),
+                Err(ReadError::Io(e)) => {
+                    // Most likely the server is shutting down, which closes connections for reading.
+                    if e.kind() == io::ErrorKind::UnexpectedEof {
+                        let _ = self.send_close(CloseCode::GoingAway, "");
+                    }
+                    self.received_close = true;
+                    return Err(e);
+                }
+                Err(ReadError::Violation(code, reason)) => {
+                    let _ = self.send_close(code, reason);
+                    self.received_close = true;
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+                }
+            }
+        }
+    }
+
+    /// Sends a message, in fragments if it is longer than the frame size.
+    ///
+    /// Sending a [`Message::Close`] closes the WebSocket without waiting for the client to
+    /// answer, unlike [`close`](WebSocket::close); nothing can be sent after it.
+    pub fn send(&mut self, message: Message) -> io::Result<()> {
+        if self.sent_close {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the WebSocket is closed",
+            ));
+        }
+        match message {
+            Message::Text(text) => self.send_data(TEXT, text.as_bytes()),
+            Message::Binary(data) => self.send_data(BINARY, &data),
+            Message::Ping(data) => self.send_control(PING, &data),
+            Message::Pong(data) => self.send_control(PONG, &data),
+            Message::Close(None) => {
+                self.sent_close = true;
+                self.send_control(CLOSE, &[])
+            }
+            Message::Close(Some(frame)) => self.send_close(frame.code, &frame.reason),
+        }
+    }
+
+    /// Closes the WebSocket, and waits a few seconds for the client to answer with a close of its
+    /// own, discarding any messages that arrive in the meantime.
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> io::Result<()> {
+        if !self.sent_close {
+            self.send_close(code, reason)?;
+        }
+        self.set_read_timeout(Some(Duration::from_secs(5)))?;
+        while !matches!(self.recv(), Ok(Message::Close(_)) | Err(_)) {}
+        Ok(())
+    }
+
+    fn send_data(&mut self, opcode: u8, data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return self.write_frame(true, opcode, &[]);
+        }
+        let mut fragments = data.chunks(self.frame_size).peekable();
+        let mut opcode = opcode;
+        while let Some(fragment) = fragments.next() {
+            self.write_frame(fragments.peek().is_none(), opcode, fragment)?;
+            opcode = CONTINUATION;
+        }
+        Ok(())
+    }
+
+    fn send_control(&mut self, opcode: u8, data: &[u8]) -> io::Result<()> {
+        if data.len() > MAX_CONTROL_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "control frames carry at most 125 bytes",
+            ));
+        }
+        self.write_frame(true, opcode, data)
+    }
+
+    fn send_close(&mut self, code: CloseCode, reason: &str) -> io::Result<()> {
+        if !code.is_valid() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{code} can't be sent in a close frame"),
+            ));
+        }
+        // Cut the reason short if need be, without splitting a character.
+        let mut end = reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+        while !reason.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut payload = code.as_u16().to_be_bytes().to_vec();
+        payload.extend_from_slice(&reason.as_bytes()[..end]);
+        self.sent_close = true;
+        self.send_control(CLOSE, &payload)
+    }
+
+    fn write_frame(&mut self, fin: bool, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        self.stream
+            .write_all(&encode_frame(fin, opcode, payload, None))?;
+        self.stream.flush()
+    }
+
+    /// Reads until a whole frame has arrived. What has arrived is kept if reading times out.
+    fn next_frame(&mut self) -> Result<Frame, ReadError> {
+        loop {
+            if let Some((frame, length)) = parse_frame(&self.incoming, self.max_message_size, true)?
+            {
+                self.incoming.drain(..length);
+                return Ok(frame);
+            }
+            let mut chunk = [0; 8192];
+            match self.stream.read(&mut chunk)? {
+                0 => {
+                    return Err(ReadError::Io(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the connection closed without a close frame",
+                    )))
+                }
+                n => self.incoming.extend_from_slice(&chunk[..n]),
+            }
+        }
+    }
+
+    /// Takes in a frame, returning the message it completes, if any.
+    fn receive(&mut self, frame: Frame) -> Result<Option<Message>, ReadError> {
+        let (opcode, payload) = match frame.opcode {
+            CONTINUATION => {
+                let Some((_, data)) = &mut self.partial else {
+                    return Err(ReadError::protocol("continuation of no message"));
+                };
+                if data.len() + frame.payload.len() > self.max_message_size {
+                    return Err(ReadError::Violation(
+                        CloseCode::MessageTooBig,
+                        "message too big",
+                    ));
+                }
+                data.extend_from_slice(&frame.payload);
+                if !frame.fin {
+                    return Ok(None);
+                }
+                self.partial.take().expect("a message is being continued")
+            }
+            TEXT | BINARY if self.partial.is_some() => {
+                return Err(ReadError::protocol("new message before the last one ended"));
+            }
+            TEXT | BINARY if !frame.fin => {
+                self.partial = Some((frame.opcode, frame.payload));
+                return Ok(None);
+            }
+            TEXT | BINARY => (frame.opcode, frame.payload),
+            CLOSE => {
+                let close = parse_close(&frame.payload)?;
+                self.received_close = true;
+                if !self.sent_close {
+                    // Echo the code back, as is customary, or send none if the client didn't.
+                    match &close {
+                        Some(close) => self.send_close(close.code, "")?,
+                        None => self.send(Message::Close(None))?,
+                    }
+                }
+                return Ok(Some(Message::Close(close)));
+            }
+            PING => {
+                if !self.sent_close {
+                    self.send_control(PONG, &frame.payload)?;
+                }
+                return Ok(Some(Message::Ping(frame.payload)));
+            }
+            PONG => return Ok(Some(Message::Pong(frame.payload))),
+            _ => return Err(ReadError::protocol("unknown opcode")),
+        };
+        Ok(Some(if opcode == TEXT {
+            let text = String::from_utf8(payload).map_err(|_| {
+                ReadError::Violation(CloseCode::InvalidPayload, "text that isn't UTF-8")
+            })?;
+            Message::Text(text)
+        } else {
+            Message::Binary(payload)
+        }))
+    }
+}
+
+impl fmt::Debug for WebSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocket")
+            .field("peer", &self.peer_addr().ok())
+            .field("sent_close", &self.sent_close)
+            .field("received_close", &self.received_close)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Drop for WebSocket {
+    fn drop(&mut self) {
+        if !self.sent_close {
+            let code = if thread::panicking() {
+                CloseCode::InternalError
+            } else {
+                CloseCode::Normal
+            };
+            let _ = self.send_close(code, "");
+        }
+    }
+}
+
+/// Why a frame or message couldn't be received.
+enum ReadError {
+    Io(io::Error),
+    /// The client broke the protocol, and the connection is to be closed with this code.
+    Violation(CloseCode, &'static str),
+}
+
+impl ReadError {
+    fn protocol(reason: &'static str) -> ReadError {
+        ReadError::Violation(CloseCode::ProtocolError, reason)
+    }
+}
+
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> ReadError {
+        ReadError::Io(e)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Encodes a frame, masking it with `mask` as a client must.
+fn encode_frame(fin: bool, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(if fin { 0x80 } else { 0 } | opcode);
+    let masked = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        length @ 0..=125 => frame.push(masked | length as u8),
+        length @ 126..=0xffff => {
+            frame.push(masked | 126);
+            frame.extend_from_slice(&(length as u16).to_be_bytes());
+        }
+        length => {
+            frame.push(masked | 127);
+            frame.extend_from_slice(&(length as u64).to_be_bytes());
+        }
+    }
+    let start = frame.len();
+    frame.extend_from_slice(payload);
+    if let Some(mask) = mask {
+        frame.splice(start..start, mask);
+        apply_mask(&mut frame[start + 4..], mask);
+    }
+    frame
+}
+
+/// Parses the frame at the start of `buf`, returning it with its length in bytes, or `None` if
+/// not all of it has arrived yet. Frames from clients are `masked`, and those from servers aren't.
+fn parse_frame(
+    buf: &[u8],
+    max_payload: usize,
+    masked: bool,
+) -> Result<Option<(Frame, usize)>, ReadError> {
+    let [first, second, ..] = *buf else {
+        return Ok(None);
+    };
+    // The reserved bits are for extensions, and none were negotiated.
+    if first & 0x70 != 0 {
+        return Err(ReadError::protocol("reserved bits set"));
+    }
+    let (fin, opcode) = (first & 0x80 != 0, first & 0x0f);
+    if (second & 0x80 != 0) != masked {
+        return Err(ReadError::protocol(if masked {
+            "unmasked frame from a client"
+        } else {
+            "masked frame from a server"
+        }));
+    }
+    let (length, mut offset) = match second & 0x7f {
+        126 => match buf.get(2..4) {
+            Some(bytes) => (u64::from(u16::from_be_bytes([bytes[0], bytes[1]])), 4),
+            None => return Ok(None),
+        },
+        127 => match buf.get(2..10) {
+            Some(bytes) => (u64::from_be_bytes(bytes.try_into().expect("8 bytes")), 10),
+            None => return Ok(None),
+        },
+        length => (u64::from(length), 2),
+    };
+    if opcode >= CLOSE && (!fin || length > MAX_CONTROL_PAYLOAD as u64) {
+        return Err(ReadError::protocol("fragmented or oversized control frame"));
+    }
+    if length > max_payload as u64 {
+        return Err(ReadError::Violation(
+            CloseCode::MessageTooBig,
+            "message too big",
+        ));
+    }
+    let mask = if masked {
+        let Some(mask) = buf.get(offset..offset + 4) else {
+            return Ok(None);
+        };
+        offset += 4;
+        Some([mask[0], mask[1], mask[2], mask[3]])
+    } else {
+        None
+    };
+    let end = offset + length as usize;
+    let Some(payload) = buf.get(offset..end) else {
+        return Ok(None);
+    };
+    let mut payload = payload.to_vec();
+    if let Some(mask) = mask {
+        apply_mask(&mut payload, mask);
+    }
+    Ok(Some((
+        Frame {
+            fin,
+            opcode,
+            payload,
+        },
+        end,
+    )))
+}
+
+/// Masks or unmasks a payload, which are the same thing.
+fn apply_mask(payload: &mut [u8], mask: [u8; 4]) {
+    for (byte, key) in payload.iter_mut().zip(mask.iter().cycle()) {
+        *byte ^= key;
+    }
+}
+
+fn parse_close(payload: &[u8]) -> Result<Option<CloseFrame>, ReadError> {
+    let [high, low, reason @ ..] = payload else {
+        return match payload {
+            [] => Ok(None),
+            _ => Err(ReadError::protocol("close frame with half a code")),
+        };
+    };
+    let code = CloseCode::from(u16::from_be_bytes([*high, *low]));
+    if !code.is_valid() {
+        return Err(ReadError::protocol("invalid close code"));
+    }
+    let reason = str::from_utf8(reason).map_err(|_| {
+        ReadError::Violation(CloseCode::InvalidPayload, "close reason that isn't UTF-8")
+    })?;
+    Ok(Some(CloseFrame {
+        code,
+        reason: reason.to_string(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{TcpListener, TcpStream},
+        thread,
+    };
+
+    use super::*;
+    use crate::{router::Router, server::Server, ThreadPool};
+
+    #[test]
+    fn accept_key_from_the_rfc() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    fn handshake(extra: &str) -> Response {
+        let raw = format!(
+            "GET /ws HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n{extra}\r\n"
+        );
+        let request = Request::read(&mut raw.as_bytes()).unwrap().unwrap();
+        upgrade(&request, |_| {})
+    }
+
+    #[test]
+    fn handshakes() {
+        let response = handshake(
+            "Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+        );
+        assert_eq!(response.status(), StatusCode::SwitchingProtocols);
+        assert_eq!(
+            response.headers().get("Sec-WebSocket-Accept"),
+            Some("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+        );
+
+        let response = handshake(
+            "Sec-WebSocket-Version: 8\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n",
+        );
+        assert_eq!(response.status(), StatusCode::UpgradeRequired);
+        assert_eq!(response.headers().get("Sec-WebSocket-Version"), Some("13"));
+
+        let response = handshake("Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: c2hvcnQ=\r\n");
+        assert_eq!(response.status(), StatusCode::BadRequest);
+
+        let request = Request::read(&mut &b"GET /ws HTTP/1.1\r\n\r\n"[..])
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            upgrade(&request, |_| {}).status(),
+            StatusCode::UpgradeRequired
+        );
+    }
+
+    #[test]
+    fn frames() {
+        for length in [0, 125, 126, 0xffff, 0x10000] {
+            let payload: Vec<u8> = (0..length).map(|i| i as u8).collect();
+            let encoded = encode_frame(true, BINARY, &payload, Some([1, 2, 3, 4]));
+            let parsed = parse_frame(&encoded, usize::MAX, true).ok().flatten();
+            let (frame, used) = parsed.unwrap();
+            assert_eq!(used, encoded.len());
+            assert_eq!(frame.payload, payload);
+
+            // Any less, and there isn't a frame yet.
+            let short = parse_frame(&encoded[..encoded.len() - 1], usize::MAX, true);
+            assert!(matches!(short, Ok(None)));
+        }
+
+        let masked = encode_frame(false, TEXT, b"Hello", Some([0x37, 0xfa, 0x21, 0x3d]));
+        // The example of a masked frame in RFC 6455, 5.7, but not the last of its message.
+        assert_eq!(masked, b"\x01\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58");
+
+        let unmasked = encode_frame(true, TEXT, b"Hello", None);
+        assert!(matches!(
+            parse_frame(&unmasked, usize::MAX, true),
+            Err(ReadError::Violation(CloseCode::ProtocolError, _))
+        ));
+        let big = encode_frame(true, TEXT, &[0; 200], Some([0; 4]));
+        assert!(matches!(
+            parse_frame(&big, 100, true),
+            Err(ReadError::Violation(CloseCode::MessageTooBig, _))
+        ));
+        let long_ping = encode_frame(true, PING, &[0; 126], Some([0; 4]));
+        assert!(matches!(
+            parse_frame(&long_ping, usize::MAX, true),
+            Err(ReadError::Violation(CloseCode::ProtocolError, _))
+        ));
+    }
+
+    /// The client's end of a connection to a test server.
+    struct Client {
+        stream: TcpStream,
+        incoming: Vec<u8>,
+    }
+
+    impl Client {
+        /// Connects and shakes hands, sending `first` straight after the request.
+        fn connect(address: SocketAddr, first: &[u8]) -> Client {
+            let mut stream = TcpStream::connect(address).unwrap();
+            let mut raw = b"GET /echo HTTP/1.1\r\nHost: x\r\nUpgrade: websocket\r\n\
+                Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+                .to_vec();
+            raw.extend_from_slice(first);
+            stream.write_all(&raw).unwrap();
+
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0];
+                stream.read_exact(&mut byte).unwrap();
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+            assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+            Client {
+                stream,
+                incoming: Vec::new(),
+            }
+        }
+
+        fn send(&mut self, fin: bool, opcode: u8, payload: &[u8]) {
+            let frame = encode_frame(fin, opcode, payload, Some([9, 8, 7, 6]));
+            self.stream.write_all(&frame).unwrap();
+        }
+
+        fn receive(&mut self) -> Frame {
+            loop {
+                if let Ok(Some((frame, used))) = parse_frame(&self.incoming, usize::MAX, false) {
+                    self.incoming.drain(..used);
+                    return frame;
+                }
+                let mut chunk = [0; 1024];
+                let n = self.stream.read(&mut chunk).unwrap();
+                assert_ne!(n, 0, "the server closed the connection");
+                self.incoming.extend_from_slice(&chunk[..n]);
+            }
+        }
+
+        fn receive_close(&mut self) -> u16 {
+            let frame = self.receive();
+            assert_eq!(frame.opcode, CLOSE);
+            u16::from_be_bytes([frame.payload[0], frame.payload[1]])
+        }
+
+        fn assert_closed(&mut self) {
+            assert_eq!(self.stream.read(&mut [0; 16]).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let router = Router::new().get("/echo", |request| {
+            upgrade(request, |mut socket| {
+                socket.set_frame_size(4);
+                while let Ok(message) = socket.recv() {
+                    match message {
+                        Message::Text(_) | Message::Binary(_) => socket.send(message).unwrap(),
+                        Message::Close(_) => break,
+                        Message::Ping(_) | Message::Pong(_) => {}
+                    }
+                }
+            })
+        });
+        let server = Server::new([listener], ThreadPool::new(2), router).unwrap();
+        let handle = server.shutdown_handle();
+        let server = thread::spawn(move || server.run());
+
+        // The first fragment arrives along with the handshake, and a ping interrupts the message.
+        let first = encode_frame(false, TEXT, b"hel", Some([1, 2, 3, 4]));
+        let mut client = Client::connect(address, &first);
+        client.send(true, PING, b"are you there?");
+        client.send(true, CONTINUATION, b"lo");
+        let pong = client.receive();
+        assert_eq!(
+            (pong.opcode, &pong.payload[..]),
+            (PONG, &b"are you there?"[..])
+        );
+        // Sent back in fragments of at most four bytes.
+        let start = client.receive();
+        assert_eq!(
+            (start.fin, start.opcode, &start.payload[..]),
+            (false, TEXT, &b"hell"[..])
+        );
+        let end = client.receive();
+        assert_eq!(
+            (end.fin, end.opcode, &end.payload[..]),
+            (true, CONTINUATION, &b"o"[..])
+        );
+
+        client.send(true, BINARY, &[0xff, 0]);
+        let binary = client.receive();
+        assert_eq!(
+            (binary.opcode, &binary.payload[..]),
+            (BINARY, &[0xff, 0][..])
+        );
+
+        let mut close = 1000u16.to_be_bytes().to_vec();
+        close.extend_from_slice(b"bye");
+        client.send(true, CLOSE, &close);
+        assert_eq!(client.receive_close(), 1000);
+        client.assert_closed();
+
+        // Clients that break the protocol are told how.
+        let mut client = Client::connect(address, &[]);
+        client
+            .stream
+            .write_all(&encode_frame(true, TEXT, b"unmasked", None))
+            .unwrap();
+        assert_eq!(client.receive_close(), 1002);
+        client.assert_closed();
+
+        let mut client = Client::connect(address, &[]);
+        client.send(true, TEXT, &[0xc3, 0x28]);
+        assert_eq!(client.receive_close(), 1007);
+        client.assert_closed();
+
+        // A WebSocket that is still open when the server shuts down is told it's going away.
+        let mut client = Client::connect(address, &[]);
+        client.send(true, TEXT, b"hi");
+        client.receive();
+        handle.shutdown();
+        assert_eq!(client.receive_close(), 1001);
+        client.assert_closed();
+        server.join().unwrap();
+    }
+}